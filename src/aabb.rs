@@ -0,0 +1,121 @@
+use crate::common::Float;
+use crate::common::Point;
+use crate::common::Ray;
+use crate::common::Vector;
+
+/// Axis-aligned bounding box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Point,
+    pub max: Point,
+}
+
+impl Aabb {
+    pub fn new(a: Point, b: Point) -> Aabb {
+        Aabb {
+            min: a.inf(&b),
+            max: a.sup(&b),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    pub fn size(&self) -> Vector {
+        self.max - self.min
+    }
+
+    pub fn contains(&self, point: &Point) -> bool {
+        (0..3).all(|i| point[i] >= self.min[i] && point[i] <= self.max[i])
+    }
+
+    /// Maps a point inside the box to [0, 1]³.
+    pub fn local_position(&self, point: &Point) -> Point {
+        Point::from((point - self.min).component_div(&self.size()))
+    }
+
+    /// Distances at which the ray enters and leaves the box, clipped to the distance
+    /// range, or None if it misses the box within the range.
+    pub fn intersect(&self, ray: &Ray, min_dist: Float, max_dist: Float) -> Option<(Float, Float)> {
+        let mut entry = min_dist;
+        let mut exit = max_dist;
+        for i in 0..3 {
+            let inverse = 1.0 / ray.direction[i];
+            let mut near = (self.min[i] - ray.origin[i]) * inverse;
+            let mut far = (self.max[i] - ray.origin[i]) * inverse;
+            if inverse < 0.0 {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // NaN from a ray in the plane of a face leaves the range unchanged
+            if near > entry {
+                entry = near;
+            }
+            if far < exit {
+                exit = far;
+            }
+            if exit < entry {
+                return None;
+            }
+        }
+        Some((entry, exit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::INFINITY;
+    use nalgebra::{point, vector, Unit};
+
+    fn unit_box() -> Aabb {
+        Aabb::new(point![1.0, 1.0, 1.0], point![-1.0, -1.0, -1.0])
+    }
+
+    #[test]
+    fn ray_through_box_gives_entry_and_exit() {
+        let ray = Ray {
+            origin: point![-3.0, 0.5, 0.0],
+            direction: Unit::new_normalize(vector![1.0, 0.0, 0.0]),
+            time: 0.0,
+        };
+        assert_eq!(unit_box().intersect(&ray, 0.0, INFINITY), Some((2.0, 4.0)));
+        assert_eq!(unit_box().intersect(&ray, 3.0, 3.5), Some((3.0, 3.5)));
+        assert_eq!(unit_box().intersect(&ray, 0.0, 1.0), None);
+    }
+
+    #[test]
+    fn ray_missing_box_gives_none() {
+        let ray = Ray {
+            origin: point![-3.0, 1.5, 0.0],
+            direction: Unit::new_normalize(vector![1.0, 0.0, 0.0]),
+            time: 0.0,
+        };
+        assert_eq!(unit_box().intersect(&ray, 0.0, INFINITY), None);
+        let diagonal = Ray {
+            origin: point![-3.0, 0.0, 0.0],
+            direction: Unit::new_normalize(vector![1.0, 1.0, 0.0]),
+            time: 0.0,
+        };
+        assert_eq!(unit_box().intersect(&diagonal, 0.0, INFINITY), None);
+    }
+
+    #[test]
+    fn union_and_local_position() {
+        let other = Aabb::new(point![0.0, 0.0, 0.0], point![3.0, 1.0, 1.0]);
+        let union = unit_box().union(&other);
+        assert_eq!(
+            union,
+            Aabb::new(point![-1.0, -1.0, -1.0], point![3.0, 1.0, 1.0])
+        );
+        assert_eq!(
+            union.local_position(&point![1.0, 0.0, 0.0]),
+            point![0.5, 0.5, 0.5]
+        );
+        assert!(union.contains(&point![2.0, 0.0, 0.0]));
+        assert!(!unit_box().contains(&point![2.0, 0.0, 0.0]));
+    }
+}
@@ -0,0 +1,280 @@
+use crate::common::Float;
+use crate::common::Material;
+use crate::common::Vector;
+use exr::prelude::{
+    AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, LayerAttributes, WritableImage,
+};
+use nalgebra::vector;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// Extra buffer rendered next to the image, for compositing and denoising. Values
+/// are linear and in scene units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aov {
+    Beauty,     // Colour of the image before it is converted to sRGB
+    Depth,      // Distance along the camera ray to the first hit
+    Normal,     // World space normal at the first hit
+    Albedo,     // Colour of the material at the first hit
+    Position,   // World space position of the first hit
+    ObjectId,   // Index of the top level object hit, starting at 1
+    MaterialId, // Same for materials with identical settings
+    Diffuse,    // Light that bounced diffusely off the first hit
+    Specular,   // Light that bounced specularly off the first hit
+    Emission,   // Light reaching the camera without bouncing
+    Variance,   // Variance of the pixel's mean luminance, for denoising
+}
+
+impl Aov {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Beauty => "beauty",
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Position => "position",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::Diffuse => "diffuse",
+            Aov::Specular => "specular",
+            Aov::Emission => "emission",
+            Aov::Variance => "variance",
+        }
+    }
+
+    /// Whether the pixel averages its samples. Depth, positions and IDs come from a
+    /// single ray through the centre of the pixel instead, so that they stay exact at
+    /// the edges of objects.
+    pub fn is_filtered(&self) -> bool {
+        !matches!(
+            self,
+            Aov::Depth | Aov::Position | Aov::ObjectId | Aov::MaterialId
+        )
+    }
+
+    /// Value for pixels where the camera sees the background.
+    pub(crate) fn background(&self) -> Vector {
+        match self {
+            Aov::Depth => vector![INFINITY, INFINITY, INFINITY],
+            _ => vector![0.0, 0.0, 0.0],
+        }
+    }
+
+    /// EXR channel names, with single values stored in one channel.
+    fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::Variance => &["Y"],
+            Aov::ObjectId | Aov::MaterialId => &["id"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            _ => &["R", "G", "B"],
+        }
+    }
+}
+
+const INFINITY: Float = Float::INFINITY;
+
+/// ID shared by materials of the same type and settings, which stays the same from
+/// one run to the next. It fits in 24 bits so that it is exact as a 32-bit float.
+pub fn material_id(material: &dyn Material) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    format!("{:?}", material).hash(&mut hasher);
+    (hasher.finish() & 0xff_ffff) as u32
+}
+
+/// Buffers for a set of AOVs, with pixels in rows from the top left.
+#[derive(Debug, Clone)]
+pub struct AovImage {
+    pub width: u32,
+    pub height: u32,
+    layers: Vec<(Aov, Vec<Vector>)>,
+}
+
+impl AovImage {
+    pub fn new(width: u32, height: u32, aovs: &[Aov]) -> AovImage {
+        let mut layers: Vec<(Aov, Vec<Vector>)> = Vec::new();
+        for aov in aovs {
+            if !layers.iter().any(|(existing, _)| existing == aov) {
+                layers.push((*aov, vec![aov.background(); (width * height) as usize]));
+            }
+        }
+        AovImage {
+            width,
+            height,
+            layers,
+        }
+    }
+
+    /// Drops the AOVs that are not in `aovs`.
+    pub fn retain(&mut self, aovs: &[Aov]) {
+        self.layers.retain(|(aov, _)| aovs.contains(aov));
+    }
+
+    pub fn aovs(&self) -> impl Iterator<Item = Aov> + '_ {
+        self.layers.iter().map(|(aov, _)| *aov)
+    }
+
+    pub fn layer(&self, aov: Aov) -> Option<&[Vector]> {
+        self.layers
+            .iter()
+            .find(|(existing, _)| *existing == aov)
+            .map(|(_, pixels)| pixels.as_slice())
+    }
+
+    pub fn layer_mut(&mut self, aov: Aov) -> Option<&mut [Vector]> {
+        self.layers
+            .iter_mut()
+            .find(|(existing, _)| *existing == aov)
+            .map(|(_, pixels)| pixels.as_mut_slice())
+    }
+
+    pub fn pixel(&self, aov: Aov, x: u32, y: u32) -> Option<Vector> {
+        self.layer(aov)
+            .map(|pixels| pixels[(y * self.width + x) as usize])
+    }
+
+    /// Copies a smaller image with the same AOVs into this one at the given offset.
+    pub fn copy_from(&mut self, tile: &AovImage, x: u32, y: u32) {
+        let width = self.width;
+        for ((_, pixels), (_, tile_pixels)) in self.layers.iter_mut().zip(&tile.layers) {
+            for row in 0..tile.height {
+                let start = ((y + row) * width + x) as usize;
+                let tile_start = (row * tile.width) as usize;
+                pixels[start..start + tile.width as usize]
+                    .copy_from_slice(&tile_pixels[tile_start..tile_start + tile.width as usize]);
+            }
+        }
+    }
+
+    /// Writes all AOVs to one EXR file, as layers named after the AOVs, such as
+    /// `normal.X` and `depth.Z`.
+    pub fn save_exr(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let channels: Vec<AnyChannel<FlatSamples>> = self
+            .layers
+            .iter()
+            .flat_map(|(aov, pixels)| {
+                aov.channels()
+                    .iter()
+                    .enumerate()
+                    .map(move |(index, channel)| {
+                        AnyChannel::new(
+                            format!("{}.{}", aov.name(), channel).as_str(),
+                            channel_samples(pixels, index),
+                        )
+                    })
+            })
+            .collect();
+        write_exr(path, self.width, self.height, channels)
+    }
+
+    /// Writes each AOV to its own EXR file, named by adding the AOV's name before the
+    /// extension of `path`, for example `frame.depth.exr`. Returns the paths written.
+    pub fn save_separate(&self, path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| format!("Invalid AOV path: {}", path.display()))?;
+        let mut paths = Vec::new();
+        for (aov, pixels) in &self.layers {
+            let aov_path = path.with_file_name(format!("{}.{}.exr", stem, aov.name()));
+            let channels = aov
+                .channels()
+                .iter()
+                .enumerate()
+                .map(|(index, channel)| AnyChannel::new(*channel, channel_samples(pixels, index)))
+                .collect();
+            write_exr(&aov_path, self.width, self.height, channels)?;
+            paths.push(aov_path);
+        }
+        Ok(paths)
+    }
+}
+
+fn channel_samples(pixels: &[Vector], index: usize) -> FlatSamples {
+    FlatSamples::F32(pixels.iter().map(|pixel| pixel[index] as f32).collect())
+}
+
+fn write_exr(
+    path: &Path,
+    width: u32,
+    height: u32,
+    channels: Vec<AnyChannel<FlatSamples>>,
+) -> Result<(), Box<dyn Error>> {
+    let layer = Layer::new(
+        (width as usize, height as usize),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels.into()),
+    );
+    Image::from_layer(layer).write().to_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::Lambertian;
+
+    #[test]
+    fn material_ids_depend_on_settings() {
+        let grey = Lambertian {
+            color: vector![0.5, 0.5, 0.5],
+        };
+        let same_grey = Lambertian {
+            color: vector![0.5, 0.5, 0.5],
+        };
+        let red = Lambertian {
+            color: vector![0.5, 0.0, 0.0],
+        };
+        assert_eq!(material_id(&grey), material_id(&same_grey));
+        assert_ne!(material_id(&grey), material_id(&red));
+        assert!(material_id(&red) < 1 << 24);
+    }
+
+    #[test]
+    fn writes_aovs_as_exr_layers_and_separate_files() {
+        let mut image = AovImage::new(3, 2, &[Aov::Depth, Aov::Normal, Aov::Depth]);
+        assert_eq!(
+            image.aovs().collect::<Vec<_>>(),
+            vec![Aov::Depth, Aov::Normal]
+        );
+        let mut tile = AovImage::new(2, 1, &[Aov::Depth, Aov::Normal]);
+        tile.layer_mut(Aov::Depth).unwrap()[1] = vector![2.5, 2.5, 2.5];
+        tile.layer_mut(Aov::Normal).unwrap()[1] = vector![0.0, 1.0, 0.0];
+        image.copy_from(&tile, 1, 1);
+        assert_eq!(image.pixel(Aov::Depth, 2, 1), Some(vector![2.5, 2.5, 2.5]));
+        assert_eq!(image.pixel(Aov::Depth, 0, 0).unwrap().x, INFINITY);
+
+        let directory = std::env::temp_dir().join("raytracer_aov");
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("frame.exr");
+        image.save_exr(&path).unwrap();
+        let read = exr::prelude::read_all_flat_layers_from_file(&path).unwrap();
+        let names: Vec<String> = read.layer_data[0]
+            .channel_data
+            .list
+            .iter()
+            .map(|channel| channel.name.to_string())
+            .collect();
+        assert_eq!(names, ["depth.Z", "normal.X", "normal.Y", "normal.Z"]);
+        let normal_y = &read.layer_data[0].channel_data.list[2].sample_data;
+        assert_eq!(normal_y.value_by_flat_index(5).to_f32(), 1.0);
+
+        let paths = image.save_separate(&path).unwrap();
+        assert_eq!(
+            paths,
+            [
+                directory.join("frame.depth.exr"),
+                directory.join("frame.normal.exr")
+            ]
+        );
+        let depth = exr::prelude::read_all_flat_layers_from_file(&paths[0]).unwrap();
+        let z = &depth.layer_data[0].channel_data.list[0];
+        assert_eq!(z.name.to_string(), "Z");
+        assert_eq!(z.sample_data.value_by_flat_index(5).to_f32(), 2.5);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::common::Float;
+use crate::sampling::Distribution2D;
+use nalgebra::{point, Point2};
+use std::error::Error;
+use std::f64::consts::PI;
+use std::path::Path;
+
+/// Shape of the lens opening, which out-of-focus highlights take on. Points on the
+/// aperture are in units of the aperture radius.
+#[derive(Debug, Clone, Default)]
+pub enum Aperture {
+    #[default]
+    Circle,
+    /// Regular polygon formed by `blades` straight diaphragm blades, with its corners
+    /// on the unit circle. A rotation of zero puts a corner at the top.
+    Polygon {
+        blades: u32,
+        rotation_degrees: Float,
+    },
+    Mask(ApertureMask),
+}
+
+/// Aperture drawn as an image, where brighter pixels let through more light. The
+/// image covers the square around the unit circle.
+#[derive(Debug, Clone)]
+pub struct ApertureMask {
+    distribution: Distribution2D,
+}
+
+impl Aperture {
+    /// Maps a uniform sample in [0, 1)² to a point on the aperture, distributed in
+    /// proportion to how much light passes there.
+    pub fn sample(&self, u: Point2<Float>) -> Point2<Float> {
+        match self {
+            Aperture::Circle => concentric_disk_point(u),
+            Aperture::Polygon {
+                blades,
+                rotation_degrees,
+            } => {
+                let blades = (*blades).max(3);
+                // Pick one of the triangles between the centre and each edge
+                let scaled = u.x * blades as Float;
+                let index = (scaled as u32).min(blades - 1);
+                let u_x = scaled - index as Float;
+                let corner = |i: u32| {
+                    let angle = PI / 2.0
+                        + rotation_degrees.to_radians()
+                        + 2.0 * PI * i as Float / blades as Float;
+                    point![angle.cos(), angle.sin()]
+                };
+                let (a, b) = (corner(index), corner(index + 1));
+                // Uniform point in the triangle with its third corner at the centre
+                let s = u_x.sqrt();
+                Point2::from(a.coords * (s * (1.0 - u.y)) + b.coords * (s * u.y))
+            }
+            Aperture::Mask(mask) => {
+                let (p, _) = mask.distribution.sample(u);
+                // Image rows run downwards
+                point![2.0 * p.x - 1.0, 1.0 - 2.0 * p.y]
+            }
+        }
+    }
+}
+
+impl ApertureMask {
+    /// Mask from row-major transmission values, starting at the top left.
+    pub fn new(width: usize, height: usize, values: &[Float]) -> ApertureMask {
+        assert_eq!(values.len(), width * height, "Mask needs a value per pixel");
+        ApertureMask {
+            distribution: Distribution2D::new(values, width, height),
+        }
+    }
+
+    /// Loads an image, using its brightness as the transmission.
+    pub fn load(path: &Path) -> Result<ApertureMask, Box<dyn Error>> {
+        let image = image::open(path)?.into_luma8();
+        let values: Vec<Float> = image.pixels().map(|p| p[0] as Float / 255.0).collect();
+        if values.iter().all(|v| *v == 0.0) {
+            return Err(format!("Aperture mask is black: {}", path.display()).into());
+        }
+        Ok(ApertureMask::new(
+            image.width() as usize,
+            image.height() as usize,
+            &values,
+        ))
+    }
+}
+
+/// Uniform point on the unit disk, mapping concentric squares to concentric circles
+/// so that stratified samples stay stratified.
+pub fn concentric_disk_point(u: Point2<Float>) -> Point2<Float> {
+    let x = 2.0 * u.x - 1.0;
+    let y = 2.0 * u.y - 1.0;
+    if x == 0.0 && y == 0.0 {
+        return Point2::origin();
+    }
+    let (radius, angle) = if x.abs() > y.abs() {
+        (x, PI / 4.0 * (y / x))
+    } else {
+        (y, PI / 2.0 - PI / 4.0 * (x / y))
+    };
+    point![radius * angle.cos(), radius * angle.sin()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn samples(aperture: &Aperture, count: usize) -> Vec<Point2<Float>> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..count)
+            .map(|_| aperture.sample(point![rng.gen(), rng.gen()]))
+            .collect()
+    }
+
+    #[test]
+    fn polygon_samples_fill_the_polygon_evenly() {
+        let aperture = Aperture::Polygon {
+            blades: 6,
+            rotation_degrees: 30.0,
+        };
+        let points = samples(&aperture, 20000);
+        // Rotated by 30 degrees the hexagon has flat edges at the top and bottom
+        let apothem = (PI / 6.0).cos();
+        assert!(points.iter().all(|p| p.y.abs() <= apothem + 1e-12));
+        let max_x = points.iter().map(|p| p.x).fold(0.0, Float::max);
+        assert!(max_x > 0.95 && max_x <= 1.0);
+        let upper = points.iter().filter(|p| p.y > 0.0).count() as Float;
+        let right = points.iter().filter(|p| p.x > 0.0).count() as Float;
+        assert_relative_eq!(upper / 20000.0, 0.5, epsilon = 0.01);
+        assert_relative_eq!(right / 20000.0, 0.5, epsilon = 0.01);
+        // A uniform density puts the area fraction of the central disk inside it
+        let inner = points.iter().filter(|p| p.coords.norm() < 0.5).count() as Float;
+        let hexagon_area = 3.0 * 3.0_f64.sqrt() / 2.0;
+        assert_relative_eq!(inner / 20000.0, PI * 0.25 / hexagon_area, epsilon = 0.01);
+    }
+
+    #[test]
+    fn circle_samples_are_uniform_on_the_disk() {
+        let points = samples(&Aperture::Circle, 20000);
+        assert!(points.iter().all(|p| p.coords.norm() <= 1.0 + 1e-12));
+        let inner = points.iter().filter(|p| p.coords.norm() < 0.5).count() as Float;
+        assert_relative_eq!(inner / 20000.0, 0.25, epsilon = 0.01);
+    }
+
+    #[test]
+    fn mask_samples_follow_the_image() {
+        // Light only passes through the top right quarter of the mask
+        let mask = ApertureMask::new(2, 2, &[0.0, 1.0, 0.0, 0.0]);
+        let points = samples(&Aperture::Mask(mask), 1000);
+        assert!(points.iter().all(|p| p.x >= 0.0 && p.y >= 0.0));
+        assert!(points.iter().any(|p| p.x > 0.9 && p.y > 0.9));
+    }
+}
@@ -0,0 +1,719 @@
+use crate::camera::Camera;
+use crate::common::Direction;
+use crate::common::Float;
+use crate::common::Point;
+use crate::common::Ray;
+use crate::common::RayIntersection;
+use crate::common::RayTracable;
+use crate::common::Vector;
+use crate::common::INFINITY;
+use crate::common::MIN_DIST;
+use crate::lights::Light;
+use crate::render::Film;
+use crate::render::FirstHit;
+use crate::render::PathEnd;
+use crate::render::PathSample;
+use crate::render::RayCounters;
+use crate::scene::Scene;
+use nalgebra::vector;
+use nalgebra::Point2;
+use nalgebra::Unit;
+use rand::Rng;
+use rand::RngCore;
+
+/// Bidirectional path tracing: a subpath from the camera and one from a light are
+/// connected in every possible way, and the strategies are weighted against each
+/// other with the balance heuristic. Only point and spot lights start subpaths of
+/// their own, while the environment and directional lights are found from the
+/// camera's side as the path tracer does. Connections to the camera, for pinhole
+/// cameras, land on arbitrary pixels and are splatted onto the film.
+pub(crate) fn trace_bidirectional<'a>(
+    ray: &Ray,
+    scene: &'a Scene,
+    film: &Film,
+    max_depth: u32,
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> PathSample<'a> {
+    let camera = film.camera;
+    let camera_path = camera_subpath(ray, scene, camera, max_depth, counters, rng);
+    let light_path = light_subpath(ray.time, scene, max_depth, counters, rng);
+    let connection = Connection {
+        scene,
+        camera,
+        camera_path: &camera_path,
+        light_path: &light_path,
+        light_choice: 1.0 / (scene.lights.len() + 1) as Float,
+        time: ray.time,
+    };
+
+    let mut sample = PathSample {
+        first_hit: camera_path.get(1).and_then(|vertex| match &vertex.kind {
+            VertexKind::Surface {
+                intersection,
+                object_index,
+                ..
+            } => Some(FirstHit::new(*object_index, intersection)),
+            _ => None,
+        }),
+        ..PathSample::default()
+    };
+    // A light vertex can always be sampled from the camera's side
+    let max_light_vertices = light_path.len().max(1);
+    for t in 1..=camera_path.len() {
+        for s in 0..=max_light_vertices {
+            // Paths need at least one segment, and lights can't be seen directly
+            if s + t < 2 || s + t - 1 > max_depth as usize || (s, t) == (1, 1) {
+                continue;
+            }
+            let Some((radiance, screen_position)) = connection.connect(s, t, counters, rng) else {
+                continue;
+            };
+            match screen_position {
+                Some(screen_position) => film.splat(screen_position, radiance),
+                None if s == 0 && t == 2 => sample.add(None, radiance),
+                // Connections at the first hit go through its non-specular lobes
+                None => sample.add(Some(t > 2 && camera_path[1].delta), radiance),
+            }
+        }
+    }
+    sample
+}
+
+enum VertexKind<'a> {
+    Camera,
+    Light(&'a dyn Light), // Point or spot light at the vertex's position
+    // The environment, or a directional light, infinitely far away along `direction`
+    Infinite {
+        light: Option<&'a dyn Light>,
+        direction: Direction,
+    },
+    Surface {
+        ray: Ray, // Arriving along the subpath
+        intersection: RayIntersection<'a>,
+        object_index: usize,
+    },
+}
+
+struct Vertex<'a> {
+    kind: VertexKind<'a>,
+    position: Point,
+    throughput: Vector, // Of the subpath up to and including the vertex
+    delta: bool,        // Scattered through a specular lobe
+    pdf_fwd: Float,     // Area density of sampling the vertex along its subpath
+    pdf_rev: Float,     // Area density of sampling it from the other end
+}
+
+impl<'a> Vertex<'a> {
+    fn is_infinite(&self) -> bool {
+        matches!(self.kind, VertexKind::Infinite { .. })
+    }
+
+    fn is_delta_light(&self) -> bool {
+        matches!(
+            self.kind,
+            VertexKind::Light(_) | VertexKind::Infinite { light: Some(_), .. }
+        )
+    }
+
+    fn normal(&self) -> Option<Direction> {
+        match &self.kind {
+            VertexKind::Surface { intersection, .. } => Some(intersection.normal),
+            _ => None,
+        }
+    }
+
+    fn direction_to(&self, other: &Vertex) -> Direction {
+        match (&self.kind, &other.kind) {
+            (_, VertexKind::Infinite { direction, .. }) => *direction,
+            (VertexKind::Infinite { direction, .. }, _) => -*direction,
+            _ => Unit::new_normalize(other.position - self.position),
+        }
+    }
+
+    /// Turns a solid angle density at this vertex into an area density at `next`.
+    fn convert_density(&self, pdf: Float, next: &Vertex) -> Float {
+        if next.is_infinite() {
+            return pdf;
+        }
+        let offset = next.position - self.position;
+        let distance_squared = offset.norm_squared();
+        let cos = next.normal().map_or(1.0, |normal| {
+            normal.dot(&offset).abs() / distance_squared.sqrt()
+        });
+        pdf * cos / distance_squared
+    }
+
+    /// Area density with which a subpath that arrived here from `previous` continues
+    /// to `next`.
+    fn pdf(&self, camera: &dyn Camera, previous: Option<&Vertex>, next: &Vertex) -> Float {
+        let direction = self.direction_to(next);
+        let pdf = match &self.kind {
+            VertexKind::Camera => camera.importance(&direction).map_or(0.0, |i| i.pdf),
+            VertexKind::Light(light) => light.emission_pdf(&direction),
+            // Neither the environment nor directional lights start subpaths
+            VertexKind::Infinite { .. } => 0.0,
+            VertexKind::Surface {
+                ray, intersection, ..
+            } => {
+                let incoming = match previous {
+                    Some(previous) => Ray {
+                        origin: previous.position,
+                        direction: previous.direction_to(self),
+                        time: ray.time,
+                    },
+                    None => ray.clone(),
+                };
+                intersection
+                    .material
+                    .pdf(&incoming, intersection, &direction)
+            }
+        };
+        self.convert_density(pdf, next)
+    }
+}
+
+fn camera_subpath<'a>(
+    ray: &Ray,
+    scene: &'a Scene,
+    camera: &dyn Camera,
+    max_depth: u32,
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> Vec<Vertex<'a>> {
+    let mut path = vec![Vertex {
+        kind: VertexKind::Camera,
+        position: ray.origin,
+        throughput: vector![1.0, 1.0, 1.0],
+        delta: false,
+        pdf_fwd: 0.0,
+        pdf_rev: 0.0,
+    }];
+    let pdf = camera.importance(&ray.direction).map_or(0.0, |i| i.pdf);
+    counters.primary += 1;
+    let end = random_walk(
+        ray.clone(),
+        vector![1.0, 1.0, 1.0],
+        pdf,
+        max_depth,
+        scene,
+        &mut path,
+        counters,
+        rng,
+    );
+    let surfaces = path
+        .iter()
+        .filter(|vertex| vertex.normal().is_some())
+        .count();
+    counters.end_path(surfaces as u32, end);
+    path
+}
+
+/// Subpath from a light picked uniformly among the lights and the environment, which
+/// is empty if that light can't start one.
+fn light_subpath<'a>(
+    time: Float,
+    scene: &'a Scene,
+    max_depth: u32,
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> Vec<Vertex<'a>> {
+    let light_count = scene.lights.len() + 1;
+    let light_choice = 1.0 / light_count as Float;
+    let Some(light) = scene.lights.get(rng.gen_range(0..light_count)) else {
+        return Vec::new();
+    };
+    let Some(emitted) = light.sample_emission(rng) else {
+        return Vec::new();
+    };
+    let mut path = vec![Vertex {
+        kind: VertexKind::Light(light.as_ref()),
+        position: emitted.origin,
+        throughput: emitted.intensity / light_choice,
+        delta: false,
+        pdf_fwd: light_choice,
+        pdf_rev: 0.0,
+    }];
+    let ray = Ray {
+        origin: emitted.origin,
+        direction: emitted.direction,
+        time,
+    };
+    counters.secondary += 1;
+    random_walk(
+        ray,
+        emitted.intensity / (light_choice * emitted.pdf),
+        emitted.pdf,
+        max_depth.saturating_sub(1),
+        scene,
+        &mut path,
+        counters,
+        rng,
+    );
+    path
+}
+
+/// Extends `path` by up to `bounces` vertices, starting with `ray` which was sampled
+/// with solid angle density `pdf`. Camera subpaths end in a vertex for the
+/// environment if they escape.
+#[allow(clippy::too_many_arguments)]
+fn random_walk<'a>(
+    mut ray: Ray,
+    mut throughput: Vector,
+    mut pdf: Float,
+    bounces: u32,
+    scene: &'a Scene,
+    path: &mut Vec<Vertex<'a>>,
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> PathEnd {
+    let from_camera = matches!(path[0].kind, VertexKind::Camera);
+    for bounce in 0..bounces {
+        if bounce > 0 {
+            counters.secondary += 1;
+        }
+        let Some((object_index, intersection)) = scene
+            .objects
+            .trace_ray_with_index(&ray, MIN_DIST, INFINITY, rng)
+        else {
+            if from_camera {
+                path.push(Vertex {
+                    kind: VertexKind::Infinite {
+                        light: None,
+                        direction: ray.direction,
+                    },
+                    position: ray.origin,
+                    throughput,
+                    delta: false,
+                    pdf_fwd: pdf,
+                    pdf_rev: 0.0,
+                });
+            }
+            return PathEnd::Escaped;
+        };
+        let material = intersection.material;
+        let scattered = if bounce + 1 < bounces {
+            material.scatter_ray(&ray, &intersection, rng)
+        } else {
+            None
+        };
+        let (next_pdf, reverse_pdf, delta) = match &scattered {
+            Some(scattered) if scattered.specular => (0.0, 0.0, true),
+            Some(scattered) => {
+                let reversed = Ray {
+                    origin: scattered.ray.at(1.0),
+                    direction: -scattered.ray.direction,
+                    time: ray.time,
+                };
+                (
+                    material.pdf(&ray, &intersection, &scattered.ray.direction),
+                    material.pdf(&reversed, &intersection, &-ray.direction),
+                    false,
+                )
+            }
+            None => (0.0, 0.0, false),
+        };
+
+        let mut vertex = Vertex {
+            position: intersection.position,
+            kind: VertexKind::Surface {
+                ray: ray.clone(),
+                intersection,
+                object_index,
+            },
+            throughput,
+            delta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        };
+        let previous = path.last_mut().unwrap();
+        vertex.pdf_fwd = previous.convert_density(pdf, &vertex);
+        previous.pdf_rev = vertex.convert_density(reverse_pdf, previous);
+        path.push(vertex);
+
+        let Some(scattered) = scattered else {
+            return if bounce + 1 < bounces {
+                PathEnd::Absorbed
+            } else {
+                PathEnd::MaxDepth
+            };
+        };
+        throughput = throughput.component_mul(&scattered.attenuation);
+        if throughput == vector![0.0, 0.0, 0.0] {
+            return PathEnd::Absorbed;
+        }
+        pdf = next_pdf;
+        ray = scattered.ray;
+    }
+    PathEnd::MaxDepth
+}
+
+/// The subpaths of one sample and how to join them.
+struct Connection<'s, 'a> {
+    scene: &'a Scene,
+    camera: &'s dyn Camera,
+    camera_path: &'s [Vertex<'a>],
+    light_path: &'s [Vertex<'a>],
+    light_choice: Float, // Probability of picking each light
+    time: Float,
+}
+
+impl<'a> Connection<'_, 'a> {
+    /// MIS weighted light carried by the path made of the first `s` light vertices
+    /// and the first `t` camera vertices, and where it lands on the screen if it was
+    /// connected to the camera.
+    fn connect(
+        &self,
+        s: usize,
+        t: usize,
+        counters: &mut RayCounters,
+        rng: &mut dyn RngCore,
+    ) -> Option<(Vector, Option<Point2<Float>>)> {
+        let pt = &self.camera_path[t - 1];
+        let mut sampled = None;
+        let mut screen_position = None;
+        let radiance = if s == 0 {
+            // The camera subpath escaped to the environment
+            let VertexKind::Infinite { direction, .. } = pt.kind else {
+                return None;
+            };
+            pt.throughput
+                .component_mul(&self.scene.environment.radiance(&direction))
+        } else if t == 1 {
+            let qs = &self.light_path[s - 1];
+            let VertexKind::Surface {
+                ray, intersection, ..
+            } = &qs.kind
+            else {
+                return None;
+            };
+            let pinhole = self.camera.pinhole()?;
+            let offset = pinhole - qs.position;
+            let direction = Unit::new_normalize(offset);
+            let importance = self.camera.importance(&-direction)?;
+            let f = intersection.material.eval(ray, intersection, &direction);
+            let camera_vertex = Vertex {
+                kind: VertexKind::Camera,
+                position: pinhole,
+                throughput: vector![1.0, 1.0, 1.0],
+                delta: false,
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0,
+            };
+            let radiance = qs.throughput.component_mul(&f)
+                * (importance.importance / offset.norm_squared())
+                * self.transmittance(&qs.position, &camera_vertex, counters, rng)?;
+            sampled = Some(camera_vertex);
+            screen_position = Some(importance.screen_position);
+            radiance
+        } else if s == 1 {
+            let VertexKind::Surface {
+                ray, intersection, ..
+            } = &pt.kind
+            else {
+                return None;
+            };
+            let light_count = self.scene.lights.len() + 1;
+            let (direction, radiance, light_vertex) =
+                match self.scene.lights.get(rng.gen_range(0..light_count)) {
+                    Some(light) => {
+                        let sample = light.sample_incident(&pt.position)?;
+                        let kind = if sample.distance.is_infinite() {
+                            VertexKind::Infinite {
+                                light: Some(light.as_ref()),
+                                direction: sample.direction,
+                            }
+                        } else {
+                            VertexKind::Light(light.as_ref())
+                        };
+                        let vertex = Vertex {
+                            kind,
+                            position: pt.position + sample.direction.into_inner() * sample.distance,
+                            throughput: vector![1.0, 1.0, 1.0],
+                            delta: false,
+                            pdf_fwd: self.light_choice,
+                            pdf_rev: 0.0,
+                        };
+                        (
+                            sample.direction,
+                            sample.radiance / self.light_choice,
+                            vertex,
+                        )
+                    }
+                    None => {
+                        let sample = self.scene.environment.sample(rng)?;
+                        let pdf = self.light_choice * sample.pdf;
+                        let vertex = Vertex {
+                            kind: VertexKind::Infinite {
+                                light: None,
+                                direction: sample.direction,
+                            },
+                            position: pt.position,
+                            throughput: vector![1.0, 1.0, 1.0],
+                            delta: false,
+                            pdf_fwd: pdf,
+                            pdf_rev: 0.0,
+                        };
+                        (sample.direction, sample.radiance / pdf, vertex)
+                    }
+                };
+            let f = intersection.material.eval(ray, intersection, &direction);
+            if f == vector![0.0, 0.0, 0.0] {
+                return None;
+            }
+            let radiance = pt.throughput.component_mul(&f).component_mul(&radiance)
+                * self.transmittance(&pt.position, &light_vertex, counters, rng)?;
+            sampled = Some(light_vertex);
+            radiance
+        } else {
+            let qs = &self.light_path[s - 1];
+            let (
+                VertexKind::Surface {
+                    ray: camera_ray,
+                    intersection: camera_hit,
+                    ..
+                },
+                VertexKind::Surface {
+                    ray: light_ray,
+                    intersection: light_hit,
+                    ..
+                },
+            ) = (&pt.kind, &qs.kind)
+            else {
+                return None;
+            };
+            let offset = qs.position - pt.position;
+            let direction = Unit::new_normalize(offset);
+            let f_camera = camera_hit.material.eval(camera_ray, camera_hit, &direction);
+            let f_light = light_hit.material.eval(light_ray, light_hit, &-direction);
+            if f_camera == vector![0.0, 0.0, 0.0] || f_light == vector![0.0, 0.0, 0.0] {
+                return None;
+            }
+            pt.throughput
+                .component_mul(&f_camera)
+                .component_mul(&f_light)
+                .component_mul(&qs.throughput)
+                / offset.norm_squared()
+                * self.transmittance(&pt.position, qs, counters, rng)?
+        };
+        if radiance == vector![0.0, 0.0, 0.0] {
+            return None;
+        }
+        Some((
+            radiance * self.mis_weight(s, t, sampled.as_ref()),
+            screen_position,
+        ))
+    }
+
+    /// Fraction of light getting from `from` to `to`, or None if it is blocked.
+    fn transmittance(
+        &self,
+        from: &Point,
+        to: &Vertex,
+        counters: &mut RayCounters,
+        rng: &mut dyn RngCore,
+    ) -> Option<Float> {
+        counters.shadow += 1;
+        let (direction, max_dist) = match &to.kind {
+            VertexKind::Infinite { direction, .. } => (*direction, INFINITY),
+            _ => {
+                let offset = to.position - from;
+                let distance = offset.norm();
+                (
+                    Unit::new_normalize(offset),
+                    distance * (1.0 - 1e-9) - MIN_DIST,
+                )
+            }
+        };
+        let shadow_ray = Ray {
+            origin: *from,
+            direction,
+            time: self.time,
+        };
+        let transmittance = self
+            .scene
+            .objects
+            .transmittance(&shadow_ray, MIN_DIST, max_dist, rng);
+        (transmittance > 0.0).then_some(transmittance)
+    }
+
+    /// Balance heuristic weight of the (s, t) strategy against the others that could
+    /// have made the same path. `sampled` replaces the endpoint that the connection
+    /// picked itself when s or t is 1.
+    fn mis_weight(&self, s: usize, t: usize, sampled: Option<&Vertex<'a>>) -> Float {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let light_vertex = |i: usize| match sampled {
+            Some(vertex) if s == 1 && i == 0 => vertex,
+            _ => &self.light_path[i],
+        };
+        let camera_vertex = |i: usize| match sampled {
+            Some(vertex) if t == 1 && i == 0 => vertex,
+            _ => &self.camera_path[i],
+        };
+        // Densities (forward, reverse) and delta flags, with the reverse densities
+        // next to the connection following from it
+        let mut light: Vec<(Float, Float, bool)> = (0..s)
+            .map(&light_vertex)
+            .map(|vertex| (vertex.pdf_fwd, vertex.pdf_rev, vertex.delta))
+            .collect();
+        let mut camera: Vec<(Float, Float, bool)> = (0..t)
+            .map(&camera_vertex)
+            .map(|vertex| (vertex.pdf_fwd, vertex.pdf_rev, vertex.delta))
+            .collect();
+        let pt = camera_vertex(t - 1);
+        let pt_minus = (t > 1).then(|| camera_vertex(t - 2));
+        let qs = (s > 0).then(|| light_vertex(s - 1));
+        let qs_minus = (s > 1).then(|| light_vertex(s - 2));
+
+        // The connected vertices scatter through their non-specular lobes
+        camera[t - 1].2 = false;
+        camera[t - 1].1 = match qs {
+            Some(qs) => qs.pdf(self.camera, qs_minus, pt),
+            // The escaped camera subpath as seen from sampling the environment
+            None => match pt.kind {
+                VertexKind::Infinite { direction, .. } => {
+                    self.light_choice * self.scene.environment.pdf(&direction)
+                }
+                _ => 0.0,
+            },
+        };
+        if let (Some(pt_minus), Some(qs)) = (pt_minus, qs) {
+            camera[t - 2].1 = pt.pdf(self.camera, Some(qs), pt_minus);
+        }
+        if let Some(qs) = qs {
+            light[s - 1].2 = false;
+            light[s - 1].1 = pt.pdf(self.camera, pt_minus, qs);
+        }
+        if let (Some(qs), Some(qs_minus)) = (qs, qs_minus) {
+            light[s - 2].1 = qs.pdf(self.camera, Some(pt), qs_minus);
+        }
+
+        // Only point and spot lights start subpaths, and only pinholes can be hit
+        let light_start = qs.map_or(pt, |_| light_vertex(0));
+        let emits = matches!(light_start.kind, VertexKind::Light(_));
+        let connectable = self.camera.pinhole().is_some();
+        let remap = |pdf: Float| if pdf == 0.0 { 1.0 } else { pdf };
+
+        let mut sum = 0.0;
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            // Sampling camera vertex i from the light instead
+            if s + t - i >= 2 && !emits {
+                break;
+            }
+            ratio *= remap(camera[i].1) / remap(camera[i].0);
+            if !camera[i].2 && !camera[i - 1].2 && (i > 1 || connectable) {
+                sum += ratio;
+            }
+        }
+        let mut ratio = 1.0;
+        for i in (0..s).rev() {
+            // Sampling light vertex i from the camera instead
+            ratio *= remap(light[i].1) / remap(light[i].0);
+            let delta_before = if i > 0 {
+                light[i - 1].2
+            } else {
+                light_start.is_delta_light()
+            };
+            if !light[i].2 && !delta_before {
+                sum += ratio;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::GradientEnvironment;
+    use crate::integrator::Integrator;
+    use crate::lights::PointLight;
+    use crate::lights::SpotLight;
+    use crate::materials::Lambertian;
+    use crate::scene::SceneList;
+    use crate::scene::Sphere;
+    use crate::test_scenes::band_colour;
+    use crate::test_scenes::config;
+    use crate::test_scenes::floor;
+    use crate::test_scenes::mirror_image_scene;
+    use crate::test_scenes::mirror_scene;
+    use crate::test_scenes::render;
+    use crate::test_scenes::FLOOR_ROWS;
+    use nalgebra::point;
+
+    fn scene(sphere_material: Box<dyn crate::common::Material>) -> Scene {
+        Scene {
+            objects: SceneList {
+                objects: vec![
+                    floor(),
+                    Box::new(Sphere {
+                        center: point![0.0, 1.0, 0.0],
+                        radius: 0.7,
+                        material: sphere_material,
+                    }),
+                ],
+            },
+            environment: Box::new(GradientEnvironment {
+                bottom: vector![0.0, 0.0, 0.0],
+                top: vector![0.2, 0.3, 0.4],
+            }),
+            lights: vec![
+                Box::new(PointLight {
+                    position: point![2.0, 3.0, 2.0],
+                    intensity: vector![8.0, 8.0, 8.0],
+                }),
+                Box::new(SpotLight {
+                    position: point![-2.0, 2.5, 1.0],
+                    direction: Unit::new_normalize(vector![1.0, -1.0, -0.5]),
+                    intensity: vector![4.0, 2.0, 1.0],
+                    cone_angle_degrees: 40.0,
+                    falloff_start_degrees: 30.0,
+                }),
+            ],
+        }
+    }
+
+    /// Mean linear colour of a horizontal band across the image.
+    fn mean_colour(scene: &Scene, integrator: Integrator, rows: (u32, u32)) -> Vector {
+        band_colour(&render(scene, &config(integrator)), rows)
+    }
+
+    #[test]
+    fn agrees_with_path_tracer_on_diffuse_scene() {
+        let scene = scene(Box::new(Lambertian {
+            color: vector![0.8, 0.5, 0.3],
+        }));
+        let all_rows = (0, config(Integrator::PathTracer).height);
+        let path_traced = mean_colour(&scene, Integrator::PathTracer, all_rows);
+        let bidirectional = mean_colour(&scene, Integrator::Bidirectional, all_rows);
+        for channel in 0..3 {
+            let relative = bidirectional[channel] / path_traced[channel];
+            assert!(
+                (relative - 1.0).abs() < 0.05,
+                "{} vs {}",
+                bidirectional,
+                path_traced
+            );
+        }
+    }
+
+    #[test]
+    fn finds_caustics_the_path_tracer_misses() {
+        // Light reflected by a mirror wall onto the floor can only be found by tracing
+        // from the light, and looks as if it came from the light's mirror image
+        let mirrored = mirror_scene();
+        let path_traced = mean_colour(&mirrored, Integrator::PathTracer, FLOOR_ROWS);
+        let bidirectional = mean_colour(&mirrored, Integrator::Bidirectional, FLOOR_ROWS);
+        let expected = mean_colour(&mirror_image_scene(), Integrator::PathTracer, FLOOR_ROWS);
+        assert!(
+            (bidirectional.x / expected.x - 1.0).abs() < 0.1,
+            "{} vs {}",
+            bidirectional,
+            expected
+        );
+        assert!(path_traced.x < expected.x * 0.8);
+    }
+}
@@ -1,7 +1,9 @@
+use crate::aperture::Aperture;
 use crate::common::Direction;
 use crate::common::Float;
 use crate::common::Point;
 use crate::common::Ray;
+use crate::common::Vector;
 use nalgebra::Isometry3;
 
 use nalgebra::point;
@@ -11,72 +13,965 @@ use nalgebra::Point2;
 
 use nalgebra::Transform3;
 use nalgebra::Unit;
+use nalgebra::UnitQuaternion;
+use std::f64::consts::PI;
 
-pub struct Camera {
+pub trait Camera: Sync + Send {
+    /// Ray through a point on the screen, whose coordinates run from -1 to 1 with y
+    /// up, at a time `shutter_position` of the way through the shutter interval.
+    /// `lens_sample` is uniform in [0, 1)² and picks where on the lens the ray starts.
+    /// Points outside the projection's image, such as the corners of a circular
+    /// fisheye, and rays blocked inside the lens give None.
+    fn generate_ray(
+        &self,
+        screen_position: Point2<Float>,
+        lens_sample: Point2<Float>,
+        shutter_position: Float,
+    ) -> Option<Ray>;
+
+    /// Position of a pinhole that paths traced from the lights can be connected to.
+    /// Cameras with a lens or a projection without a single centre return None and
+    /// only see light through paths that start at the camera.
+    fn pinhole(&self) -> Option<Point> {
+        None
+    }
+
+    /// Importance of the ray leaving the pinhole along `direction`, or None if it
+    /// falls outside the image.
+    fn importance(&self, _direction: &Direction) -> Option<CameraImportance> {
+        None
+    }
+}
+
+/// Where a ray from a pinhole camera lands on the screen and how much it counts.
+/// The importance includes the cosine at the pinhole and is normalized so that it
+/// integrates to one over the solid angle of the whole image.
+#[derive(Debug)]
+pub struct CameraImportance {
+    pub screen_position: Point2<Float>,
+    pub importance: Float,
+    pub pdf: Float, // Solid angle density with which generate_ray() picks the direction
+}
+
+/// Thin lens camera with a perspective projection.
+pub struct PerspectiveCamera {
     pub origin: Point,         // Origin of the lens
     pub direction: Direction,  // Direction that the lens is looking
-    pub focal_length: Float,   // Assuming 35mm sensor (36x24mm)
+    pub focal_length: Float,   // In metres
     pub focus_distance: Float, // Distance from the lens to the focal plane
     pub f_number: Float,       // f-number: f/f_number
+    pub shutter_open: Float,   // Time when the shutter opens
+    pub shutter_close: Float,  // Time when the shutter closes
+    aperture: Aperture,
+    cats_eye: Float,
+    aspect_ratio: Float,
+    isometry: Isometry3<Float>,
+    image_extent: (Float, Float), // Half the width and height of the image at unit distance
     transform: Transform3<Float>,
     lens_transformation: Transform3<Float>,
 }
 
-const SENSOR_DIAGONAL_MM: Float = 43.3;
-const SENSOR_HEIGHT_MM: Float = 24.0;
+/// Camera with parallel rays, for architectural views without perspective. The view
+/// is `height` high in scene units.
+pub struct OrthographicCamera {
+    view: View,
+    height: Float,
+    aspect_ratio: Float,
+}
 
-impl Camera {
-    pub fn new(
-        origin: Point,
-        look_at: Point,
-        field_of_view_height_degrees: Float,
-        f_number: Float,
-        aspect_ratio: Float,
-    ) -> Camera {
-        let fov = field_of_view_height_degrees / 180.0 * std::f64::consts::PI;
-        let focal_length = (SENSOR_DIAGONAL_MM / 1000.0 / 2.0) / (fov / 2.0).tan();
-        let focus_vector = look_at - origin;
-        let focus_distance = focus_vector.norm();
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FisheyeMapping {
+    Equidistant, // Distance from the centre proportional to the angle
+    Equisolid,   // Area in the image proportional to solid angle
+}
+
+/// Circular fisheye whose image circle fills the height of the image.
+/// `field_of_view_degrees` is the angle across the circle and may exceed 180.
+pub struct FisheyeCamera {
+    view: View,
+    mapping: FisheyeMapping,
+    half_angle: Float,
+    aspect_ratio: Float,
+}
+
+/// Full 360 by 180 degree spherical panorama in the equirectangular layout used by
+/// VR viewers, which needs an aspect ratio of 2. The centre of the image looks at
+/// `look_at`.
+pub struct EquirectangularCamera {
+    view: View,
+}
+
+/// Panorama projected onto a cylinder around the up axis. Horizontal angles are
+/// spread evenly across the image while vertical lines stay straight.
+pub struct CylindricalCamera {
+    view: View,
+    half_angle: Float,
+    aspect_ratio: Float,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+/// How the two eyes of a stereo pair share the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoLayout {
+    SideBySide, // Left eye in the left half
+    TopBottom,  // Left eye in the top half
+}
+
+/// Left and right eye views rendered together into one image. Each eye's camera
+/// should have the aspect ratio of its half of the image.
+pub struct StereoCamera {
+    left: Box<dyn Camera>,
+    right: Box<dyn Camera>,
+    layout: StereoLayout,
+}
+
+/// One eye of an omni-directional stereo panorama in the equirectangular layout. Every
+/// column is seen from the eye's position as the head turns to face it, so each ray
+/// starts on a circle with a diameter of the interpupillary distance.
+pub struct OmniStereoCamera {
+    view: View,
+    eye_offset: Float, // Signed distance of the eye to the right of the head's centre
+}
+
+/// Position, orientation and shutter interval shared by all projections. In camera
+/// space the camera looks along -z with y up.
+struct View {
+    isometry: Isometry3<Float>,
+    shutter: (Float, Float),
+}
+
+impl View {
+    fn ray(&self, direction: Vector, shutter_position: Float) -> Ray {
+        self.ray_from(Point::origin(), direction, shutter_position)
+    }
+
+    fn ray_from(&self, origin: Point, direction: Vector, shutter_position: Float) -> Ray {
+        let (open, close) = self.shutter;
+        Ray {
+            origin: self.isometry * origin,
+            direction: Unit::new_normalize(self.isometry * direction),
+            time: open + (close - open) * shutter_position,
+        }
+    }
+}
+
+/// Size of the image sensor, which together with the focal length sets the field of
+/// view and the depth of field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sensor {
+    pub width_mm: Float,
+    pub height_mm: Float,
+}
+
+impl Sensor {
+    pub const FULL_FRAME: Sensor = Sensor {
+        width_mm: 36.0,
+        height_mm: 24.0,
+    };
+    pub const APS_C: Sensor = Sensor {
+        width_mm: 23.6,
+        height_mm: 15.6,
+    };
+    pub const SUPER_35: Sensor = Sensor {
+        width_mm: 24.89,
+        height_mm: 18.66,
+    };
+
+    /// Height of the largest area with the image's aspect ratio that fits on the sensor.
+    fn image_height_mm(&self, aspect_ratio: Float) -> Float {
+        (self.width_mm / aspect_ratio).min(self.height_mm)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Lens {
+    FieldOfView(Float), // Vertical, in degrees
+    FocalLength(Float), // In millimetres
+}
+
+/// Settings shared by all cameras, and those of the thin lens for perspective
+/// cameras. By default it has a 50mm lens on a full-frame sensor,
+/// is focused on `look_at`, and is a pinhole camera with everything in focus.
+#[derive(Debug, Clone)]
+pub struct CameraBuilder {
+    origin: Point,
+    look_at: Point,
+    up: Vector,
+    roll_degrees: Float,
+    lens: Lens,
+    sensor: Sensor,
+    focus_distance: Option<Float>,
+    f_number: Float,
+    aperture: Aperture,
+    cats_eye: Float,
+    aspect_ratio: Option<Float>,
+    shutter: (Float, Float),
+}
+
+impl CameraBuilder {
+    /// Vertical field of view of the image. The focal length follows from the sensor.
+    pub fn field_of_view_degrees(mut self, degrees: Float) -> CameraBuilder {
+        self.lens = Lens::FieldOfView(degrees);
+        self
+    }
+
+    pub fn focal_length_mm(mut self, millimetres: Float) -> CameraBuilder {
+        self.lens = Lens::FocalLength(millimetres);
+        self
+    }
+
+    pub fn sensor(mut self, sensor: Sensor) -> CameraBuilder {
+        self.sensor = sensor;
+        self
+    }
+
+    /// Distance to the plane in focus, instead of the distance to `look_at`.
+    pub fn focus_distance(mut self, distance: Float) -> CameraBuilder {
+        self.focus_distance = Some(distance);
+        self
+    }
+
+    /// Aperture as a fraction of the focal length. Zero gives a pinhole camera.
+    pub fn f_number(mut self, f_number: Float) -> CameraBuilder {
+        self.f_number = f_number;
+        self
+    }
+
+    /// Width over height of the image, which defaults to the sensor's. Images that are
+    /// narrower or wider than the sensor use the largest part of it that fits.
+    pub fn aspect_ratio(mut self, aspect_ratio: Float) -> CameraBuilder {
+        self.aspect_ratio = Some(aspect_ratio);
+        self
+    }
+
+    /// Direction that is up in the image, before roll.
+    pub fn up(mut self, up: Vector) -> CameraBuilder {
+        self.up = up;
+        self
+    }
+
+    /// Rotation about the viewing direction, counterclockwise as seen from behind.
+    pub fn roll_degrees(mut self, degrees: Float) -> CameraBuilder {
+        self.roll_degrees = degrees;
+        self
+    }
+
+    pub fn aperture(mut self, aperture: Aperture) -> CameraBuilder {
+        self.aperture = aperture;
+        self
+    }
+
+    /// Vignetting by the lens barrel, which clips out-of-focus highlights towards the
+    /// edges of the image into cat's eye shapes and darkens the corners. It is how far
+    /// the clipping circle has moved at the corners, in aperture radii, so 2 leaves the
+    /// corners black.
+    pub fn cats_eye(mut self, strength: Float) -> CameraBuilder {
+        self.cats_eye = strength;
+        self
+    }
+
+    pub fn shutter(mut self, open: Float, close: Float) -> CameraBuilder {
+        self.shutter = (open, close);
+        self
+    }
+
+    pub fn build_orthographic(self, height: Float) -> OrthographicCamera {
+        OrthographicCamera {
+            aspect_ratio: self.image_aspect_ratio(),
+            view: self.view(),
+            height,
+        }
+    }
+
+    pub fn build_fisheye(
+        self,
+        mapping: FisheyeMapping,
+        field_of_view_degrees: Float,
+    ) -> FisheyeCamera {
+        FisheyeCamera {
+            aspect_ratio: self.image_aspect_ratio(),
+            view: self.view(),
+            mapping,
+            half_angle: field_of_view_degrees.to_radians() / 2.0,
+        }
+    }
+
+    pub fn build_equirectangular(self) -> EquirectangularCamera {
+        EquirectangularCamera { view: self.view() }
+    }
 
-        let scale_y = SENSOR_HEIGHT_MM / 1000.0 / 2.0 * (focus_distance / focal_length);
+    /// Panorama spanning `field_of_view_degrees` horizontally, up to 360.
+    pub fn build_cylindrical(self, field_of_view_degrees: Float) -> CylindricalCamera {
+        CylindricalCamera {
+            aspect_ratio: self.image_aspect_ratio(),
+            view: self.view(),
+            half_angle: field_of_view_degrees.to_radians() / 2.0,
+        }
+    }
+
+    pub fn build_omnidirectional_stereo(
+        self,
+        eye: Eye,
+        interpupillary_distance: Float,
+    ) -> OmniStereoCamera {
+        OmniStereoCamera {
+            view: self.view(),
+            eye_offset: eye.side() * interpupillary_distance / 2.0,
+        }
+    }
+
+    /// Settings for one eye of a stereo pair, moved sideways from the origin by half
+    /// the interpupillary distance. The eyes look parallel to each other, or turn in
+    /// to meet at `convergence_distance` in front of the camera. They keep the focus
+    /// distance of the original camera.
+    pub fn eye(
+        &self,
+        eye: Eye,
+        interpupillary_distance: Float,
+        convergence_distance: Option<Float>,
+    ) -> CameraBuilder {
+        let forward = self.look_at - self.origin;
+        let right = self.view().isometry * Vector::x();
+        let offset = right * eye.side() * interpupillary_distance / 2.0;
+        let look_at = match convergence_distance {
+            Some(distance) => self.origin + forward.normalize() * distance,
+            None => self.look_at + offset,
+        };
+        CameraBuilder {
+            origin: self.origin + offset,
+            look_at,
+            focus_distance: Some(self.focus_distance.unwrap_or(forward.norm())),
+            ..self.clone()
+        }
+    }
+
+    fn image_aspect_ratio(&self) -> Float {
+        self.aspect_ratio
+            .unwrap_or(self.sensor.width_mm / self.sensor.height_mm)
+    }
+
+    fn view(&self) -> View {
+        let isometry =
+            Isometry3::face_towards(
+                &self.origin,
+                &(self.origin - (self.look_at - self.origin)),
+                &self.up,
+            ) * UnitQuaternion::from_axis_angle(&Vector::z_axis(), self.roll_degrees.to_radians());
+        View {
+            isometry,
+            shutter: self.shutter,
+        }
+    }
+
+    pub fn build(self) -> PerspectiveCamera {
+        let aspect_ratio = self.image_aspect_ratio();
+        let image_height = self.sensor.image_height_mm(aspect_ratio) / 1000.0;
+        let focal_length = match self.lens {
+            Lens::FieldOfView(degrees) => image_height / 2.0 / (degrees.to_radians() / 2.0).tan(),
+            Lens::FocalLength(millimetres) => millimetres / 1000.0,
+        };
+        let focus_vector = self.look_at - self.origin;
+        let focus_distance = self.focus_distance.unwrap_or(focus_vector.norm());
+
+        // The image spans the sensor projected through the lens onto the focal plane
+        let scale_y = image_height / 2.0 * (focus_distance / focal_length);
         let scale_x = scale_y * aspect_ratio;
         let scale = Matrix4::new_nonuniform_scaling(&vector![scale_x, scale_y, 1.0]);
         let translate = Matrix4::new_translation(&vector![0.0, 0.0, -focus_distance]);
-        let isometry = Isometry3::face_towards(
-            &origin,
-            &(origin + (origin - look_at)),
-            &vector![0.0, 1.0, 0.0],
-        );
+        let isometry = self.view().isometry;
         let transform = isometry.to_homogeneous() * translate * scale;
 
-        let aperture_radius = if f_number == 0.0 {
+        let aperture_radius = if self.f_number == 0.0 {
             0.0
         } else {
-            focal_length / f_number / 2.0
+            focal_length / self.f_number / 2.0
         };
         let lens_transformation = isometry.to_homogeneous() * Matrix4::new_scaling(aperture_radius);
 
-        Camera {
-            origin,
+        PerspectiveCamera {
+            origin: self.origin,
             direction: Unit::new_normalize(focus_vector),
             focal_length,
-            focus_distance: focus_vector.norm(),
-            f_number,
+            focus_distance,
+            f_number: self.f_number,
+            shutter_open: self.shutter.0,
+            shutter_close: self.shutter.1,
+            aperture: self.aperture,
+            cats_eye: self.cats_eye,
+            aspect_ratio,
+            isometry,
+            image_extent: (scale_x / focus_distance, scale_y / focus_distance),
             transform: Transform3::from_matrix_unchecked(transform),
             lens_transformation: Transform3::from_matrix_unchecked(lens_transformation),
         }
     }
+}
+
+impl PerspectiveCamera {
+    pub fn builder(origin: Point, look_at: Point) -> CameraBuilder {
+        CameraBuilder {
+            origin,
+            look_at,
+            up: vector![0.0, 1.0, 0.0],
+            roll_degrees: 0.0,
+            lens: Lens::FocalLength(50.0),
+            sensor: Sensor::FULL_FRAME,
+            focus_distance: None,
+            f_number: 0.0,
+            aperture: Aperture::Circle,
+            cats_eye: 0.0,
+            aspect_ratio: None,
+            shutter: (0.0, 0.0),
+        }
+    }
+
+    /// Full-frame camera focused on `look_at`.
+    pub fn new(
+        origin: Point,
+        look_at: Point,
+        field_of_view_height_degrees: Float,
+        f_number: Float,
+        aspect_ratio: Float,
+    ) -> PerspectiveCamera {
+        PerspectiveCamera::builder(origin, look_at)
+            .field_of_view_degrees(field_of_view_height_degrees)
+            .f_number(f_number)
+            .aspect_ratio(aspect_ratio)
+            .build()
+    }
+}
+
+impl Eye {
+    fn side(&self) -> Float {
+        match self {
+            Eye::Left => -1.0,
+            Eye::Right => 1.0,
+        }
+    }
+}
 
-    pub fn generate_ray(
+impl StereoCamera {
+    pub fn new(
+        left: Box<dyn Camera>,
+        right: Box<dyn Camera>,
+        layout: StereoLayout,
+    ) -> StereoCamera {
+        StereoCamera {
+            left,
+            right,
+            layout,
+        }
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn generate_ray(
         &self,
         screen_position: Point2<Float>,
-        lens_position: Point2<Float>,
-    ) -> Ray {
+        lens_sample: Point2<Float>,
+        shutter_position: Float,
+    ) -> Option<Ray> {
+        let lens_position = self.aperture.sample(lens_sample);
+        if self.cats_eye > 0.0 {
+            // Off axis the lens barrel hides part of the aperture, seen from the corners
+            // as a second circle shifted towards the centre of the image
+            let corner_distance = self.aspect_ratio.hypot(1.0);
+            let shift = vector![screen_position.x * self.aspect_ratio, screen_position.y]
+                * (self.cats_eye / corner_distance);
+            if (lens_position.coords + shift).norm_squared() > 1.0 {
+                return None;
+            }
+        }
         let screen_3d_point: Point = point![screen_position.x, screen_position.y, 0.0];
         let origin = self.lens_transformation * point![lens_position.x, lens_position.y, 0.0];
-        Ray {
+        Some(Ray {
             origin,
             direction: Unit::new_normalize(self.transform * screen_3d_point - origin),
+            time: self.shutter_open + (self.shutter_close - self.shutter_open) * shutter_position,
+        })
+    }
+
+    fn pinhole(&self) -> Option<Point> {
+        (self.f_number == 0.0).then_some(self.origin)
+    }
+
+    fn importance(&self, direction: &Direction) -> Option<CameraImportance> {
+        // Camera space, looking along -z
+        let local = self.isometry.inverse_transform_vector(direction);
+        let cos_theta = -local.z;
+        if self.f_number != 0.0 || cos_theta <= 0.0 {
+            return None;
+        }
+        let (half_width, half_height) = self.image_extent;
+        let screen_position = point![
+            local.x / cos_theta / half_width,
+            local.y / cos_theta / half_height
+        ];
+        if screen_position.x.abs() > 1.0 || screen_position.y.abs() > 1.0 {
+            return None;
+        }
+        // The screen at unit distance is seen at a grazing angle off axis
+        let pdf = 1.0 / (4.0 * half_width * half_height * cos_theta.powi(3));
+        Some(CameraImportance {
+            screen_position,
+            importance: pdf,
+            pdf,
+        })
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn generate_ray(
+        &self,
+        screen_position: Point2<Float>,
+        _lens_sample: Point2<Float>,
+        shutter_position: Float,
+    ) -> Option<Ray> {
+        let half_height = self.height / 2.0;
+        let origin = point![
+            screen_position.x * half_height * self.aspect_ratio,
+            screen_position.y * half_height,
+            0.0
+        ];
+        Some(
+            self.view
+                .ray_from(origin, vector![0.0, 0.0, -1.0], shutter_position),
+        )
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn generate_ray(
+        &self,
+        screen_position: Point2<Float>,
+        _lens_sample: Point2<Float>,
+        shutter_position: Float,
+    ) -> Option<Ray> {
+        let x = screen_position.x * self.aspect_ratio;
+        let y = screen_position.y;
+        let radius = x.hypot(y);
+        if radius > 1.0 {
+            return None;
+        }
+        let theta = match self.mapping {
+            FisheyeMapping::Equidistant => radius * self.half_angle,
+            FisheyeMapping::Equisolid => {
+                2.0 * (radius * (self.half_angle / 2.0).sin())
+                    .clamp(-1.0, 1.0)
+                    .asin()
+            }
+        };
+        let phi = y.atan2(x);
+        let direction = vector![
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            -theta.cos()
+        ];
+        Some(self.view.ray(direction, shutter_position))
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn generate_ray(
+        &self,
+        screen_position: Point2<Float>,
+        _lens_sample: Point2<Float>,
+        shutter_position: Float,
+    ) -> Option<Ray> {
+        let longitude = screen_position.x * PI;
+        let latitude = screen_position.y * PI / 2.0;
+        let direction = vector![
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+            -latitude.cos() * longitude.cos()
+        ];
+        Some(self.view.ray(direction, shutter_position))
+    }
+}
+
+impl Camera for StereoCamera {
+    fn generate_ray(
+        &self,
+        screen_position: Point2<Float>,
+        lens_sample: Point2<Float>,
+        shutter_position: Float,
+    ) -> Option<Ray> {
+        // Stretch the eye's half of the screen over the whole of -1 to 1
+        let (x, y) = (screen_position.x, screen_position.y);
+        let (eye, eye_position) = match self.layout {
+            StereoLayout::SideBySide if x < 0.0 => (&self.left, point![2.0 * x + 1.0, y]),
+            StereoLayout::SideBySide => (&self.right, point![2.0 * x - 1.0, y]),
+            StereoLayout::TopBottom if y > 0.0 => (&self.left, point![x, 2.0 * y - 1.0]),
+            StereoLayout::TopBottom => (&self.right, point![x, 2.0 * y + 1.0]),
+        };
+        eye.generate_ray(eye_position, lens_sample, shutter_position)
+    }
+}
+
+impl Camera for OmniStereoCamera {
+    fn generate_ray(
+        &self,
+        screen_position: Point2<Float>,
+        _lens_sample: Point2<Float>,
+        shutter_position: Float,
+    ) -> Option<Ray> {
+        let longitude = screen_position.x * PI;
+        let latitude = screen_position.y * PI / 2.0;
+        let direction = vector![
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+            -latitude.cos() * longitude.cos()
+        ];
+        // Right of the head when it faces this longitude
+        let right = vector![longitude.cos(), 0.0, longitude.sin()];
+        let origin = Point::from(right * self.eye_offset);
+        Some(self.view.ray_from(origin, direction, shutter_position))
+    }
+}
+
+impl Camera for CylindricalCamera {
+    fn generate_ray(
+        &self,
+        screen_position: Point2<Float>,
+        _lens_sample: Point2<Float>,
+        shutter_position: Float,
+    ) -> Option<Ray> {
+        let angle = screen_position.x * self.half_angle;
+        // Square pixels at the horizon
+        let height = screen_position.y * self.half_angle / self.aspect_ratio;
+        let direction = vector![angle.sin(), height, -angle.cos()];
+        Some(self.view.ray(direction, shutter_position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn angle_to_centre(camera: &PerspectiveCamera, x: Float, y: Float) -> Float {
+        // Through the centre of the lens
+        let ray = camera
+            .generate_ray(point![x, y], point![0.5, 0.5], 0.0)
+            .unwrap();
+        ray.direction.angle(&camera.direction).to_degrees()
+    }
+
+    #[test]
+    fn rendered_field_of_view_matches_requested_value() {
+        for fov in [20.0, 60.0, 90.0, 120.0] {
+            let camera = PerspectiveCamera::new(
+                point![1.0, 2.0, 3.0],
+                point![4.0, 0.0, -2.0],
+                fov,
+                2.0,
+                16.0 / 9.0,
+            );
+            assert_relative_eq!(
+                angle_to_centre(&camera, 0.0, 1.0),
+                fov / 2.0,
+                epsilon = 1e-9
+            );
+            assert_relative_eq!(
+                angle_to_centre(&camera, 0.0, -1.0),
+                fov / 2.0,
+                epsilon = 1e-9
+            );
+            let horizontal = ((fov / 2.0 as Float).to_radians().tan() * 16.0 / 9.0).atan();
+            assert_relative_eq!(
+                angle_to_centre(&camera, 1.0, 0.0),
+                horizontal.to_degrees(),
+                epsilon = 1e-9
+            );
+        }
+    }
+
+    #[test]
+    fn focal_length_and_sensor_set_field_of_view() {
+        let fov = |sensor: Sensor, aspect_ratio: Float| {
+            let camera = PerspectiveCamera::builder(point![0.0, 0.0, 0.0], point![0.0, 0.0, -1.0])
+                .focal_length_mm(50.0)
+                .sensor(sensor)
+                .aspect_ratio(aspect_ratio)
+                .build();
+            2.0 * angle_to_centre(&camera, 0.0, 1.0)
+        };
+        let expected = |height_mm: Float| 2.0 * (height_mm / 2.0 / 50.0).atan().to_degrees();
+        assert_relative_eq!(fov(Sensor::FULL_FRAME, 1.5), expected(24.0), epsilon = 1e-9);
+        assert_relative_eq!(fov(Sensor::APS_C, 1.5), expected(15.6), epsilon = 1e-9);
+        // A wide image uses the full width of the sensor and crops its height
+        assert_relative_eq!(fov(Sensor::FULL_FRAME, 2.0), expected(18.0), epsilon = 1e-9);
+        assert_relative_eq!(
+            fov(Sensor::SUPER_35, 16.0 / 9.0),
+            expected(24.89 * 9.0 / 16.0),
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn rays_through_the_lens_meet_at_the_focus_distance() {
+        let camera = PerspectiveCamera::builder(point![0.0, 1.0, 0.0], point![0.0, 1.0, -10.0])
+            .focus_distance(3.0)
+            .f_number(1.4)
+            .build();
+        assert_relative_eq!(camera.focal_length, 0.05);
+        let target = point![0.0, 1.0, -3.0];
+        for lens_sample in [point![1.0, 0.5], point![0.5, 0.0], point![0.2, 0.9]] {
+            let ray = camera
+                .generate_ray(Point2::origin(), lens_sample, 0.0)
+                .unwrap();
+            assert!(ray.origin != camera.origin);
+            let distance = (target - ray.origin).dot(&ray.direction);
+            assert_relative_eq!(ray.at(distance), target, epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn importance_projects_rays_back_onto_the_screen() {
+        let builder = PerspectiveCamera::builder(point![1.0, 2.0, 3.0], point![4.0, 0.0, -2.0])
+            .field_of_view_degrees(60.0)
+            .aspect_ratio(1.5)
+            .roll_degrees(20.0);
+        let camera = builder.clone().build();
+        assert_eq!(camera.pinhole(), Some(camera.origin));
+        for screen_position in [point![0.0, 0.0], point![0.5, -0.25], point![-0.9, 0.8]] {
+            let ray = camera
+                .generate_ray(screen_position, point![0.5, 0.5], 0.0)
+                .unwrap();
+            let importance = camera.importance(&ray.direction).unwrap();
+            assert_relative_eq!(importance.screen_position, screen_position, epsilon = 1e-9);
+            // Uniform over the screen, which is seen at a grazing angle off axis
+            let cos_theta = ray.direction.dot(&camera.direction);
+            let centre = camera.importance(&camera.direction).unwrap();
+            assert_relative_eq!(importance.pdf * cos_theta.powi(3), centre.pdf);
+            assert_relative_eq!(importance.importance, importance.pdf);
+        }
+        assert!(camera.importance(&-camera.direction).is_none());
+
+        let thin_lens = builder.f_number(2.8).build();
+        assert!(thin_lens.pinhole().is_none());
+        assert!(thin_lens.importance(&thin_lens.direction).is_none());
+    }
+
+    #[test]
+    fn up_vector_and_roll_orient_the_image() {
+        let top_direction = |camera: PerspectiveCamera| {
+            camera
+                .generate_ray(point![0.0, 1.0], Point2::origin(), 0.0)
+                .unwrap()
+                .direction
+        };
+        let builder = || PerspectiveCamera::builder(point![0.0, 0.0, 0.0], point![0.0, 0.0, -1.0]);
+        assert!(top_direction(builder().build()).y > 0.0);
+        assert!(top_direction(builder().up(vector![1.0, 0.0, 0.0]).build()).x > 0.0);
+        let rolled = top_direction(builder().roll_degrees(90.0).build());
+        assert!(rolled.x < 0.0);
+        assert_relative_eq!(rolled.y, 0.0, epsilon = 1e-9);
+    }
+
+    fn direction(camera: &dyn Camera, x: Float, y: Float) -> Vector {
+        camera
+            .generate_ray(point![x, y], Point2::origin(), 0.0)
+            .unwrap()
+            .direction
+            .into_inner()
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel() {
+        let camera = PerspectiveCamera::builder(point![0.0, 1.0, 0.0], point![0.0, 1.0, -5.0])
+            .aspect_ratio(2.0)
+            .build_orthographic(4.0);
+        let corner = camera
+            .generate_ray(point![1.0, -1.0], Point2::origin(), 0.0)
+            .unwrap();
+        assert_relative_eq!(corner.origin, point![4.0, -1.0, 0.0]);
+        assert_relative_eq!(corner.direction.into_inner(), vector![0.0, 0.0, -1.0]);
+        assert_relative_eq!(direction(&camera, 0.0, 0.0), vector![0.0, 0.0, -1.0]);
+    }
+
+    #[test]
+    fn fisheye_angle_follows_mapping() {
+        let fov: Float = 180.0;
+        let builder =
+            PerspectiveCamera::builder(Point::origin(), point![0.0, 0.0, -1.0]).aspect_ratio(1.5);
+        let forward = vector![0.0, 0.0, -1.0];
+        let angle =
+            |camera: &FisheyeCamera, x, y| direction(camera, x, y).angle(&forward).to_degrees();
+        for mapping in [FisheyeMapping::Equidistant, FisheyeMapping::Equisolid] {
+            let camera = builder.clone().build_fisheye(mapping, fov);
+            assert_relative_eq!(angle(&camera, 0.0, 1.0), fov / 2.0, epsilon = 1e-9);
+            assert_relative_eq!(angle(&camera, 1.0 / 1.5, 0.0), fov / 2.0, epsilon = 1e-9);
+            assert!(camera
+                .generate_ray(point![0.9, 0.9], Point2::origin(), 0.0)
+                .is_none());
         }
+        let equidistant = builder
+            .clone()
+            .build_fisheye(FisheyeMapping::Equidistant, fov);
+        assert_relative_eq!(angle(&equidistant, 0.0, 0.5), fov / 4.0, epsilon = 1e-9);
+        let equisolid = builder.build_fisheye(FisheyeMapping::Equisolid, fov);
+        let expected = 2.0 * (0.5 * (fov / 4.0).to_radians().sin()).asin();
+        assert_relative_eq!(
+            angle(&equisolid, 0.0, 0.5),
+            expected.to_degrees(),
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn equirectangular_covers_the_sphere() {
+        let camera = PerspectiveCamera::builder(Point::origin(), point![0.0, 0.0, -1.0])
+            .build_equirectangular();
+        let check = |x, y, expected: Vector| {
+            assert_relative_eq!(direction(&camera, x, y), expected, epsilon = 1e-9)
+        };
+        check(0.0, 0.0, vector![0.0, 0.0, -1.0]);
+        check(0.5, 0.0, vector![1.0, 0.0, 0.0]);
+        check(1.0, 0.0, vector![0.0, 0.0, 1.0]);
+        check(-1.0, 0.0, vector![0.0, 0.0, 1.0]);
+        check(0.3, 1.0, vector![0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn cylindrical_spreads_angles_evenly() {
+        let camera = PerspectiveCamera::builder(Point::origin(), point![0.0, 0.0, -1.0])
+            .aspect_ratio(3.0)
+            .build_cylindrical(180.0);
+        assert_relative_eq!(
+            direction(&camera, 1.0, 0.0),
+            vector![1.0, 0.0, 0.0],
+            epsilon = 1e-9
+        );
+        let halfway = direction(&camera, 0.5, 0.0);
+        assert_relative_eq!(halfway.x.atan2(-halfway.z).to_degrees(), 45.0);
+        // Vertical lines stay vertical
+        let top = direction(&camera, 0.5, 1.0);
+        assert_relative_eq!(top.x / top.z, halfway.x / halfway.z);
+        assert!(top.y > 0.0);
+    }
+
+    #[test]
+    fn stereo_eyes_are_offset_and_converge() {
+        let builder = PerspectiveCamera::builder(point![0.0, 1.0, 0.0], point![0.0, 1.0, -5.0])
+            .roll_degrees(90.0);
+        // Rolled on its side the eyes are one above the other
+        let left = builder.eye(Eye::Left, 0.064, None).build();
+        let right = builder.eye(Eye::Right, 0.064, None).build();
+        assert_relative_eq!(left.origin, point![0.0, 0.968, 0.0]);
+        assert_relative_eq!(right.origin, point![0.0, 1.032, 0.0]);
+        assert_relative_eq!(left.direction, right.direction);
+        assert_relative_eq!(left.focus_distance, 5.0);
+
+        let centre_ray = |eye| {
+            builder
+                .eye(eye, 0.064, Some(2.0))
+                .build()
+                .generate_ray(Point2::origin(), Point2::origin(), 0.0)
+                .unwrap()
+        };
+        let (left, right) = (centre_ray(Eye::Left), centre_ray(Eye::Right));
+        let meet = |ray: &Ray| {
+            let t = (-2.0 - ray.origin.z) / ray.direction.z;
+            ray.origin + ray.direction.into_inner() * t
+        };
+        assert_relative_eq!(meet(&left), point![0.0, 1.0, -2.0], epsilon = 1e-9);
+        assert_relative_eq!(meet(&right), point![0.0, 1.0, -2.0], epsilon = 1e-9);
+    }
+
+    #[test]
+    fn stereo_layouts_split_the_image() {
+        let builder = PerspectiveCamera::builder(Point::origin(), point![0.0, 0.0, -1.0]);
+        let eyes = |aspect_ratio| {
+            [Eye::Left, Eye::Right].map(|eye| -> Box<dyn Camera> {
+                Box::new(
+                    builder
+                        .clone()
+                        .aspect_ratio(aspect_ratio)
+                        .eye(eye, 0.1, None)
+                        .build(),
+                )
+            })
+        };
+        let origin_x = |camera: &StereoCamera, x, y| {
+            camera
+                .generate_ray(point![x, y], Point2::origin(), 0.0)
+                .unwrap()
+                .origin
+                .x
+        };
+        let [left, right] = eyes(1.0);
+        let side_by_side = StereoCamera::new(left, right, StereoLayout::SideBySide);
+        assert_relative_eq!(origin_x(&side_by_side, -0.5, 0.9), -0.05);
+        assert_relative_eq!(origin_x(&side_by_side, 0.5, -0.9), 0.05);
+        // The centre of each half looks straight ahead
+        assert_relative_eq!(direction(&side_by_side, -0.5, 0.0), vector![0.0, 0.0, -1.0]);
+        let [left, right] = eyes(4.0);
+        let top_bottom = StereoCamera::new(left, right, StereoLayout::TopBottom);
+        assert_relative_eq!(origin_x(&top_bottom, 0.9, 0.5), -0.05);
+        assert_relative_eq!(origin_x(&top_bottom, -0.9, -0.5), 0.05);
+        assert_relative_eq!(direction(&top_bottom, 0.0, -0.5), vector![0.0, 0.0, -1.0]);
+    }
+
+    #[test]
+    fn omnidirectional_stereo_eyes_circle_the_head() {
+        let camera = |eye| {
+            PerspectiveCamera::builder(Point::origin(), point![0.0, 0.0, -1.0])
+                .build_omnidirectional_stereo(eye, 0.064)
+        };
+        for x in [-1.0, -0.3, 0.0, 0.5, 0.8] {
+            let left = camera(Eye::Left)
+                .generate_ray(point![x, 0.2], Point2::origin(), 0.0)
+                .unwrap();
+            let right = camera(Eye::Right)
+                .generate_ray(point![x, 0.2], Point2::origin(), 0.0)
+                .unwrap();
+            assert_relative_eq!(left.direction, right.direction);
+            assert_relative_eq!(left.origin.coords.norm(), 0.032);
+            assert_relative_eq!(left.origin, Point::from(-right.origin.coords));
+            // Eyes are level and sideways to the direction they look
+            assert_relative_eq!(left.origin.y, 0.0);
+            assert_relative_eq!(
+                left.origin.coords.dot(&left.direction),
+                0.0,
+                epsilon = 1e-12
+            );
+        }
+        let ahead = camera(Eye::Left)
+            .generate_ray(Point2::origin(), Point2::origin(), 0.0)
+            .unwrap();
+        assert_relative_eq!(ahead.origin, point![-0.032, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn cats_eye_clips_the_aperture_off_axis() {
+        let camera = PerspectiveCamera::builder(Point::origin(), point![0.0, 0.0, -2.0])
+            .f_number(2.0)
+            .aspect_ratio(1.0)
+            .cats_eye(1.0)
+            .build();
+        let passing = |x: Float, y: Float| {
+            let mut rng = StdRng::seed_from_u64(0);
+            (0..4000)
+                .filter(|_| {
+                    camera
+                        .generate_ray(point![x, y], point![rng.gen(), rng.gen()], 0.0)
+                        .is_some()
+                })
+                .count() as Float
+                / 4000.0
+        };
+        assert_eq!(passing(0.0, 0.0), 1.0);
+        // At the corner the aperture overlaps a unit circle one radius away
+        let overlap = 2.0 * (0.5 as Float).acos() - 0.5 * (3.0 as Float).sqrt();
+        assert_relative_eq!(passing(1.0, 1.0), overlap / PI, epsilon = 0.02);
+        assert!(passing(0.5, 0.0) > passing(1.0, 0.0));
     }
 }
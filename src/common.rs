@@ -1,4 +1,6 @@
+use crate::aabb::Aabb;
 use nalgebra;
+use rand::RngCore;
 
 pub type Float = f64;
 pub type Point = nalgebra::Point3<Float>;
@@ -6,11 +8,14 @@ pub type Vector = nalgebra::Vector3<Float>;
 pub type Direction = nalgebra::Unit<Vector>;
 
 pub const INFINITY: Float = Float::INFINITY;
+// Distance that rays leaving a surface skip, so that they don't hit it again
+pub const MIN_DIST: Float = 0.001;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Ray {
     pub origin: Point,
     pub direction: Direction,
+    pub time: Float, // Moment within the shutter interval that the ray samples
 }
 
 impl Ray {
@@ -23,6 +28,7 @@ impl Ray {
 pub struct RayIntersection<'a> {
     pub position: Point,
     pub normal: Direction,
+    pub uv: nalgebra::Point2<Float>, // Surface coordinates for texturing, zero where there are none
     pub distance: Float,
     pub material: &'a dyn Material,
 }
@@ -31,14 +37,97 @@ pub struct RayIntersection<'a> {
 pub struct ScatteredRay {
     pub attenuation: Vector,
     pub ray: Ray,
+    pub specular: bool, // Sampled from a delta lobe that eval() and pdf() don't cover
 }
 
 pub trait Material: std::fmt::Debug + Sync + Send {
-    fn scatter_ray(&self, ray: &Ray, intersection: &RayIntersection) -> Option<ScatteredRay>;
+    fn scatter_ray(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        rng: &mut dyn RngCore,
+    ) -> Option<ScatteredRay>;
+
+    /// BSDF times the cosine term for light arriving from `direction` and leaving
+    /// towards `-ray.direction`. Specular lobes are excluded.
+    fn eval(&self, ray: &Ray, intersection: &RayIntersection, direction: &Direction) -> Vector;
+
+    /// Solid angle density with which scatter_ray() picks `direction` through its
+    /// non-specular lobes.
+    fn pdf(&self, ray: &Ray, intersection: &RayIntersection, direction: &Direction) -> Float;
+
+    /// Like `scatter_ray`, for light of a single `wavelength` in nanometres. Materials
+    /// that scatter wavelengths differently, such as dispersive glass, override this
+    /// and `is_dispersive`, and spectral renders follow only one wavelength from them.
+    fn scatter_wavelength(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        _wavelength: Float,
+        rng: &mut dyn RngCore,
+    ) -> Option<ScatteredRay> {
+        self.scatter_ray(ray, intersection, rng)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+
+    /// Overall colour of the surface at `intersection`, for albedo AOVs and denoising.
+    fn albedo(&self, _intersection: &RayIntersection) -> Vector {
+        Vector::new(1.0, 1.0, 1.0)
+    }
+
+    /// Where light entering the surface at `intersection` comes out again, for
+    /// materials that scatter light beneath the surface. The path continues as if it
+    /// had hit the exit. Other materials return None.
+    fn sample_subsurface_exit(
+        &self,
+        _ray: &Ray,
+        _intersection: &RayIntersection,
+        _rng: &mut dyn RngCore,
+    ) -> Option<SubsurfaceExit<'_>> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub struct SubsurfaceExit<'a> {
+    pub ray: Ray, // Last part of the path beneath the surface, ending at the exit
+    pub intersection: RayIntersection<'a>,
+    pub throughput: Vector, // Zero if the light was absorbed
 }
 
 pub trait RayTracable: Sync + Send {
-    fn trace_ray(&self, ray: &Ray, min_dist: f64, max_dist: f64) -> Option<RayIntersection>;
+    /// Finds the closest intersection within the distance range. Objects such as
+    /// participating media may pick their intersections at random using `rng`.
+    fn trace_ray(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Option<RayIntersection<'_>>;
+
+    /// Fraction of light that makes it through the distance range unscattered, used
+    /// for shadow rays. Opaque objects give zero or one, media may estimate it.
+    fn transmittance(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Float {
+        match self.trace_ray(ray, min_dist, max_dist, rng) {
+            Some(_) => 0.0,
+            None => 1.0,
+        }
+    }
+
+    /// Box enclosing the object at all times, or None if it is unbounded.
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
 }
 
 #[cfg(test)]
@@ -51,6 +140,7 @@ mod tests {
         let ray = Ray {
             origin: nalgebra::point![1.0, 2.0, 3.0],
             direction: nalgebra::Unit::new_normalize(nalgebra::vector![1.0, 0.0, 0.0]),
+            time: 0.0,
         };
         assert_eq!(ray.at(0.0), ray.origin);
         assert_eq!(ray.at(1.0), nalgebra::point![2.0, 2.0, 3.0]);
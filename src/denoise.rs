@@ -0,0 +1,234 @@
+use crate::aov::Aov;
+use crate::aov::AovImage;
+use crate::common::Float;
+use crate::common::Vector;
+use crate::srgb::luminance;
+use nalgebra::vector;
+use serde::Deserialize;
+
+// B3 spline weights of the 5 by 5 à-trous kernel
+const KERNEL: [Float; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+// Albedo below this is treated as black, and the light there is filtered as it is
+const MIN_ALBEDO: Float = 1e-3;
+
+/// Edge-avoiding à-trous wavelet filter for images rendered with few samples per
+/// pixel, guided by the albedo, normal and variance AOVs. Texture is divided out
+/// before filtering and multiplied back in afterwards, so only the lighting is
+/// smoothed. Each iteration doubles the spacing of the kernel's taps.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Denoiser {
+    pub iterations: u32,
+    pub color_sigma: Float, // Luminance difference allowed, in standard deviations
+    pub normal_power: Float, // Higher values keep edges between surfaces sharper
+    pub albedo_sigma: Float, // Albedo difference allowed
+}
+
+impl Default for Denoiser {
+    fn default() -> Denoiser {
+        Denoiser {
+            iterations: 5,
+            color_sigma: 2.0,
+            normal_power: 128.0,
+            albedo_sigma: 0.1,
+        }
+    }
+}
+
+/// Guide values for one pixel.
+struct Guide {
+    normal: Vector,
+    albedo: Vector,
+}
+
+impl Denoiser {
+    /// AOVs the filter reads.
+    pub const AOVS: [Aov; 4] = [Aov::Beauty, Aov::Albedo, Aov::Normal, Aov::Variance];
+
+    /// Filtered beauty buffer. Panics if any of the AOVs in `Denoiser::AOVS` is missing.
+    pub fn denoise(&self, aovs: &AovImage) -> Vec<Vector> {
+        let layer = |aov: Aov| {
+            aovs.layer(aov)
+                .unwrap_or_else(|| panic!("Denoising needs the {} AOV", aov.name()))
+        };
+        let beauty = layer(Aov::Beauty);
+        let guides: Vec<Guide> = layer(Aov::Normal)
+            .iter()
+            .zip(layer(Aov::Albedo))
+            .map(|(normal, albedo)| Guide {
+                normal: *normal,
+                albedo: *albedo,
+            })
+            .collect();
+        let demodulation: Vec<Vector> = layer(Aov::Albedo)
+            .iter()
+            .map(|albedo| albedo.map(|a| if a < MIN_ALBEDO { 1.0 } else { a }))
+            .collect();
+        let mut irradiance: Vec<Vector> = beauty
+            .iter()
+            .zip(&demodulation)
+            .map(|(colour, albedo)| colour.component_div(albedo))
+            .collect();
+        let mut variance: Vec<Float> = layer(Aov::Variance)
+            .iter()
+            .zip(&demodulation)
+            .map(|(variance, albedo)| variance.x / luminance(albedo).powi(2))
+            .collect();
+
+        let (width, height) = (aovs.width as usize, aovs.height as usize);
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let blurred_variance = blur_3x3(&variance, width, height);
+            let mut next_irradiance = irradiance.clone();
+            let mut next_variance = variance.clone();
+            for y in 0..height {
+                for x in 0..width {
+                    let index = y * width + x;
+                    let centre = &guides[index];
+                    let centre_luminance = luminance(&irradiance[index]);
+                    let mut sum = vector![0.0, 0.0, 0.0];
+                    let mut variance_sum = 0.0;
+                    let mut weight_sum = 0.0;
+                    for (j, ky) in KERNEL.iter().enumerate() {
+                        let sy = y as isize + (j as isize - 2) * step;
+                        if sy < 0 || sy >= height as isize {
+                            continue;
+                        }
+                        for (i, kx) in KERNEL.iter().enumerate() {
+                            let sx = x as isize + (i as isize - 2) * step;
+                            if sx < 0 || sx >= width as isize {
+                                continue;
+                            }
+                            let other = sy as usize * width + sx as usize;
+                            // Both pixels are noisy estimates, so their difference
+                            // has the sum of their variances
+                            let luminance_difference =
+                                (luminance(&irradiance[other]) - centre_luminance).abs();
+                            let luminance_scale = self.color_sigma
+                                * (blurred_variance[index] + blurred_variance[other])
+                                    .max(0.0)
+                                    .sqrt()
+                                + 1e-6;
+                            let weight = kx
+                                * ky
+                                * self.guide_weight(centre, &guides[other])
+                                * (-luminance_difference / luminance_scale).exp();
+                            sum += irradiance[other] * weight;
+                            variance_sum += variance[other] * weight * weight;
+                            weight_sum += weight;
+                        }
+                    }
+                    // The centre tap always has a positive weight
+                    next_irradiance[index] = sum / weight_sum;
+                    next_variance[index] = variance_sum / (weight_sum * weight_sum);
+                }
+            }
+            irradiance = next_irradiance;
+            variance = next_variance;
+        }
+
+        irradiance
+            .iter()
+            .zip(&demodulation)
+            .map(|(irradiance, albedo)| irradiance.component_mul(albedo))
+            .collect()
+    }
+
+    fn guide_weight(&self, centre: &Guide, other: &Guide) -> Float {
+        // Pixels without a surface have zero normals and only blend with each other
+        let normal_weight = match (centre.normal.norm() > 0.0, other.normal.norm() > 0.0) {
+            (false, false) => 1.0,
+            (true, true) => {
+                let cos = centre.normal.normalize().dot(&other.normal.normalize());
+                cos.max(0.0).powf(self.normal_power)
+            }
+            _ => 0.0,
+        };
+        let albedo_difference = (centre.albedo - other.albedo).norm_squared();
+        normal_weight * (-albedo_difference / (self.albedo_sigma * self.albedo_sigma)).exp()
+    }
+}
+
+fn blur_3x3(values: &[Float], width: usize, height: usize) -> Vec<Float> {
+    let kernel = [0.25, 0.5, 0.25];
+    let mut blurred = vec![0.0; values.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = 0.0;
+            let mut weight_sum = 0.0;
+            for (j, ky) in kernel.iter().enumerate() {
+                for (i, kx) in kernel.iter().enumerate() {
+                    let (sx, sy) = (x + i, y + j);
+                    if sx == 0 || sy == 0 || sx > width || sy > height {
+                        continue;
+                    }
+                    sum += values[(sy - 1) * width + sx - 1] * kx * ky;
+                    weight_sum += kx * ky;
+                }
+            }
+            blurred[y * width + x] = sum / weight_sum;
+        }
+    }
+    blurred
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Noisy image of two flat surfaces that meet at x = 8, facing different ways.
+    fn two_surfaces(noise: Float) -> AovImage {
+        let (width, height) = (16, 8);
+        let mut image = AovImage::new(width, height, &Denoiser::AOVS);
+        let mut rng = StdRng::seed_from_u64(0);
+        for y in 0..height {
+            for x in 0..width {
+                let index = (y * width + x) as usize;
+                let (brightness, normal) = if x < 8 {
+                    (0.2, vector![0.0, 0.0, 1.0])
+                } else {
+                    (0.8, vector![1.0, 0.0, 0.0])
+                };
+                let value = brightness * (1.0 + noise * (rng.gen::<Float>() - 0.5));
+                image.layer_mut(Aov::Beauty).unwrap()[index] = vector![value, value, value];
+                image.layer_mut(Aov::Albedo).unwrap()[index] = vector![0.5, 0.5, 0.5];
+                image.layer_mut(Aov::Normal).unwrap()[index] = normal;
+                let variance = (brightness * noise).powi(2) / 12.0;
+                image.layer_mut(Aov::Variance).unwrap()[index] =
+                    vector![variance, variance, variance];
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn smooths_noise_without_crossing_edges() {
+        // The noise moves pixels by up to 25%
+        let denoised = Denoiser::default().denoise(&two_surfaces(0.5));
+        for y in 0..8 {
+            for x in 0..16 {
+                let expected = if x < 8 { 0.2 } else { 0.8 };
+                let value = denoised[y * 16 + x];
+                assert!(
+                    (value.x - expected).abs() < 0.08 * expected,
+                    "{} at {}, {}",
+                    value.x,
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_clean_images_alone() {
+        let image = two_surfaces(0.0);
+        let denoised = Denoiser::default().denoise(&image);
+        for (value, original) in denoised.iter().zip(image.layer(Aov::Beauty).unwrap()) {
+            assert_relative_eq!(value, original, epsilon = 1e-9);
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use crate::common::Float;
+use crate::common::Point;
+use crate::common::Vector;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Density that varies over the unit cube [0, 1]³, scaled by a volume to its bounds.
+pub trait DensityField: Sync + Send {
+    fn density(&self, position: &Point) -> Float;
+
+    /// Upper bound of density() everywhere, used as the majorant for tracking.
+    fn max_density(&self) -> Float;
+}
+
+/// Dense grid of voxel densities, interpolated trilinearly between voxel centres.
+pub struct VoxelGrid {
+    sizes: [usize; 3],
+    values: Vec<Float>, // x varies fastest, then y, then z
+    max_density: Float,
+}
+
+impl VoxelGrid {
+    pub fn new(sizes: [usize; 3], values: Vec<Float>) -> VoxelGrid {
+        assert!(sizes.iter().all(|&size| size > 0), "Empty voxel grid");
+        assert_eq!(values.len(), sizes[0] * sizes[1] * sizes[2]);
+        let max_density = values.iter().cloned().fold(0.0, Float::max);
+        VoxelGrid {
+            sizes,
+            values,
+            max_density,
+        }
+    }
+
+    /// Loads a three-dimensional NRRD file with the data attached after the header.
+    /// Supported types are uchar, float and double with raw or ascii encoding, in
+    /// either endianness.
+    pub fn load(path: &Path) -> Result<VoxelGrid, Box<dyn Error>> {
+        let bytes = fs::read(path)?;
+        let header_end = bytes
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .ok_or("NRRD header has no end")?;
+        let header = std::str::from_utf8(&bytes[..header_end])?;
+        let data = &bytes[header_end + 2..];
+
+        let mut lines = header.lines();
+        if !lines.next().unwrap_or("").starts_with("NRRD") {
+            return Err("Not a NRRD file".into());
+        }
+        let mut sizes = None;
+        let mut kind = None;
+        let mut encoding = None;
+        let mut big_endian = false;
+        for line in lines {
+            if line.starts_with('#') {
+                continue;
+            }
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field.trim(), value.trim()),
+                None => continue,
+            };
+            match field {
+                "dimension" if value != "3" => {
+                    return Err("Only 3D NRRD files are supported".into())
+                }
+                "sizes" => {
+                    let parsed = value
+                        .split_whitespace()
+                        .map(|s| s.parse::<usize>())
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if parsed.len() != 3 {
+                        return Err("NRRD sizes must have three values".into());
+                    }
+                    if parsed.contains(&0) {
+                        return Err("NRRD sizes must not be zero".into());
+                    }
+                    sizes = Some([parsed[0], parsed[1], parsed[2]]);
+                }
+                "type" => kind = Some(value.to_string()),
+                "encoding" => encoding = Some(value.to_string()),
+                "endian" => big_endian = value == "big",
+                "data file" | "datafile" => {
+                    return Err("Detached NRRD data is not supported".into())
+                }
+                _ => {}
+            }
+        }
+        let sizes = sizes.ok_or("NRRD header has no sizes")?;
+        let count = sizes[0] * sizes[1] * sizes[2];
+        let kind = kind.ok_or("NRRD header has no type")?;
+
+        let values: Vec<Float> = match encoding.as_deref() {
+            Some("raw") => {
+                let width = match kind.as_str() {
+                    "uchar" | "unsigned char" | "uint8" => 1,
+                    "float" => 4,
+                    "double" => 8,
+                    _ => return Err(format!("Unsupported NRRD type: {}", kind).into()),
+                };
+                if data.len() < count * width {
+                    return Err("NRRD file has too little data".into());
+                }
+                data.chunks_exact(width)
+                    .take(count)
+                    .map(|chunk| decode_raw(chunk, big_endian))
+                    .collect()
+            }
+            Some("ascii" | "text" | "txt") => std::str::from_utf8(data)?
+                .split_whitespace()
+                .take(count)
+                .map(|s| s.parse::<Float>())
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => return Err("Unsupported NRRD encoding".into()),
+        };
+        if values.len() != count {
+            return Err("NRRD file has too little data".into());
+        }
+        Ok(VoxelGrid::new(sizes, values))
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> Float {
+        self.values[(z * self.sizes[1] + y) * self.sizes[0] + x]
+    }
+}
+
+fn decode_raw(chunk: &[u8], big_endian: bool) -> Float {
+    match chunk.len() {
+        1 => chunk[0] as Float / 255.0,
+        4 => {
+            let bytes = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            let value = if big_endian {
+                f32::from_be_bytes(bytes)
+            } else {
+                f32::from_le_bytes(bytes)
+            };
+            value as Float
+        }
+        _ => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(chunk);
+            if big_endian {
+                f64::from_be_bytes(bytes)
+            } else {
+                f64::from_le_bytes(bytes)
+            }
+        }
+    }
+}
+
+impl DensityField for VoxelGrid {
+    fn density(&self, position: &Point) -> Float {
+        // Continuous voxel coordinates with voxel centres at integers
+        let mut base = [0; 3];
+        let mut fraction = [0.0; 3];
+        for i in 0..3 {
+            let c = (position[i] * self.sizes[i] as Float - 0.5)
+                .clamp(0.0, (self.sizes[i] - 1) as Float);
+            base[i] = (c.floor() as usize).min(self.sizes[i].saturating_sub(2));
+            fraction[i] = c - base[i] as Float;
+        }
+        let next = |i: usize| (base[i] + 1).min(self.sizes[i] - 1);
+        let mut value = 0.0;
+        for corner in 0..8 {
+            let pick = |i: usize| corner >> i & 1 == 1;
+            let weight: Float = (0..3)
+                .map(|i| {
+                    if pick(i) {
+                        fraction[i]
+                    } else {
+                        1.0 - fraction[i]
+                    }
+                })
+                .product();
+            if weight > 0.0 {
+                let index = |i: usize| if pick(i) { next(i) } else { base[i] };
+                value += weight * self.voxel(index(0), index(1), index(2));
+            }
+        }
+        value
+    }
+
+    fn max_density(&self) -> Float {
+        self.max_density
+    }
+}
+
+/// Fractal Brownian motion built from Perlin gradient noise. The density is
+/// `density * max(0, fbm + offset)`, where fbm is in about [-1, 1] and a negative
+/// offset carves out empty space between puffs.
+pub struct NoiseDensity {
+    pub frequency: Float, // Noise cells across the unit cube
+    pub octaves: u32,
+    pub density: Float,
+    pub offset: Float,
+    permutation: Vec<usize>,
+}
+
+impl NoiseDensity {
+    pub fn new(
+        frequency: Float,
+        octaves: u32,
+        density: Float,
+        offset: Float,
+        seed: u64,
+    ) -> NoiseDensity {
+        let mut permutation: Vec<usize> = (0..256).collect();
+        permutation.shuffle(&mut StdRng::seed_from_u64(seed));
+        let repeated = permutation
+            .iter()
+            .chain(permutation.iter())
+            .cloned()
+            .collect();
+        NoiseDensity {
+            frequency,
+            octaves,
+            density,
+            offset,
+            permutation: repeated,
+        }
+    }
+
+    fn noise(&self, p: Vector) -> Float {
+        let cell = p.map(|c| c.floor());
+        let f = p - cell;
+        let index = cell.map(|c| (c as i64).rem_euclid(256) as usize);
+        let fade = f.map(|t| t * t * t * (t * (t * 6.0 - 15.0) + 10.0));
+        let hash = |dx: usize, dy: usize, dz: usize| {
+            let p = &self.permutation;
+            p[p[p[index.x + dx] + index.y + dy] + index.z + dz]
+        };
+        let gradient = |h: usize, x: Float, y: Float, z: Float| {
+            // Ken Perlin's improved noise gradients towards the cube's edge midpoints
+            let h = h & 15;
+            let u = if h < 8 { x } else { y };
+            let v = if h < 4 {
+                y
+            } else if h == 12 || h == 14 {
+                x
+            } else {
+                z
+            };
+            (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+        };
+        let lerp = |t: Float, a: Float, b: Float| a + t * (b - a);
+        let corner = |dx: usize, dy: usize, dz: usize| {
+            gradient(
+                hash(dx, dy, dz),
+                f.x - dx as Float,
+                f.y - dy as Float,
+                f.z - dz as Float,
+            )
+        };
+        lerp(
+            fade.z,
+            lerp(
+                fade.y,
+                lerp(fade.x, corner(0, 0, 0), corner(1, 0, 0)),
+                lerp(fade.x, corner(0, 1, 0), corner(1, 1, 0)),
+            ),
+            lerp(
+                fade.y,
+                lerp(fade.x, corner(0, 0, 1), corner(1, 0, 1)),
+                lerp(fade.x, corner(0, 1, 1), corner(1, 1, 1)),
+            ),
+        )
+    }
+
+    fn fbm(&self, position: &Point) -> Float {
+        let mut sum = 0.0;
+        let mut amplitude = 0.5;
+        let mut p = position.coords * self.frequency;
+        for _ in 0..self.octaves {
+            sum += amplitude * self.noise(p);
+            amplitude *= 0.5;
+            p *= 2.0;
+        }
+        sum
+    }
+}
+
+impl DensityField for NoiseDensity {
+    fn density(&self, position: &Point) -> Float {
+        self.density * (self.fbm(position) + self.offset).max(0.0)
+    }
+
+    fn max_density(&self) -> Float {
+        // Improved Perlin noise stays within [-1, 1]
+        let amplitude_sum = 1.0 - 0.5_f64.powi(self.octaves as i32);
+        self.density * (amplitude_sum + self.offset).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use nalgebra::point;
+
+    #[test]
+    fn voxel_grid_interpolates_between_voxel_centres() {
+        let grid = VoxelGrid::new([2, 1, 1], vec![1.0, 3.0]);
+        assert_relative_eq!(grid.density(&point![0.25, 0.5, 0.5]), 1.0);
+        assert_relative_eq!(grid.density(&point![0.5, 0.5, 0.5]), 2.0);
+        assert_relative_eq!(grid.density(&point![0.75, 0.2, 0.9]), 3.0);
+        assert_relative_eq!(grid.density(&point![1.0, 0.5, 0.5]), 3.0);
+        assert_eq!(grid.max_density(), 3.0);
+    }
+
+    #[test]
+    fn loads_raw_and_ascii_nrrd_files() {
+        let path = std::env::temp_dir().join("raytracer_density_test.nrrd");
+        let mut bytes = b"NRRD0004\n# Test\ntype: float\ndimension: 3\nsizes: 2 2 1\nencoding: raw\nendian: little\n\n".to_vec();
+        for value in [0.0f32, 1.0, 2.0, 3.0] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        fs::write(&path, &bytes).unwrap();
+        let grid = VoxelGrid::load(&path).unwrap();
+        assert_eq!(grid.sizes, [2, 2, 1]);
+        assert_eq!(grid.voxel(1, 1, 0), 3.0);
+
+        fs::write(
+            &path,
+            "NRRD0001\ntype: double\ndimension: 3\nsizes: 1 1 2\nencoding: ascii\n\n0.5 0.25\n",
+        )
+        .unwrap();
+        let grid = VoxelGrid::load(&path).unwrap();
+        assert_eq!(grid.voxel(0, 0, 1), 0.25);
+
+        fs::write(&path, "NRRD0001\ntype: float\ndimension: 2\nsizes: 1 1\n\n").unwrap();
+        assert!(VoxelGrid::load(&path).is_err());
+
+        fs::write(
+            &path,
+            "NRRD0001\ntype: float\ndimension: 3\nsizes: 0 1 1\nencoding: ascii\n\n",
+        )
+        .unwrap();
+        assert!(VoxelGrid::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn noise_density_stays_below_its_maximum() {
+        let noise = NoiseDensity::new(4.0, 4, 2.0, 0.1, 7);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut any_empty = false;
+        let mut any_dense = false;
+        for _ in 0..10_000 {
+            let position = point![rng.gen(), rng.gen(), rng.gen()];
+            let density = noise.density(&position);
+            assert!(density <= noise.max_density());
+            any_empty |= density == 0.0;
+            any_dense |= density > 0.2;
+        }
+        assert!(any_empty && any_dense);
+    }
+}
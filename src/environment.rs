@@ -0,0 +1,343 @@
+use crate::common::Direction;
+use crate::common::Float;
+use crate::common::Vector;
+use crate::sampling::uniform_sphere_direction;
+use crate::sampling::Distribution2D;
+use crate::sampling::UNIFORM_SPHERE_PDF;
+use crate::srgb::luminance;
+use image::codecs::hdr::HdrDecoder;
+use nalgebra::{point, vector, Point2, Unit};
+use rand::prelude::*;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+#[derive(Debug)]
+pub struct EnvironmentSample {
+    pub direction: Direction,
+    pub radiance: Vector,
+    pub pdf: Float, // Solid angle density
+}
+
+/// Light arriving from infinitely far away, seen by rays that miss the scene.
+pub trait Environment: Sync + Send {
+    fn radiance(&self, direction: &Direction) -> Vector;
+
+    /// Picks a direction to send a shadow ray towards, or None if there is no light.
+    fn sample(&self, rng: &mut dyn RngCore) -> Option<EnvironmentSample>;
+
+    /// Solid angle density with which sample() picks `direction`.
+    fn pdf(&self, direction: &Direction) -> Float;
+}
+
+pub struct ConstantEnvironment {
+    pub color: Vector,
+}
+
+impl Environment for ConstantEnvironment {
+    fn radiance(&self, _direction: &Direction) -> Vector {
+        self.color
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore) -> Option<EnvironmentSample> {
+        sample_uniform_sphere(self, self.color == vector![0.0, 0.0, 0.0], rng)
+    }
+
+    fn pdf(&self, _direction: &Direction) -> Float {
+        uniform_sphere_pdf(self.color == vector![0.0, 0.0, 0.0])
+    }
+}
+
+/// Sky that blends linearly from `bottom` straight down to `top` straight up.
+pub struct GradientEnvironment {
+    pub bottom: Vector,
+    pub top: Vector,
+}
+
+impl GradientEnvironment {
+    fn is_black(&self) -> bool {
+        self.bottom == vector![0.0, 0.0, 0.0] && self.top == vector![0.0, 0.0, 0.0]
+    }
+}
+
+impl Environment for GradientEnvironment {
+    fn radiance(&self, direction: &Direction) -> Vector {
+        let t = 0.5 * (direction.y + 1.0);
+        self.bottom * (1.0 - t) + self.top * t
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore) -> Option<EnvironmentSample> {
+        sample_uniform_sphere(self, self.is_black(), rng)
+    }
+
+    fn pdf(&self, _direction: &Direction) -> Float {
+        uniform_sphere_pdf(self.is_black())
+    }
+}
+
+fn sample_uniform_sphere(
+    environment: &dyn Environment,
+    is_black: bool,
+    rng: &mut dyn RngCore,
+) -> Option<EnvironmentSample> {
+    if is_black {
+        return None;
+    }
+    let direction = uniform_sphere_direction(rng);
+    Some(EnvironmentSample {
+        direction,
+        radiance: environment.radiance(&direction),
+        pdf: UNIFORM_SPHERE_PDF,
+    })
+}
+
+fn uniform_sphere_pdf(is_black: bool) -> Float {
+    if is_black {
+        0.0
+    } else {
+        UNIFORM_SPHERE_PDF
+    }
+}
+
+/// Equirectangular (latitude-longitude) image of linear radiance. The centre of the
+/// image faces -z before rotation, and the top row is straight up. Directions are
+/// importance sampled by luminance.
+pub struct EnvironmentMap {
+    pub rotation_degrees: Float, // Counterclockwise about the y axis, seen from above
+    pub intensity: Float,
+    width: usize,
+    height: usize,
+    pixels: Vec<Vector>,
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    pub fn new(width: usize, height: usize, pixels: Vec<Vector>) -> EnvironmentMap {
+        assert_eq!(pixels.len(), width * height);
+        let weights: Vec<Float> = pixels
+            .iter()
+            .enumerate()
+            .map(|(i, pixel)| {
+                // Rows near the poles cover less solid angle
+                let theta = ((i / width) as Float + 0.5) / height as Float * PI;
+                luminance(pixel) * theta.sin()
+            })
+            .collect();
+        EnvironmentMap {
+            rotation_degrees: 0.0,
+            intensity: 1.0,
+            width,
+            height,
+            distribution: Distribution2D::new(&weights, width, height),
+            pixels,
+        }
+    }
+
+    /// Tabulates `radiance` at the centre of every pixel.
+    pub fn from_fn(
+        width: usize,
+        height: usize,
+        radiance: impl Fn(&Direction) -> Vector,
+    ) -> EnvironmentMap {
+        let pixels = (0..width * height)
+            .map(|i| {
+                let uv = point![
+                    ((i % width) as Float + 0.5) / width as Float,
+                    ((i / width) as Float + 0.5) / height as Float
+                ];
+                radiance(&lat_long_direction(uv, 0.0))
+            })
+            .collect();
+        EnvironmentMap::new(width, height, pixels)
+    }
+
+    /// Loads a Radiance `.hdr` or OpenEXR `.exr` file.
+    pub fn load(path: &Path) -> Result<EnvironmentMap, Box<dyn Error>> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match extension.as_deref() {
+            Some("hdr") => {
+                let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+                let metadata = decoder.metadata();
+                let pixels = decoder
+                    .read_image_hdr()?
+                    .iter()
+                    .map(|p| vector![p[0] as Float, p[1] as Float, p[2] as Float])
+                    .collect();
+                Ok(EnvironmentMap::new(
+                    metadata.width as usize,
+                    metadata.height as usize,
+                    pixels,
+                ))
+            }
+            Some("exr") => {
+                let image = exr::prelude::read_first_rgba_layer_from_file(
+                    path,
+                    |resolution, _| {
+                        let (width, height) = (resolution.width(), resolution.height());
+                        (width, height, vec![vector![0.0, 0.0, 0.0]; width * height])
+                    },
+                    |(width, _, pixels), position, (r, g, b, _): (f32, f32, f32, f32)| {
+                        pixels[position.y() * *width + position.x()] =
+                            vector![r as Float, g as Float, b as Float];
+                    },
+                )?;
+                let (width, height, pixels) = image.layer_data.channel_data.pixels;
+                Ok(EnvironmentMap::new(width, height, pixels))
+            }
+            _ => Err(format!("Unsupported environment map format: {}", path.display()).into()),
+        }
+    }
+
+    fn direction_to_uv(&self, direction: &Direction) -> Point2<Float> {
+        let theta = direction.y.clamp(-1.0, 1.0).acos();
+        let phi = direction.x.atan2(-direction.z) - self.rotation_degrees.to_radians();
+        point![
+            (phi / (2.0 * PI) + 0.5).rem_euclid(1.0),
+            (theta / PI).min(1.0 - Float::EPSILON)
+        ]
+    }
+
+    fn uv_to_direction(&self, uv: Point2<Float>) -> Direction {
+        lat_long_direction(uv, self.rotation_degrees.to_radians())
+    }
+
+    fn lookup(&self, uv: Point2<Float>) -> Vector {
+        let x = ((uv.x * self.width as Float) as usize).min(self.width - 1);
+        let y = ((uv.y * self.height as Float) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x] * self.intensity
+    }
+}
+
+fn lat_long_direction(uv: Point2<Float>, rotation: Float) -> Direction {
+    let theta = uv.y * PI;
+    let phi = (uv.x - 0.5) * 2.0 * PI + rotation;
+    Unit::new_normalize(vector![
+        theta.sin() * phi.sin(),
+        theta.cos(),
+        -theta.sin() * phi.cos()
+    ])
+}
+
+impl Environment for EnvironmentMap {
+    fn radiance(&self, direction: &Direction) -> Vector {
+        self.lookup(self.direction_to_uv(direction))
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore) -> Option<EnvironmentSample> {
+        let (uv, uv_pdf) = self.distribution.sample(point![rng.gen(), rng.gen()]);
+        let sin_theta = (uv.y * PI).sin();
+        if uv_pdf == 0.0 || sin_theta == 0.0 {
+            return None;
+        }
+        let direction = self.uv_to_direction(uv);
+        Some(EnvironmentSample {
+            direction,
+            radiance: self.lookup(uv),
+            pdf: uv_pdf / (2.0 * PI * PI * sin_theta),
+        })
+    }
+
+    fn pdf(&self, direction: &Direction) -> Float {
+        let uv = self.direction_to_uv(direction);
+        let sin_theta = (uv.y * PI).sin();
+        if sin_theta == 0.0 {
+            0.0
+        } else {
+            self.distribution.pdf(uv) / (2.0 * PI * PI * sin_theta)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use rand::rngs::StdRng;
+
+    fn spot_map() -> EnvironmentMap {
+        let (width, height) = (32, 16);
+        let mut pixels = vec![vector![0.1, 0.1, 0.1]; width * height];
+        pixels[5 * width + 20] = vector![1000.0, 800.0, 600.0];
+        EnvironmentMap::new(width, height, pixels)
+    }
+
+    #[test]
+    fn map_directions_round_trip() {
+        let mut map = spot_map();
+        map.rotation_degrees = 30.0;
+        let uv = point![0.3, 0.7];
+        let back = map.direction_to_uv(&map.uv_to_direction(uv));
+        assert_relative_eq!(back, uv, epsilon = 1e-9);
+        map.rotation_degrees = 0.0;
+        let forward = map.direction_to_uv(&Unit::new_normalize(vector![0.0, 0.0, -1.0]));
+        assert_relative_eq!(forward, point![0.5, 0.5], epsilon = 1e-9);
+    }
+
+    #[test]
+    fn map_pdf_integrates_to_one() {
+        let map = spot_map();
+        // Cell edges line up with the pixel edges of the map
+        let (n_theta, n_phi) = (16 * 20, 32 * 20);
+        let (d_theta, d_phi) = (PI / n_theta as Float, 2.0 * PI / n_phi as Float);
+        let mut integral = 0.0;
+        for i in 0..n_theta {
+            let theta = (i as Float + 0.5) * d_theta;
+            for j in 0..n_phi {
+                let phi = (j as Float + 0.5) * d_phi;
+                let direction = Unit::new_normalize(vector![
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin()
+                ]);
+                integral += map.pdf(&direction) * theta.sin() * d_theta * d_phi;
+            }
+        }
+        assert_relative_eq!(integral, 1.0, epsilon = 1e-3);
+    }
+
+    #[test]
+    fn map_samples_match_radiance_and_pdf() {
+        let mut map = spot_map();
+        map.rotation_degrees = -70.0;
+        map.intensity = 2.0;
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut bright = 0;
+        for _ in 0..1000 {
+            let sample = map.sample(&mut rng).unwrap();
+            assert_relative_eq!(sample.radiance, map.radiance(&sample.direction));
+            assert_relative_eq!(sample.pdf, map.pdf(&sample.direction), max_relative = 1e-6);
+            if sample.radiance.x > 1.0 {
+                bright += 1;
+            }
+        }
+        assert!(bright > 900);
+    }
+
+    #[test]
+    fn loads_radiance_hdr_files() {
+        let path = std::env::temp_dir().join("raytracer_environment_test.hdr");
+        let pixels = [image::Rgb([1.0, 2.0, 4.0]), image::Rgb([0.5, 0.25, 0.125])];
+        image::codecs::hdr::HdrEncoder::new(File::create(&path).unwrap())
+            .encode(&pixels, 2, 1)
+            .unwrap();
+        let map = EnvironmentMap::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((map.width, map.height), (2, 1));
+        assert_relative_eq!(map.pixels[1], vector![0.5, 0.25, 0.125]);
+    }
+
+    #[test]
+    fn black_constant_environment_is_not_sampled() {
+        let black = ConstantEnvironment {
+            color: vector![0.0, 0.0, 0.0],
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(black.sample(&mut rng).is_none());
+        assert_eq!(black.pdf(&Unit::new_normalize(vector![1.0, 0.0, 0.0])), 0.0);
+    }
+}
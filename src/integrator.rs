@@ -0,0 +1,457 @@
+use crate::aov::material_id;
+use crate::bdpt::trace_bidirectional;
+use crate::common::Float;
+use crate::common::Ray;
+use crate::common::RayTracable;
+use crate::common::Vector;
+use crate::common::INFINITY;
+use crate::common::MIN_DIST;
+use crate::materials::random_direction_on_hemisphere_cosine_weighted;
+use crate::render::sample_lights;
+use crate::render::trace_path;
+use crate::render::trace_spectral_path;
+use crate::render::FirstHit;
+use crate::render::Pass;
+use crate::render::PathEnd;
+use crate::render::PathSample;
+use crate::render::RayCounters;
+use crate::scene;
+use crate::scene::Scene;
+use crate::srgb::rgb_to_srgb;
+use crate::srgb::srgb_to_rgb;
+use image::RgbImage;
+use nalgebra::vector;
+use rand::RngCore;
+use serde::Deserialize;
+use std::time::Instant;
+
+/// How camera rays are turned into colours. Besides the path tracer, its spectral
+/// variant, the bidirectional path tracer, progressive photon mapping and Metropolis
+/// light transport there are cheap previews for checking the layout of a scene, and
+/// debug views that show why a scene is slow or looks wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrator {
+    #[default]
+    PathTracer,
+    // Path tracing at sampled wavelengths rather than in RGB, so that glass with an
+    // Abbe number splits white light into colours
+    Spectral,
+    Bidirectional, // Connects paths from the camera and the lights, for caustics
+    // Path tracing, with caustics from point and spot lights gathered from photons
+    PhotonMapping {
+        #[serde(default = "default_photons")]
+        photons: u32, // Traced for each pass
+        #[serde(default = "default_photon_radius")]
+        radius: Float, // Gather radius of the first pass, which later passes shrink
+    },
+    // Markov chains of path traced paths, for light that only few paths find. Only
+    // the image is rendered, the other AOVs stay black
+    Metropolis {
+        #[serde(default = "default_mutation_size")]
+        mutation_size: Float, // Standard deviation of small steps in primary samples
+        #[serde(default = "default_large_step_probability")]
+        large_step_probability: Float, // Chance of replacing all primary samples
+        #[serde(default = "default_bootstrap")]
+        bootstrap: u32, // Paths that estimate the image's brightness
+        #[serde(default = "default_chains")]
+        chains: u32,
+    },
+    // Share of the hemisphere above the first hit that nothing blocks within `radius`
+    AmbientOcclusion {
+        #[serde(default = "default_occlusion_radius")]
+        radius: Float,
+    },
+    Whitted, // Direct light, and what mirrors and glass show, without indirect light
+    IntersectionHeatmap, // Objects tested against the rays of the pixel's paths
+    Normals, // World space normal, with each axis mapped to [0, 1]
+    Depth,   // Distance to the first hit, brighter further away
+    UvChecker, // Checker pattern in the surface coordinates, tinted by them
+    MaterialId, // Colour for each material
+    TimeCost, // Time spent on the pixel's paths
+}
+
+impl Integrator {
+    /// Whether the view shows a value relative to the largest in the image. These
+    /// views render the raw value to every channel, which `normalize` turns into a
+    /// colour once the image is done.
+    pub fn is_normalized(&self) -> bool {
+        matches!(
+            self,
+            Integrator::IntersectionHeatmap | Integrator::Depth | Integrator::TimeCost
+        )
+    }
+
+    /// Whether the pixel averages samples spread by the pixel filter. Views of values
+    /// that can't be averaged, such as depth, trace every sample through the centre of
+    /// the pixel instead, as the unfiltered AOVs do.
+    pub fn is_filtered(&self) -> bool {
+        !matches!(self, Integrator::Depth | Integrator::MaterialId)
+    }
+
+    /// Whether light also reaches the image through paths connected to the camera,
+    /// which can land on any pixel and are added once the image is done.
+    pub fn splats(&self) -> bool {
+        matches!(
+            self,
+            Integrator::Bidirectional | Integrator::Metropolis { .. }
+        )
+    }
+
+    /// Colours the image of a normalized view from the values it rendered, given in
+    /// rows from the top left.
+    pub fn normalize(&self, values: &[Vector], image: &mut RgbImage) {
+        let max = values
+            .iter()
+            .map(|value| value.x)
+            .filter(|value| value.is_finite())
+            .fold(0.0, Float::max);
+        if max == 0.0 {
+            return;
+        }
+        for (pixel, value) in image.pixels_mut().zip(values) {
+            let fraction = (value.x / max).clamp(0.0, 1.0);
+            let colour = match self {
+                Integrator::Depth => vector![fraction, fraction, fraction],
+                _ => heat(fraction),
+            };
+            *pixel = rgb_to_srgb(srgb_to_rgb(colour));
+        }
+    }
+
+    /// Light arriving along a camera ray. Debug views return their colour as the
+    /// emission, and those that need a whole path trace it as the path tracer would,
+    /// so that the AOVs stay the same.
+    pub(crate) fn sample<'a>(
+        &self,
+        ray: &Ray,
+        scene: &'a Scene,
+        pass: &Pass,
+        max_depth: u32,
+        counters: &mut RayCounters,
+        rng: &mut dyn RngCore,
+    ) -> PathSample<'a> {
+        let splat = |value: Float| vector![value, value, value];
+        match self {
+            // Metropolis chains trace their paths themselves, as the path tracer does
+            Integrator::PathTracer | Integrator::Metropolis { .. } => {
+                trace_path(ray, scene, MIN_DIST, INFINITY, max_depth, counters, rng)
+            }
+            Integrator::Spectral => trace_spectral_path(ray, scene, max_depth, counters, rng),
+            Integrator::Bidirectional => {
+                trace_bidirectional(ray, scene, pass.film, max_depth, counters, rng)
+            }
+            Integrator::PhotonMapping { .. } => {
+                let mut path = trace_path(ray, scene, MIN_DIST, INFINITY, max_depth, counters, rng);
+                if let Some(photons) = pass.photons {
+                    let (direct, specular) = photons.gather(ray, scene, max_depth, counters, rng);
+                    path.add(Some(false), direct);
+                    path.add(Some(true), specular);
+                }
+                path
+            }
+            Integrator::AmbientOcclusion { radius } => {
+                ambient_occlusion(ray, scene, *radius, counters, rng)
+            }
+            Integrator::Whitted => trace_whitted(ray, scene, max_depth, counters, rng),
+            Integrator::IntersectionHeatmap => {
+                let tests = scene::intersection_tests();
+                let path = trace_path(ray, scene, MIN_DIST, INFINITY, max_depth, counters, rng);
+                PathSample {
+                    emission: splat((scene::intersection_tests() - tests) as Float),
+                    first_hit: path.first_hit,
+                    ..PathSample::default()
+                }
+            }
+            Integrator::TimeCost => {
+                let start = Instant::now();
+                let path = trace_path(ray, scene, MIN_DIST, INFINITY, max_depth, counters, rng);
+                PathSample {
+                    emission: splat(start.elapsed().as_secs_f64()),
+                    first_hit: path.first_hit,
+                    ..PathSample::default()
+                }
+            }
+            Integrator::Normals
+            | Integrator::Depth
+            | Integrator::UvChecker
+            | Integrator::MaterialId => {
+                counters.primary += 1;
+                let Some((object_index, intersection)) = scene
+                    .objects
+                    .trace_ray_with_index(ray, MIN_DIST, INFINITY, rng)
+                else {
+                    return PathSample::default();
+                };
+                // Colours are picked as they should look on screen
+                let emission = match self {
+                    Integrator::Normals => {
+                        srgb_to_rgb((intersection.normal.into_inner() + splat(1.0)) / 2.0)
+                    }
+                    Integrator::Depth => splat(intersection.distance),
+                    Integrator::UvChecker => {
+                        let uv = intersection.uv;
+                        let shade = if (((uv.x * 10.0).floor() + (uv.y * 10.0).floor()) as i64)
+                            .rem_euclid(2)
+                            == 0
+                        {
+                            0.9
+                        } else {
+                            0.3
+                        };
+                        let tint = vector![
+                            0.5 + 0.5 * uv.x.rem_euclid(1.0),
+                            0.5 + 0.5 * uv.y.rem_euclid(1.0),
+                            0.75
+                        ];
+                        srgb_to_rgb(tint * shade)
+                    }
+                    _ => {
+                        let id = material_id(intersection.material);
+                        let channel =
+                            |shift: u32| 0.2 + 0.8 * ((id >> shift) & 0xff) as Float / 255.0;
+                        srgb_to_rgb(vector![channel(16), channel(8), channel(0)])
+                    }
+                };
+                PathSample {
+                    emission,
+                    first_hit: Some(FirstHit::new(object_index, &intersection)),
+                    ..PathSample::default()
+                }
+            }
+        }
+    }
+}
+
+fn default_photons() -> u32 {
+    100_000
+}
+
+fn default_photon_radius() -> Float {
+    0.05
+}
+
+fn default_occlusion_radius() -> Float {
+    1.0
+}
+
+fn default_mutation_size() -> Float {
+    0.01
+}
+
+fn default_large_step_probability() -> Float {
+    0.3
+}
+
+fn default_bootstrap() -> u32 {
+    100_000
+}
+
+fn default_chains() -> u32 {
+    1000
+}
+
+/// One occlusion ray from the first hit along `ray`, cosine weighted around the
+/// side of the surface that the ray hit, so that the mean over the pixel's samples
+/// is the ambient occlusion. Misses stay black.
+fn ambient_occlusion<'a>(
+    ray: &Ray,
+    scene: &'a Scene,
+    radius: Float,
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> PathSample<'a> {
+    counters.primary += 1;
+    let Some((object_index, intersection)) = scene
+        .objects
+        .trace_ray_with_index(ray, MIN_DIST, INFINITY, rng)
+    else {
+        return PathSample::default();
+    };
+    let normal = if ray.direction.dot(&intersection.normal) > 0.0 {
+        -intersection.normal
+    } else {
+        intersection.normal
+    };
+    counters.shadow += 1;
+    let occlusion_ray = Ray {
+        origin: intersection.position,
+        direction: random_direction_on_hemisphere_cosine_weighted(&normal, rng),
+        time: ray.time,
+    };
+    let open = scene
+        .objects
+        .transmittance(&occlusion_ray, MIN_DIST, radius, rng);
+    PathSample {
+        emission: vector![open, open, open],
+        first_hit: Some(FirstHit::new(object_index, &intersection)),
+        ..PathSample::default()
+    }
+}
+
+/// Whitted-style ray tracing: light from the delta lights and one sample of the
+/// environment at the first surface that isn't specular, which ends the path, and
+/// whatever mirrors and glass on the way there reflect and refract.
+fn trace_whitted<'a>(
+    ray: &Ray,
+    scene: &'a Scene,
+    max_depth: u32,
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> PathSample<'a> {
+    let mut sample = PathSample::default();
+    let mut throughput = vector![1.0, 1.0, 1.0];
+    let mut ray = ray.clone();
+    // Whether the first bounce was specular, or None before it
+    let mut first_lobe: Option<bool> = None;
+    let mut length = 0;
+    let mut end = PathEnd::MaxDepth;
+
+    for depth in 0..max_depth {
+        if depth == 0 {
+            counters.primary += 1;
+        } else {
+            counters.secondary += 1;
+        }
+        let Some((object_index, intersection)) = scene
+            .objects
+            .trace_ray_with_index(&ray, MIN_DIST, INFINITY, rng)
+        else {
+            sample.add(
+                first_lobe,
+                throughput.component_mul(&scene.environment.radiance(&ray.direction)),
+            );
+            end = PathEnd::Escaped;
+            break;
+        };
+        length += 1;
+        if depth == 0 {
+            sample.first_hit = Some(FirstHit::new(object_index, &intersection));
+        }
+        let material = intersection.material;
+        let direct_lobe = Some(first_lobe.unwrap_or(false));
+
+        // As in the path tracer, light that scatters here is only counted if there is
+        // a bounce left for it
+        if depth + 1 < max_depth {
+            let mut light =
+                sample_lights(&ray, &intersection, scene, &None, MIN_DIST, counters, rng);
+            // Nothing else samples the environment, so its sample isn't weighted
+            if let Some(env_sample) = scene.environment.sample(rng) {
+                let f = material.eval(&ray, &intersection, &env_sample.direction);
+                if f != vector![0.0, 0.0, 0.0] {
+                    counters.shadow += 1;
+                    let shadow_ray = Ray {
+                        origin: intersection.position,
+                        direction: env_sample.direction,
+                        time: ray.time,
+                    };
+                    let transmittance =
+                        scene
+                            .objects
+                            .transmittance(&shadow_ray, MIN_DIST, INFINITY, rng);
+                    light +=
+                        f.component_mul(&env_sample.radiance) * (transmittance / env_sample.pdf);
+                }
+            }
+            sample.add(direct_lobe, throughput.component_mul(&light));
+        }
+
+        match material.scatter_ray(&ray, &intersection, rng) {
+            Some(scattered) if scattered.specular => {
+                first_lobe.get_or_insert(true);
+                throughput = throughput.component_mul(&scattered.attenuation);
+                ray = scattered.ray;
+            }
+            // Light scattered any other way is indirect, which isn't traced
+            _ => {
+                end = PathEnd::Absorbed;
+                break;
+            }
+        }
+    }
+    counters.end_path(length, end);
+    sample
+}
+
+/// Blue through cyan, green and yellow to red.
+fn heat(fraction: Float) -> Vector {
+    const STOPS: [[Float; 3]; 5] = [
+        [0.0, 0.0, 1.0],
+        [0.0, 1.0, 1.0],
+        [0.0, 1.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [1.0, 0.0, 0.0],
+    ];
+    let scaled = fraction * (STOPS.len() - 1) as Float;
+    let index = (scaled as usize).min(STOPS.len() - 2);
+    let t = scaled - index as Float;
+    Vector::from(STOPS[index]) * (1.0 - t) + Vector::from(STOPS[index + 1]) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Point;
+    use crate::environment::ConstantEnvironment;
+    use crate::lights::PointLight;
+    use crate::materials::Lambertian;
+    use crate::scene::{SceneList, Sphere};
+    use approx::assert_relative_eq;
+    use nalgebra::{point, Unit};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn heat_runs_from_blue_to_red() {
+        assert_eq!(heat(0.0), vector![0.0, 0.0, 1.0]);
+        assert_eq!(heat(0.5), vector![0.0, 1.0, 0.0]);
+        assert_eq!(heat(1.0), vector![1.0, 0.0, 0.0]);
+        assert_relative_eq!(heat(0.625), vector![0.5, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn whitted_bounces_as_often_as_the_path_tracer() {
+        let scene = Scene {
+            objects: SceneList {
+                objects: vec![Box::new(Sphere {
+                    center: Point::origin(),
+                    radius: 1.0,
+                    material: Box::new(Lambertian {
+                        color: vector![0.5, 0.5, 0.5],
+                    }),
+                })],
+            },
+            environment: Box::new(ConstantEnvironment {
+                color: vector![0.0, 0.0, 0.0],
+            }),
+            lights: vec![Box::new(PointLight {
+                position: point![0.0, 0.0, 3.0],
+                intensity: vector![4.0, 4.0, 4.0],
+            })],
+        };
+        let ray = Ray {
+            origin: point![0.0, 0.0, 4.0],
+            direction: Unit::new_normalize(vector![0.0, 0.0, -1.0]),
+            time: 0.0,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counters = RayCounters::default();
+        for max_depth in [1, 2] {
+            let whitted = trace_whitted(&ray, &scene, max_depth, &mut counters, &mut rng);
+            let path = trace_path(
+                &ray,
+                &scene,
+                MIN_DIST,
+                INFINITY,
+                max_depth,
+                &mut counters,
+                &mut rng,
+            );
+            assert_relative_eq!(whitted.radiance(), path.radiance(), epsilon = 1e-12);
+        }
+        // Without a bounce left for it, not even direct light reaches the camera
+        let whitted = trace_whitted(&ray, &scene, 1, &mut counters, &mut rng);
+        assert_eq!(whitted.radiance(), vector![0.0, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::common::Float;
+use crate::common::Point;
+use crate::common::Vector;
+use nalgebra::Isometry3;
+use serde::Deserialize;
+
+/// Values that can be blended between two keyframes.
+pub trait Interpolate: Clone {
+    fn interpolate(&self, other: &Self, t: Float) -> Self;
+}
+
+impl Interpolate for Float {
+    fn interpolate(&self, other: &Self, t: Float) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Interpolate for Point {
+    fn interpolate(&self, other: &Self, t: Float) -> Self {
+        Point::from(self.coords.lerp(&other.coords, t))
+    }
+}
+
+impl Interpolate for Vector {
+    fn interpolate(&self, other: &Self, t: Float) -> Self {
+        self.lerp(other, t)
+    }
+}
+
+impl Interpolate for Isometry3<Float> {
+    fn interpolate(&self, other: &Self, t: Float) -> Self {
+        self.lerp_slerp(other, t)
+    }
+}
+
+/// How the value moves from one keyframe to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+    Step, // Holds each value until the next keyframe
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Curve {
+    /// Maps the fraction of time between two keyframes to the fraction of the way
+    /// between their values.
+    pub fn ease(&self, t: Float) -> Float {
+        match self {
+            Curve::Step => 0.0,
+            Curve::Linear => t,
+            Curve::EaseIn => t * t,
+            Curve::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Curve::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+/// Value that changes over time. It is interpolated between keyframes along `curve`
+/// and holds its first and last values before and after them.
+#[derive(Debug, Clone)]
+pub struct Keyframes<T> {
+    keys: Vec<(Float, T)>, // Sorted by time
+    pub curve: Curve,
+}
+
+impl<T: Interpolate> Keyframes<T> {
+    pub fn new(mut keys: Vec<(Float, T)>) -> Keyframes<T> {
+        assert!(!keys.is_empty(), "Keyframes need at least one key");
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Keyframes {
+            keys,
+            curve: Curve::Linear,
+        }
+    }
+
+    pub fn constant(value: T) -> Keyframes<T> {
+        Keyframes::new(vec![(0.0, value)])
+    }
+
+    pub fn with_curve(self, curve: Curve) -> Keyframes<T> {
+        Keyframes { curve, ..self }
+    }
+
+    pub fn keys(&self) -> &[(Float, T)] {
+        &self.keys
+    }
+
+    pub fn at(&self, time: Float) -> T {
+        let next = self.keys.partition_point(|(key_time, _)| *key_time <= time);
+        if next == 0 {
+            return self.keys[0].1.clone();
+        }
+        if next == self.keys.len() {
+            return self.keys[next - 1].1.clone();
+        }
+        let (start_time, start) = &self.keys[next - 1];
+        let (end_time, end) = &self.keys[next];
+        let t = (time - start_time) / (end_time - start_time);
+        start.interpolate(end, self.curve.ease(t))
+    }
+}
+
+impl<T: Interpolate> From<T> for Keyframes<T> {
+    fn from(value: T) -> Keyframes<T> {
+        Keyframes::constant(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use nalgebra::{point, vector, Translation3, UnitQuaternion};
+
+    #[test]
+    fn interpolates_between_keys_and_holds_at_the_ends() {
+        let keyframes = Keyframes::new(vec![
+            (1.0, point![0.0, 0.0, 0.0]),
+            (0.0, point![2.0, 0.0, 0.0]),
+            (3.0, point![0.0, 4.0, 0.0]),
+        ]);
+        assert_eq!(keyframes.at(-1.0), point![2.0, 0.0, 0.0]);
+        assert_eq!(keyframes.at(0.5), point![1.0, 0.0, 0.0]);
+        assert_eq!(keyframes.at(1.0), point![0.0, 0.0, 0.0]);
+        assert_eq!(keyframes.at(2.5), point![0.0, 3.0, 0.0]);
+        assert_eq!(keyframes.at(7.0), point![0.0, 4.0, 0.0]);
+        assert_eq!(Keyframes::constant(5.0).at(3.0), 5.0);
+    }
+
+    #[test]
+    fn curves_shape_the_motion_between_keys() {
+        let keyframes = Keyframes::new(vec![(0.0, 0.0), (2.0, 1.0)]);
+        let at = |curve| keyframes.clone().with_curve(curve).at(0.5);
+        assert_eq!(at(Curve::Step), 0.0);
+        assert_eq!(at(Curve::Linear), 0.25);
+        assert_eq!(at(Curve::EaseIn), 0.0625);
+        assert_eq!(at(Curve::EaseOut), 0.4375);
+        assert_eq!(at(Curve::EaseInOut), 0.15625);
+        assert_eq!(keyframes.with_curve(Curve::Step).at(2.0), 1.0);
+    }
+
+    #[test]
+    fn transforms_rotate_along_the_shortest_arc() {
+        let keyframes = Keyframes::new(vec![
+            (0.0, Isometry3::identity()),
+            (
+                1.0,
+                Isometry3::from_parts(
+                    Translation3::new(2.0, 0.0, 0.0),
+                    UnitQuaternion::from_axis_angle(&Vector::y_axis(), 1.0),
+                ),
+            ),
+        ]);
+        let halfway = keyframes.at(0.5);
+        assert_relative_eq!(halfway.translation.vector, vector![1.0, 0.0, 0.0]);
+        assert_relative_eq!(halfway.rotation.angle(), 0.5);
+    }
+}
@@ -1,6 +1,29 @@
+pub mod aabb;
+pub mod aov;
+pub mod aperture;
+pub(crate) mod bdpt;
 pub mod camera;
 pub mod common;
+pub mod denoise;
+pub mod density;
+pub mod environment;
+pub mod integrator;
+pub mod keyframes;
+pub mod lights;
+#[cfg(any(test, feature = "material-testing"))]
+pub mod material_testing;
 pub mod materials;
+pub mod media;
+pub mod metropolis;
+pub(crate) mod photons;
+pub mod preview;
 pub mod render;
+pub mod sampling;
 pub mod scene;
+pub mod scene_file;
+pub mod sky;
+pub mod spectral;
 pub mod srgb;
+pub mod subsurface;
+#[cfg(test)]
+mod test_scenes;
@@ -0,0 +1,208 @@
+use crate::common::Direction;
+use crate::common::Float;
+use crate::common::Point;
+use crate::common::Vector;
+use crate::common::INFINITY;
+use crate::sampling::uniform_cone_direction;
+use crate::sampling::uniform_cone_pdf;
+use crate::sampling::uniform_sphere_direction;
+use crate::sampling::UNIFORM_SPHERE_PDF;
+use nalgebra::Unit;
+use rand::RngCore;
+
+#[derive(Debug)]
+pub struct LightSample {
+    pub direction: Direction, // From the shaded point towards the light
+    pub distance: Float,      // Distance to the light, infinite for directional lights
+    pub radiance: Vector,     // Incident light including falloff with distance
+}
+
+/// Ray leaving a light, which starts the light's half of a bidirectional path.
+#[derive(Debug)]
+pub struct EmittedRay {
+    pub origin: Point,
+    pub direction: Direction,
+    pub intensity: Vector, // Radiant intensity along `direction`
+    pub pdf: Float,        // Solid angle density of `direction`
+}
+
+/// Light that can't be hit by rays and only contributes through shadow rays.
+pub trait Light: Sync + Send {
+    fn sample_incident(&self, point: &Point) -> Option<LightSample>;
+
+    /// Picks a ray for tracing light away from the light. Lights that aren't at a
+    /// point, such as directional lights, return None.
+    fn sample_emission(&self, _rng: &mut dyn RngCore) -> Option<EmittedRay> {
+        None
+    }
+
+    /// Solid angle density with which sample_emission() picks `direction`.
+    fn emission_pdf(&self, _direction: &Direction) -> Float {
+        0.0
+    }
+}
+
+/// Light radiating equally in all directions from a point. `intensity` is the
+/// radiant intensity, which falls off with the inverse square of the distance.
+pub struct PointLight {
+    pub position: Point,
+    pub intensity: Vector,
+}
+
+impl Light for PointLight {
+    fn sample_incident(&self, point: &Point) -> Option<LightSample> {
+        sample_positional_light(&self.position, self.intensity, point)
+    }
+
+    fn sample_emission(&self, rng: &mut dyn RngCore) -> Option<EmittedRay> {
+        Some(EmittedRay {
+            origin: self.position,
+            direction: uniform_sphere_direction(rng),
+            intensity: self.intensity,
+            pdf: UNIFORM_SPHERE_PDF,
+        })
+    }
+
+    fn emission_pdf(&self, _direction: &Direction) -> Float {
+        UNIFORM_SPHERE_PDF
+    }
+}
+
+/// Point light restricted to a cone. The intensity is full inside
+/// `falloff_start_degrees` of `direction` and falls off smoothly to zero at
+/// `cone_angle_degrees`.
+pub struct SpotLight {
+    pub position: Point,
+    pub direction: Direction,
+    pub intensity: Vector,
+    pub cone_angle_degrees: Float,
+    pub falloff_start_degrees: Float,
+}
+
+impl SpotLight {
+    fn falloff(&self, emitted_direction: &Direction) -> Float {
+        let cos_angle = emitted_direction.dot(&self.direction);
+        let cos_cone = self.cone_angle_degrees.to_radians().cos();
+        let cos_falloff_start = self.falloff_start_degrees.to_radians().cos();
+        if cos_angle >= cos_falloff_start {
+            1.0
+        } else if cos_angle <= cos_cone || cos_falloff_start <= cos_cone {
+            0.0
+        } else {
+            let t = (cos_angle - cos_cone) / (cos_falloff_start - cos_cone);
+            t * t * (3.0 - 2.0 * t)
+        }
+    }
+}
+
+impl Light for SpotLight {
+    fn sample_incident(&self, point: &Point) -> Option<LightSample> {
+        let sample = sample_positional_light(&self.position, self.intensity, point)?;
+        let falloff = self.falloff(&-sample.direction);
+        if falloff == 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            radiance: sample.radiance * falloff,
+            ..sample
+        })
+    }
+
+    fn sample_emission(&self, rng: &mut dyn RngCore) -> Option<EmittedRay> {
+        let cos_cone = self.cone_angle_degrees.to_radians().cos();
+        let direction = uniform_cone_direction(&self.direction, cos_cone, rng);
+        let falloff = self.falloff(&direction);
+        if falloff == 0.0 {
+            return None;
+        }
+        Some(EmittedRay {
+            origin: self.position,
+            direction,
+            intensity: self.intensity * falloff,
+            pdf: uniform_cone_pdf(cos_cone),
+        })
+    }
+
+    fn emission_pdf(&self, direction: &Direction) -> Float {
+        let cos_cone = self.cone_angle_degrees.to_radians().cos();
+        if direction.dot(&self.direction) > cos_cone {
+            uniform_cone_pdf(cos_cone)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Light arriving from infinitely far away along `direction`, e.g. the sun.
+/// `irradiance` is measured on a surface facing the light.
+pub struct DirectionalLight {
+    pub direction: Direction, // Direction that the light travels in
+    pub irradiance: Vector,
+}
+
+impl Light for DirectionalLight {
+    fn sample_incident(&self, _point: &Point) -> Option<LightSample> {
+        Some(LightSample {
+            direction: -self.direction,
+            distance: INFINITY,
+            radiance: self.irradiance,
+        })
+    }
+}
+
+fn sample_positional_light(
+    position: &Point,
+    intensity: Vector,
+    point: &Point,
+) -> Option<LightSample> {
+    let to_light = position - point;
+    let distance_squared = to_light.norm_squared();
+    if distance_squared == 0.0 {
+        return None;
+    }
+    Some(LightSample {
+        direction: Unit::new_normalize(to_light),
+        distance: distance_squared.sqrt(),
+        radiance: intensity / distance_squared,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use nalgebra::{point, vector};
+
+    #[test]
+    fn point_light_falls_off_with_inverse_square() {
+        let light = PointLight {
+            position: point![0.0, 2.0, 0.0],
+            intensity: vector![4.0, 8.0, 12.0],
+        };
+        let sample = light.sample_incident(&point![0.0, 0.0, 0.0]).unwrap();
+        assert_relative_eq!(sample.radiance, vector![1.0, 2.0, 3.0]);
+        assert_relative_eq!(sample.distance, 2.0);
+        assert_relative_eq!(sample.direction.into_inner(), vector![0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn spot_light_is_limited_to_its_cone() {
+        let light = SpotLight {
+            position: point![0.0, 1.0, 0.0],
+            direction: Unit::new_normalize(vector![0.0, -1.0, 0.0]),
+            intensity: vector![1.0, 1.0, 1.0],
+            cone_angle_degrees: 30.0,
+            falloff_start_degrees: 20.0,
+        };
+        let at_angle = |degrees: Float| {
+            let point = point![degrees.to_radians().tan(), 0.0, 0.0];
+            light
+                .sample_incident(&point)
+                .map_or(0.0, |s| s.radiance.x * s.distance * s.distance)
+        };
+        assert_relative_eq!(at_angle(0.0), 1.0);
+        assert_relative_eq!(at_angle(19.0), 1.0);
+        assert!(at_angle(25.0) > 0.0 && at_angle(25.0) < 1.0);
+        assert_eq!(at_angle(31.0), 0.0);
+    }
+}
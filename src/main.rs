@@ -1,18 +1,160 @@
 use image::RgbImage;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
 use nalgebra::point;
 use nalgebra::vector;
 use raytracer::camera::Camera;
+use raytracer::camera::PerspectiveCamera;
+use raytracer::environment::ConstantEnvironment;
+use raytracer::environment::Environment;
+use raytracer::environment::EnvironmentMap;
+use raytracer::integrator::Integrator;
 use raytracer::materials::FloorMaterial;
 use raytracer::materials::Lambertian;
 use raytracer::materials::MixedMaterial;
-use raytracer::render::render;
+use raytracer::preview::serve;
+use raytracer::preview::Orbit;
+use raytracer::render::render_with_observer;
+use raytracer::render::CancellationToken;
 use raytracer::render::RenderConfig;
+use raytracer::render::RenderOutput;
 use raytracer::scene::Floor;
+use raytracer::scene::Scene;
 use raytracer::scene::SceneList;
 use raytracer::scene::Sphere;
+use raytracer::scene_file::frame_path;
+use raytracer::scene_file::SceneFile;
+use raytracer::sky::PhysicalSky;
 use raytracer::srgb::srgb_to_rgb;
+use std::error::Error;
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    // Ctrl-C stops the render after the current sample pass instead of killing it
+    let cancel = CancellationToken::new();
+    let handler_token = cancel.clone();
+    ctrlc::set_handler(move || handler_token.cancel()).unwrap();
+    match args.get(1).map(String::as_str) {
+        Some("animate") => animate(&args[2..], &cancel),
+        Some("preview") => preview(&args[2..], &cancel),
+        environment_arg => render_still(environment_arg, &cancel),
+    }
+}
+
+/// Renders frames `first..=last` of the animation in a scene file to numbered images,
+/// e.g. `raytracer animate scene.json 0 99 frames/frame_####.png`. Frames that already
+/// exist are skipped, so an interrupted render can be resumed.
+fn animate(args: &[String], cancel: &CancellationToken) {
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!(
+            "Usage: raytracer animate <scene.json> <first frame> <last frame> [output pattern]"
+        );
+        process::exit(2);
+    }
+    let parse_frame = |arg: &str| {
+        arg.parse::<u32>().unwrap_or_else(|_| {
+            eprintln!("Invalid frame number: {}", arg);
+            process::exit(2);
+        })
+    };
+    let (first, last) = (parse_frame(&args[1]), parse_frame(&args[2]));
+    let pattern = args.get(3).map_or("frame_####.png", String::as_str);
+
+    let file = SceneFile::load(Path::new(&args[0])).unwrap_or_else(|error| {
+        eprintln!("Failed to load {}: {}", args[0], error);
+        process::exit(1);
+    });
+    let scene = file.scene().unwrap_or_else(|error| {
+        eprintln!("Failed to build scene: {}", error);
+        process::exit(1);
+    });
+
+    for frame in first..=last {
+        let path = frame_path(pattern, frame);
+        if path.exists() {
+            println!(
+                "Skipping frame {}, {} already exists",
+                frame,
+                path.display()
+            );
+            continue;
+        }
+        println!("Rendering frame {} to {}", frame, path.display());
+        let output =
+            render_with_progress(&file.config, &scene, file.camera(frame).as_ref(), cancel);
+        // Partial frames aren't saved, so resuming renders them again
+        if output.incomplete {
+            println!("Cancelled frame {}", frame);
+            process::exit(130);
+        }
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory).unwrap();
+        }
+        // The image is what marks a frame as done, so it is written last
+        if !file.config.aovs.is_empty() {
+            let aov_path = path.with_extension("exr");
+            if file.separate_aov_files {
+                output.aovs.save_separate(&aov_path).unwrap();
+            } else {
+                output.aovs.save_exr(&aov_path).unwrap();
+            }
+        }
+        save_whole(&output.image, &path).unwrap();
+    }
+}
+
+/// Saves `image` to a temporary file next to `path` and then renames it into place,
+/// so that a process killed while writing never leaves a truncated frame behind.
+fn save_whole(image: &RgbImage, path: &Path) -> Result<(), Box<dyn Error>> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    let temporary = path.with_extension(format!("partial.{}", extension));
+    image.save(&temporary)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Renders the first frame of a scene file progressively and serves it to a browser,
+/// e.g. `raytracer preview scene.json 127.0.0.1:8000`. Dragging the image orbits the
+/// camera around the point it looks at.
+fn preview(args: &[String], cancel: &CancellationToken) {
+    if args.is_empty() || args.len() > 2 {
+        eprintln!("Usage: raytracer preview <scene.json> [address]");
+        process::exit(2);
+    }
+    let address = args.get(1).map_or("127.0.0.1:8000", String::as_str);
+    let file = SceneFile::load(Path::new(&args[0])).unwrap_or_else(|error| {
+        eprintln!("Failed to load {}: {}", args[0], error);
+        process::exit(1);
+    });
+    let scene = file.scene().unwrap_or_else(|error| {
+        eprintln!("Failed to build scene: {}", error);
+        process::exit(1);
+    });
+    let listener = TcpListener::bind(address).unwrap_or_else(|error| {
+        eprintln!("Failed to listen on {}: {}", address, error);
+        process::exit(1);
+    });
+    println!("Previewing at http://{}/", address);
+    let (origin, look_at) = file.camera_view(0);
+    serve(
+        listener,
+        &file.config,
+        &scene,
+        Orbit::new(origin, look_at),
+        |orbit| file.camera_with_view(0, orbit.origin(), orbit.look_at),
+        cancel,
+    )
+    .unwrap();
+}
+
+fn render_still(environment_arg: Option<&str>, cancel: &CancellationToken) {
     let aspect_ratio = 16.0 / 9.0;
     let width = 1920;
     let config = RenderConfig {
@@ -22,9 +164,22 @@ fn main() {
         samples_per_pixel: 300,
         max_depth: 50,
         tile_size: 16,
+        aovs: vec![],
+        denoiser: None,
+        integrator: Integrator::PathTracer,
+        seed: None,
     };
 
-    let scene = SceneList {
+    // Either "sky" or an equirectangular .hdr or .exr file can be given to light the scene
+    let environment: Box<dyn Environment> = match environment_arg {
+        Some("sky") => Box::new(PhysicalSky::new(35.0, 30.0, 3.0, 0.025)),
+        Some(path) => Box::new(EnvironmentMap::load(Path::new(path)).unwrap()),
+        None => Box::new(ConstantEnvironment {
+            color: srgb_to_rgb(vector![0.9, 0.9, 0.9]),
+        }),
+    };
+
+    let objects = SceneList {
         objects: vec![
             Box::new(Sphere {
                 center: point![0.0, 1.0, -5.0],
@@ -71,8 +226,13 @@ fn main() {
             }),
         ],
     };
+    let scene = Scene {
+        objects,
+        environment,
+        lights: vec![],
+    };
 
-    let camera = Camera::new(
+    let camera = PerspectiveCamera::new(
         point![0.0, 1.5, -1.0],
         point![0.0, 1.0, -5.0],
         90.0,
@@ -80,7 +240,76 @@ fn main() {
         aspect_ratio,
     );
 
-    let img: RgbImage = render(&config, &scene, &camera);
+    let output = render_with_progress(&config, &scene, &camera, cancel);
+    if output.incomplete {
+        println!("Render cancelled, saving the partial image");
+    }
+    let img: RgbImage = output.image;
 
     img.save("output.png").unwrap();
 }
+
+/// Renders with a progress bar on the terminal and prints how long it took.
+fn render_with_progress(
+    config: &RenderConfig,
+    scene: &Scene,
+    camera: &dyn Camera,
+    cancel: &CancellationToken,
+) -> RenderOutput {
+    let pb = ProgressBar::new(0);
+    pb.set_style(
+        ProgressStyle::default_bar().template(
+            "[{elapsed_precise} of {duration_precise}] {spinner} {wide_bar} {percent}% [ETA: {eta}] {msg}",
+        ),
+    );
+    pb.tick();
+    let output = render_with_observer(
+        config,
+        scene,
+        camera,
+        &|finished, total| {
+            pb.set_length(total as u64);
+            pb.set_position(finished as u64);
+        },
+        cancel,
+    );
+    pb.finish();
+
+    let stats = &output.stats;
+    println!(
+        "Rendered {:.3} million samples in {:.3} seconds. {:.5} million samples/second, {:.5} million rays/second.",
+        stats.samples as f64 / 1e6,
+        stats.render_time.as_secs_f64(),
+        stats.samples_per_second() / 1e6,
+        stats.rays_per_second() / 1e6
+    );
+    let rays = &stats.rays;
+    println!(
+        "Rays: {} primary, {} secondary, {} shadow, {} photon. {:.2} intersection tests per ray.",
+        rays.primary,
+        rays.secondary,
+        rays.shadow,
+        rays.photon,
+        rays.intersection_tests_per_ray()
+    );
+    println!(
+        "Paths: {} escaped, {} cut off by max depth, {} absorbed.",
+        rays.escaped, rays.max_depth, rays.absorbed
+    );
+    let paths = rays.paths().max(1) as f64;
+    let histogram: Vec<String> = rays
+        .path_lengths
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(length, count)| format!("{}: {:.1}%", length, *count as f64 / paths * 100.0))
+        .collect();
+    println!("Surfaces hit per path: {}", histogram.join(", "));
+    if config.denoiser.is_some() {
+        println!(
+            "Denoised in {:.3} seconds.",
+            stats.denoise_time.as_secs_f64()
+        );
+    }
+    output
+}
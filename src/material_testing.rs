@@ -0,0 +1,552 @@
+//! Statistical checks that any `Material` implementation can be run through.
+//!
+//! The material is placed at the origin of a surface facing +y, and rays arrive from
+//! a chosen outgoing direction `wo` (pointing away from the surface). The checks
+//! catch materials that create energy, whose sampler disagrees with `pdf()`, whose
+//! sample weights disagree with `eval() / pdf()`, and whose BSDF isn't reciprocal.
+
+use crate::common::Direction;
+use crate::common::Float;
+use crate::common::Material;
+use crate::common::Ray;
+use crate::common::RayIntersection;
+use crate::common::Vector;
+use nalgebra::{point, vector, Point2, Unit};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::f64::consts::PI;
+
+const THETA_BINS: usize = 10;
+const PHI_BINS: usize = 20;
+const BIN_SUBDIVISIONS: usize = 8;
+// Strongly forward-scattering phase functions vary too much within a bin for 8
+const PHASE_BIN_SUBDIVISIONS: usize = 16;
+const MIN_EXPECTED_FREQUENCY: Float = 5.0;
+
+#[derive(Debug)]
+pub struct ChiSquareResult {
+    pub statistic: Float,
+    pub degrees_of_freedom: usize,
+    pub p_value: Float,
+}
+
+/// Returns the directional albedo for light leaving along `wo`, i.e. the mean sample
+/// weight, together with its standard error. Absorbed samples count as zero.
+pub fn white_furnace(
+    material: &dyn Material,
+    wo: &Direction,
+    samples: usize,
+    rng: &mut dyn RngCore,
+) -> (Vector, Vector) {
+    let intersection = test_intersection(material);
+    let ray = incoming_ray(wo);
+    let mut sum = vector![0.0, 0.0, 0.0];
+    let mut sum_squared = vector![0.0, 0.0, 0.0];
+    for _ in 0..samples {
+        if let Some(scattered) = material.scatter_ray(&ray, &intersection, rng) {
+            sum += scattered.attenuation;
+            sum_squared += scattered.attenuation.component_mul(&scattered.attenuation);
+        }
+    }
+    let n = samples as Float;
+    let mean = sum / n;
+    let variance = (sum_squared / n - mean.component_mul(&mean)).map(|v| v.max(0.0));
+    (mean, (variance / n).map(Float::sqrt))
+}
+
+/// Runs Pearson's chi-square test of the directions sampled by `scatter_ray()` against
+/// the density given by `pdf()`. Specular and absorbed samples share one extra bin
+/// holding the probability mass that `pdf()` doesn't cover.
+pub fn chi_square_test(
+    material: &dyn Material,
+    wo: &Direction,
+    samples: usize,
+    rng: &mut dyn RngCore,
+) -> ChiSquareResult {
+    binned_chi_square_test(material, wo, samples, BIN_SUBDIVISIONS, rng)
+}
+
+fn binned_chi_square_test(
+    material: &dyn Material,
+    wo: &Direction,
+    samples: usize,
+    subdivisions: usize,
+    rng: &mut dyn RngCore,
+) -> ChiSquareResult {
+    let intersection = test_intersection(material);
+    let ray = incoming_ray(wo);
+
+    let mut observed = vec![0.0; THETA_BINS * PHI_BINS + 1];
+    for _ in 0..samples {
+        match material.scatter_ray(&ray, &intersection, rng) {
+            Some(scattered) if !scattered.specular => {
+                observed[direction_bin(&scattered.ray.direction)] += 1.0;
+            }
+            _ => observed[THETA_BINS * PHI_BINS] += 1.0,
+        }
+    }
+
+    let mut expected = integrate_pdf_over_bins(material, &ray, &intersection, subdivisions);
+    let covered: Float = expected.iter().sum();
+    expected.push((1.0 - covered).max(0.0));
+    for e in expected.iter_mut() {
+        *e *= samples as Float;
+    }
+
+    chi_square(&observed, &expected)
+}
+
+/// Largest relative difference between a non-specular sample's attenuation and
+/// `eval() / pdf()` for its direction.
+pub fn max_sample_weight_error(
+    material: &dyn Material,
+    wo: &Direction,
+    samples: usize,
+    rng: &mut dyn RngCore,
+) -> Float {
+    let intersection = test_intersection(material);
+    let ray = incoming_ray(wo);
+    let mut max_error: Float = 0.0;
+    for _ in 0..samples {
+        if let Some(scattered) = material.scatter_ray(&ray, &intersection, rng) {
+            if scattered.specular {
+                continue;
+            }
+            let direction = &scattered.ray.direction;
+            let pdf = material.pdf(&ray, &intersection, direction);
+            if pdf <= 0.0 {
+                return Float::INFINITY;
+            }
+            let expected = material.eval(&ray, &intersection, direction) / pdf;
+            let error = (scattered.attenuation - expected).amax() / expected.amax().max(1.0);
+            max_error = max_error.max(error);
+        }
+    }
+    max_error
+}
+
+/// Largest relative difference between f(wo, wi) and f(wi, wo) over random pairs of
+/// directions above the surface. f is `eval()`, with the cosine term divided out if
+/// `cosine_weighted` is set.
+pub fn max_reciprocity_error(
+    material: &dyn Material,
+    cosine_weighted: bool,
+    pairs: usize,
+    rng: &mut dyn RngCore,
+) -> Float {
+    let intersection = test_intersection(material);
+    let mut max_error: Float = 0.0;
+    for _ in 0..pairs {
+        let wo = random_upper_hemisphere_direction(rng);
+        let wi = random_upper_hemisphere_direction(rng);
+        let (cos_wo, cos_wi) = if cosine_weighted {
+            (wo.y, wi.y)
+        } else {
+            (1.0, 1.0)
+        };
+        let f_forward = material.eval(&incoming_ray(&wo), &intersection, &wi) / cos_wi;
+        let f_backward = material.eval(&incoming_ray(&wi), &intersection, &wo) / cos_wo;
+        let error = (f_forward - f_backward).amax() / f_forward.amax().max(1e-3);
+        max_error = max_error.max(error);
+    }
+    max_error
+}
+
+/// Asserts that `material` passes every check for a handful of outgoing directions.
+/// The material should be set up with a white colour so that its albedo must not
+/// exceed one.
+pub fn check_material(material: &dyn Material) {
+    check(material, true, BIN_SUBDIVISIONS);
+}
+
+/// Like check_material(), but for phase functions, whose `eval()` has no cosine term.
+pub fn check_phase_function(material: &dyn Material) {
+    check(material, false, PHASE_BIN_SUBDIVISIONS);
+}
+
+fn check(material: &dyn Material, cosine_weighted: bool, subdivisions: usize) {
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+    let directions: Vec<Direction> = [0.0, 30.0, 60.0, 85.0]
+        .iter()
+        .map(|degrees: &Float| {
+            let theta = degrees.to_radians();
+            Unit::new_normalize(vector![theta.sin(), theta.cos(), 0.0])
+        })
+        .collect();
+    // Sidak correction so the whole suite has a 1% false positive rate
+    let significance = 1.0 - (0.99 as Float).powf(1.0 / directions.len() as Float);
+
+    for wo in &directions {
+        let (albedo, std_error) = white_furnace(material, wo, 20_000, &mut rng);
+        for i in 0..3 {
+            assert!(
+                albedo[i] <= 1.0 + 5.0 * std_error[i] + 1e-9,
+                "{:?} creates energy for wo = {:?}: albedo {:?}",
+                material,
+                wo,
+                albedo
+            );
+        }
+
+        let result = binned_chi_square_test(material, wo, 20_000, subdivisions, &mut rng);
+        assert!(
+            result.p_value >= significance,
+            "{:?} samples directions inconsistent with its pdf for wo = {:?}: {:?}",
+            material,
+            wo,
+            result
+        );
+
+        let weight_error = max_sample_weight_error(material, wo, 2_000, &mut rng);
+        assert!(
+            weight_error < 1e-6,
+            "{:?} has sample weights that differ from eval/pdf for wo = {:?}: error {}",
+            material,
+            wo,
+            weight_error
+        );
+    }
+
+    let reciprocity_error = max_reciprocity_error(material, cosine_weighted, 1000, &mut rng);
+    assert!(
+        reciprocity_error < 1e-6,
+        "{:?} is not reciprocal: error {}",
+        material,
+        reciprocity_error
+    );
+}
+
+fn test_intersection(material: &dyn Material) -> RayIntersection<'_> {
+    RayIntersection {
+        position: point![0.0, 0.0, 0.0],
+        normal: Unit::new_unchecked(vector![0.0, 1.0, 0.0]),
+        uv: Point2::origin(),
+        distance: 1.0,
+        material,
+    }
+}
+
+fn incoming_ray(wo: &Direction) -> Ray {
+    Ray {
+        origin: point![0.0, 0.0, 0.0] + wo.into_inner(),
+        direction: -*wo,
+        time: 0.0,
+    }
+}
+
+fn random_upper_hemisphere_direction(rng: &mut dyn RngCore) -> Direction {
+    let cos_theta: Float = rng.gen_range(0.01..1.0);
+    let phi: Float = rng.gen_range(0.0..2.0 * PI);
+    spherical_direction(cos_theta, phi)
+}
+
+fn spherical_direction(cos_theta: Float, phi: Float) -> Direction {
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    Unit::new_normalize(vector![
+        sin_theta * phi.cos(),
+        cos_theta,
+        sin_theta * phi.sin()
+    ])
+}
+
+// Bins are equal-area: cos(theta) and phi are both split uniformly
+fn direction_bin(direction: &Direction) -> usize {
+    let cos_theta = direction.y.clamp(-1.0, 1.0);
+    let phi = direction.z.atan2(direction.x).rem_euclid(2.0 * PI);
+    let theta_bin = (((1.0 - cos_theta) / 2.0 * THETA_BINS as Float) as usize).min(THETA_BINS - 1);
+    let phi_bin = ((phi / (2.0 * PI) * PHI_BINS as Float) as usize).min(PHI_BINS - 1);
+    theta_bin * PHI_BINS + phi_bin
+}
+
+fn integrate_pdf_over_bins(
+    material: &dyn Material,
+    ray: &Ray,
+    intersection: &RayIntersection,
+    subdivisions: usize,
+) -> Vec<Float> {
+    let cos_theta_step = 2.0 / (THETA_BINS * subdivisions) as Float;
+    let phi_step = 2.0 * PI / (PHI_BINS * subdivisions) as Float;
+    let mut integrals = vec![0.0; THETA_BINS * PHI_BINS];
+    for i in 0..THETA_BINS * subdivisions {
+        let cos_theta = 1.0 - (i as Float + 0.5) * cos_theta_step;
+        for j in 0..PHI_BINS * subdivisions {
+            let phi = (j as Float + 0.5) * phi_step;
+            let direction = spherical_direction(cos_theta, phi);
+            let bin = (i / subdivisions) * PHI_BINS + j / subdivisions;
+            integrals[bin] +=
+                material.pdf(ray, intersection, &direction) * cos_theta_step * phi_step;
+        }
+    }
+    integrals
+}
+
+fn chi_square(observed: &[Float], expected: &[Float]) -> ChiSquareResult {
+    let mut bins: Vec<(Float, Float)> = observed
+        .iter()
+        .cloned()
+        .zip(expected.iter().cloned())
+        .collect();
+    bins.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+    let mut statistic = 0.0;
+    let mut degrees_of_freedom = 0;
+    let mut pooled_observed = 0.0;
+    let mut pooled_expected = 0.0;
+    for (o, e) in bins {
+        if e < MIN_EXPECTED_FREQUENCY {
+            // Samples where the pdf says there can be none mean certain failure
+            if e == 0.0 && o > 0.0 {
+                return ChiSquareResult {
+                    statistic: Float::INFINITY,
+                    degrees_of_freedom,
+                    p_value: 0.0,
+                };
+            }
+            pooled_observed += o;
+            pooled_expected += e;
+        } else {
+            statistic += (o - e).powi(2) / e;
+            degrees_of_freedom += 1;
+        }
+    }
+    if pooled_expected > 0.0 {
+        statistic += (pooled_observed - pooled_expected).powi(2) / pooled_expected;
+        degrees_of_freedom += 1;
+    }
+    // One degree of freedom is lost because the sample count is fixed
+    let degrees_of_freedom = degrees_of_freedom.saturating_sub(1);
+    let p_value = if degrees_of_freedom == 0 {
+        1.0
+    } else {
+        regularized_gamma_q(degrees_of_freedom as Float / 2.0, statistic / 2.0)
+    };
+    ChiSquareResult {
+        statistic,
+        degrees_of_freedom,
+        p_value,
+    }
+}
+
+fn ln_gamma(x: Float) -> Float {
+    // Lanczos approximation (g = 7, n = 9)
+    const COEFFICIENTS: [Float; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let series = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as Float + 1.0)
+        });
+    0.5 * (2.0 * PI).ln() + (x + 0.5) * t.ln() - t + series.ln()
+}
+
+/// Q(a, x) = Γ(a, x) / Γ(a), the upper tail of the chi-square distribution.
+fn regularized_gamma_q(a: Float, x: Float) -> Float {
+    if x <= 0.0 {
+        return 1.0;
+    }
+    let prefactor = (-x + a * x.ln() - ln_gamma(a)).exp();
+    if x < a + 1.0 {
+        // Series expansion of the lower incomplete gamma function
+        let mut term = 1.0 / a;
+        let mut sum = term;
+        let mut n = a;
+        for _ in 0..1000 {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+            if term.abs() < sum.abs() * 1e-15 {
+                break;
+            }
+        }
+        (1.0 - sum * prefactor).max(0.0)
+    } else {
+        // Continued fraction for the upper incomplete gamma function (modified Lentz)
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..1000 {
+            let an = -(i as Float) * (i as Float - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < 1e-15 {
+                break;
+            }
+        }
+        prefactor * h
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ScatteredRay;
+    use approx::assert_relative_eq;
+
+    /// Lambertian that reflects more light than it receives.
+    #[derive(Debug)]
+    struct Glowing;
+
+    impl Material for Glowing {
+        fn scatter_ray(
+            &self,
+            ray: &Ray,
+            intersection: &RayIntersection,
+            rng: &mut dyn RngCore,
+        ) -> Option<ScatteredRay> {
+            Honest
+                .scatter_ray(ray, intersection, rng)
+                .map(|s| ScatteredRay {
+                    attenuation: s.attenuation * 1.5,
+                    ..s
+                })
+        }
+
+        fn eval(&self, ray: &Ray, intersection: &RayIntersection, direction: &Direction) -> Vector {
+            Honest.eval(ray, intersection, direction) * 1.5
+        }
+
+        fn pdf(&self, ray: &Ray, intersection: &RayIntersection, direction: &Direction) -> Float {
+            Honest.pdf(ray, intersection, direction)
+        }
+    }
+
+    /// Lambertian that samples the hemisphere uniformly and weights samples correctly.
+    #[derive(Debug)]
+    struct Honest;
+
+    impl Material for Honest {
+        fn scatter_ray(
+            &self,
+            _ray: &Ray,
+            intersection: &RayIntersection,
+            rng: &mut dyn RngCore,
+        ) -> Option<ScatteredRay> {
+            let direction = spherical_direction(rng.gen(), rng.gen_range(0.0..2.0 * PI));
+            Some(ScatteredRay {
+                attenuation: vector![1.0, 1.0, 1.0] * 2.0 * direction.y,
+                ray: Ray {
+                    origin: intersection.position,
+                    direction,
+                    time: 0.0,
+                },
+                specular: false,
+            })
+        }
+
+        fn eval(
+            &self,
+            _ray: &Ray,
+            _intersection: &RayIntersection,
+            direction: &Direction,
+        ) -> Vector {
+            vector![1.0, 1.0, 1.0] * direction.y.max(0.0) / PI
+        }
+
+        fn pdf(&self, _ray: &Ray, _intersection: &RayIntersection, direction: &Direction) -> Float {
+            if direction.y >= 0.0 {
+                1.0 / (2.0 * PI)
+            } else {
+                0.0
+            }
+        }
+    }
+
+    /// Samples uniformly but claims to sample proportionally to the cosine.
+    #[derive(Debug)]
+    struct Liar;
+
+    impl Material for Liar {
+        fn scatter_ray(
+            &self,
+            ray: &Ray,
+            intersection: &RayIntersection,
+            rng: &mut dyn RngCore,
+        ) -> Option<ScatteredRay> {
+            Honest.scatter_ray(ray, intersection, rng)
+        }
+
+        fn eval(&self, ray: &Ray, intersection: &RayIntersection, direction: &Direction) -> Vector {
+            Honest.eval(ray, intersection, direction)
+        }
+
+        fn pdf(&self, _ray: &Ray, _intersection: &RayIntersection, direction: &Direction) -> Float {
+            direction.y.max(0.0) / PI
+        }
+    }
+
+    fn normal_incidence() -> Direction {
+        Unit::new_normalize(vector![0.0, 1.0, 0.0])
+    }
+
+    #[test]
+    fn regularized_gamma_q_matches_known_values() {
+        // Chi-square survival function for 1 and 10 degrees of freedom
+        assert_relative_eq!(
+            regularized_gamma_q(0.5, 3.841_459 / 2.0),
+            0.05,
+            epsilon = 1e-6
+        );
+        assert_relative_eq!(
+            regularized_gamma_q(5.0, 18.307_038 / 2.0),
+            0.05,
+            epsilon = 1e-6
+        );
+        assert_relative_eq!(
+            regularized_gamma_q(5.0, 2.558_212 / 2.0),
+            0.99,
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn honest_material_passes() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let result = chi_square_test(&Honest, &normal_incidence(), 100_000, &mut rng);
+        assert!(result.p_value > 0.001, "{:?}", result);
+        assert!(max_sample_weight_error(&Honest, &normal_incidence(), 1000, &mut rng) < 1e-6);
+    }
+
+    #[test]
+    fn white_furnace_detects_energy_creation() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let (albedo, std_error) = white_furnace(&Glowing, &normal_incidence(), 10_000, &mut rng);
+        assert!(albedo.x > 1.0 + 5.0 * std_error.x);
+    }
+
+    #[test]
+    fn chi_square_detects_sampler_disagreeing_with_pdf() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let result = chi_square_test(&Liar, &normal_incidence(), 100_000, &mut rng);
+        assert!(result.p_value < 1e-10, "{:?}", result);
+    }
+
+    #[test]
+    fn sample_weight_check_detects_inconsistent_weights() {
+        let mut rng = StdRng::seed_from_u64(4);
+        assert!(max_sample_weight_error(&Liar, &normal_incidence(), 1000, &mut rng) > 0.1);
+    }
+}
@@ -9,7 +9,7 @@ use crate::srgb::srgb_to_rgb;
 use nalgebra::vector;
 use nalgebra::Unit;
 use rand::prelude::*;
-use rand::thread_rng;
+use std::f64::consts::PI;
 
 #[derive(Debug)]
 pub struct Lambertian {
@@ -17,12 +17,30 @@ pub struct Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter_ray(&self, _ray: &Ray, intersection: &RayIntersection) -> Option<ScatteredRay> {
+    fn scatter_ray(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        rng: &mut dyn RngCore,
+    ) -> Option<ScatteredRay> {
         Some(ScatteredRay {
-            ray: generate_lambertian_ray(intersection),
+            ray: generate_lambertian_ray(ray, intersection, rng),
             attenuation: self.color,
+            specular: false,
         })
     }
+
+    fn eval(&self, _ray: &Ray, intersection: &RayIntersection, direction: &Direction) -> Vector {
+        self.color * lambertian_pdf(intersection, direction)
+    }
+
+    fn pdf(&self, _ray: &Ray, intersection: &RayIntersection, direction: &Direction) -> Float {
+        lambertian_pdf(intersection, direction)
+    }
+
+    fn albedo(&self, _intersection: &RayIntersection) -> Vector {
+        self.color
+    }
 }
 
 #[derive(Debug)]
@@ -31,14 +49,35 @@ pub struct Metal {
 }
 
 impl Material for Metal {
-    fn scatter_ray(&self, ray: &Ray, intersection: &RayIntersection) -> Option<ScatteredRay> {
+    fn scatter_ray(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        _rng: &mut dyn RngCore,
+    ) -> Option<ScatteredRay> {
         Some(ScatteredRay {
             ray: generate_reflection_ray(ray, intersection),
             attenuation: self.color,
+            specular: true,
         })
     }
+
+    fn eval(&self, _ray: &Ray, _intersection: &RayIntersection, _direction: &Direction) -> Vector {
+        vector![0.0, 0.0, 0.0]
+    }
+
+    fn pdf(&self, _ray: &Ray, _intersection: &RayIntersection, _direction: &Direction) -> Float {
+        0.0
+    }
+
+    fn albedo(&self, _intersection: &RayIntersection) -> Vector {
+        self.color
+    }
 }
 
+/// Mix of a mirror and a Lambertian lobe, both tinted by `color`. The mirror lobe is
+/// picked with probability `shininess`, which is also its weight in the mix, so the
+/// sampled lobe's attenuation is simply `color`.
 #[derive(Debug)]
 pub struct MixedMaterial {
     pub color: Vector,
@@ -46,44 +85,212 @@ pub struct MixedMaterial {
 }
 
 impl Material for MixedMaterial {
-    fn scatter_ray(&self, ray: &Ray, intersection: &RayIntersection) -> Option<ScatteredRay> {
-        let mut rng = thread_rng();
-        let scattered_ray = if rng.gen::<Float>() < self.shininess {
+    fn scatter_ray(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        rng: &mut dyn RngCore,
+    ) -> Option<ScatteredRay> {
+        let specular = rng.gen::<Float>() < self.shininess;
+        let scattered_ray = if specular {
             generate_reflection_ray(ray, intersection)
         } else {
-            generate_lambertian_ray(intersection)
+            generate_lambertian_ray(ray, intersection, rng)
         };
         Some(ScatteredRay {
             ray: scattered_ray,
             attenuation: self.color,
+            specular,
         })
     }
+
+    fn eval(&self, _ray: &Ray, intersection: &RayIntersection, direction: &Direction) -> Vector {
+        self.color * (1.0 - self.shininess) * lambertian_pdf(intersection, direction)
+    }
+
+    fn pdf(&self, _ray: &Ray, intersection: &RayIntersection, direction: &Direction) -> Float {
+        (1.0 - self.shininess) * lambertian_pdf(intersection, direction)
+    }
+
+    fn albedo(&self, _intersection: &RayIntersection) -> Vector {
+        self.color
+    }
 }
 
 #[derive(Debug)]
 pub struct FloorMaterial {
     pub color: Vector,
 }
-impl Material for FloorMaterial {
-    fn scatter_ray(&self, _ray: &Ray, intersection: &RayIntersection) -> Option<ScatteredRay> {
+
+impl FloorMaterial {
+    fn color_at(&self, intersection: &RayIntersection) -> Vector {
         let position = intersection.position;
+        if ((position.x.round() as i64) + (position.z.round() as i64)) % 2 == 0 {
+            srgb_to_rgb(self.color)
+        } else {
+            srgb_to_rgb(vector![0.2, 0.2, 0.2])
+        }
+    }
+}
+
+impl Material for FloorMaterial {
+    fn scatter_ray(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        rng: &mut dyn RngCore,
+    ) -> Option<ScatteredRay> {
         Some(ScatteredRay {
-            ray: generate_lambertian_ray(intersection),
-            attenuation: if ((position.x.round() as i64) + (position.z.round() as i64)) % 2 == 0 {
-                srgb_to_rgb(self.color)
-            } else {
-                srgb_to_rgb(vector![0.2, 0.2, 0.2])
-            },
+            ray: generate_lambertian_ray(ray, intersection, rng),
+            attenuation: self.color_at(intersection),
+            specular: false,
         })
     }
+
+    fn eval(&self, _ray: &Ray, intersection: &RayIntersection, direction: &Direction) -> Vector {
+        self.color_at(intersection) * lambertian_pdf(intersection, direction)
+    }
+
+    fn pdf(&self, _ray: &Ray, intersection: &RayIntersection, direction: &Direction) -> Float {
+        lambertian_pdf(intersection, direction)
+    }
+
+    fn albedo(&self, intersection: &RayIntersection) -> Vector {
+        self.color_at(intersection)
+    }
+}
+
+/// Smooth glass or water, which reflects and refracts light as given by the Fresnel
+/// equations. `ior` is the index of refraction inside the surface, whose normal
+/// points out into air, and `color` tints the light passing through. With an Abbe
+/// number the glass disperses light in spectral renders, and `ior` is the index at
+/// the helium d line.
+#[derive(Debug)]
+pub struct Dielectric {
+    pub color: Vector,
+    pub ior: Float,
+    pub abbe_number: Option<Float>, // Lower for more dispersion, such as 64 for crown glass
+}
+
+/// Wavelengths in nanometres of the Fraunhofer lines that define the Abbe number.
+const D_LINE: Float = 587.56;
+const F_LINE: Float = 486.13;
+const C_LINE: Float = 656.27;
+
+impl Dielectric {
+    /// Index of refraction at `wavelength` in nanometres, from Cauchy's equation
+    /// n = A + B / λ² fitted to `ior` and the Abbe number.
+    pub fn ior_at(&self, wavelength: Float) -> Float {
+        let Some(abbe_number) = self.abbe_number else {
+            return self.ior;
+        };
+        let inverse_squared = |wavelength: Float| 1e6 / (wavelength * wavelength);
+        let b =
+            (self.ior - 1.0) / (abbe_number * (inverse_squared(F_LINE) - inverse_squared(C_LINE)));
+        self.ior + b * (inverse_squared(wavelength) - inverse_squared(D_LINE))
+    }
+
+    fn scatter_with_ior(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        ior: Float,
+        rng: &mut dyn RngCore,
+    ) -> Option<ScatteredRay> {
+        let cos_incident = -ray.direction.dot(&intersection.normal);
+        let (eta, normal, cos_incident) = if cos_incident > 0.0 {
+            (1.0 / ior, intersection.normal, cos_incident)
+        } else {
+            (ior, -intersection.normal, -cos_incident)
+        };
+        // Snell's law, with total internal reflection when there is no solution
+        let sin_squared_transmitted = eta * eta * (1.0 - cos_incident * cos_incident);
+        let cos_transmitted = (1.0 - sin_squared_transmitted).max(0.0).sqrt();
+        let reflectance = if sin_squared_transmitted >= 1.0 {
+            1.0
+        } else {
+            fresnel_dielectric(eta, cos_incident, cos_transmitted)
+        };
+        let scattered = if rng.gen::<Float>() < reflectance {
+            ScatteredRay {
+                ray: generate_reflection_ray(ray, intersection),
+                attenuation: vector![1.0, 1.0, 1.0],
+                specular: true,
+            }
+        } else {
+            ScatteredRay {
+                ray: Ray {
+                    origin: intersection.position,
+                    direction: Unit::new_normalize(
+                        ray.direction.into_inner() * eta
+                            + normal.into_inner() * (eta * cos_incident - cos_transmitted),
+                    ),
+                    time: ray.time,
+                },
+                attenuation: self.color,
+                specular: true,
+            }
+        };
+        Some(scattered)
+    }
 }
 
-fn random_direction_on_hemisphere_cosine_weighted(normal: &Direction) -> Direction {
-    let mut rng = thread_rng();
+impl Material for Dielectric {
+    fn scatter_ray(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        rng: &mut dyn RngCore,
+    ) -> Option<ScatteredRay> {
+        self.scatter_with_ior(ray, intersection, self.ior, rng)
+    }
+
+    fn scatter_wavelength(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        wavelength: Float,
+        rng: &mut dyn RngCore,
+    ) -> Option<ScatteredRay> {
+        self.scatter_with_ior(ray, intersection, self.ior_at(wavelength), rng)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.abbe_number.is_some()
+    }
+
+    fn eval(&self, _ray: &Ray, _intersection: &RayIntersection, _direction: &Direction) -> Vector {
+        vector![0.0, 0.0, 0.0]
+    }
+
+    fn pdf(&self, _ray: &Ray, _intersection: &RayIntersection, _direction: &Direction) -> Float {
+        0.0
+    }
+
+    fn albedo(&self, _intersection: &RayIntersection) -> Vector {
+        self.color
+    }
+}
+
+/// Fraction of unpolarized light reflected at a smooth boundary, where `eta` is the
+/// ratio of the indices of refraction on the incident and transmitted sides.
+fn fresnel_dielectric(eta: Float, cos_incident: Float, cos_transmitted: Float) -> Float {
+    let parallel = (cos_incident - eta * cos_transmitted) / (cos_incident + eta * cos_transmitted);
+    let perpendicular =
+        (eta * cos_incident - cos_transmitted) / (eta * cos_incident + cos_transmitted);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+pub(crate) fn random_direction_on_hemisphere_cosine_weighted(
+    normal: &Direction,
+    rng: &mut dyn RngCore,
+) -> Direction {
     loop {
         let v = vector![rng.gen::<Float>(), rng.gen::<Float>(), rng.gen::<Float>()];
-        let d = Unit::new_normalize((v - vector![0.5, 0.5, 0.5]) * 2.0);
-        if d.norm_squared() <= 1.0 {
+        let v = (v - vector![0.5, 0.5, 0.5]) * 2.0;
+        // Only points inside the unit ball give uniformly distributed directions
+        if v.norm_squared() <= 1.0 && v.norm_squared() > 0.0 {
+            let d = Unit::new_normalize(v);
             let cos_of_normal_angle = d.dot(normal);
             // Accept direction with probability relative to cos(normal_angle)
             if rng.gen::<Float>() <= cos_of_normal_angle.abs() {
@@ -97,10 +304,19 @@ fn random_direction_on_hemisphere_cosine_weighted(normal: &Direction) -> Directi
     }
 }
 
-fn generate_lambertian_ray(intersection: &RayIntersection) -> Ray {
+fn lambertian_pdf(intersection: &RayIntersection, direction: &Direction) -> Float {
+    direction.dot(&intersection.normal).max(0.0) / PI
+}
+
+fn generate_lambertian_ray(
+    ray: &Ray,
+    intersection: &RayIntersection,
+    rng: &mut dyn RngCore,
+) -> Ray {
     Ray {
         origin: intersection.position,
-        direction: random_direction_on_hemisphere_cosine_weighted(&intersection.normal),
+        direction: random_direction_on_hemisphere_cosine_weighted(&intersection.normal, rng),
+        time: ray.time,
     }
 }
 
@@ -112,5 +328,144 @@ fn generate_reflection_ray(ray: &Ray, intersection: &RayIntersection) -> Ray {
     Ray {
         origin: intersection.position,
         direction: reflection,
+        time: ray.time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Point;
+    use crate::material_testing::check_material;
+    use approx::assert_relative_eq;
+    use nalgebra::point;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn lambertian_passes_material_tests() {
+        check_material(&Lambertian {
+            color: vector![1.0, 1.0, 1.0],
+        });
+    }
+
+    #[test]
+    fn metal_passes_material_tests() {
+        check_material(&Metal {
+            color: vector![1.0, 1.0, 1.0],
+        });
+    }
+
+    #[test]
+    fn mixed_material_passes_material_tests() {
+        for shininess in [0.0, 0.3, 0.95, 1.0] {
+            check_material(&MixedMaterial {
+                color: vector![1.0, 1.0, 1.0],
+                shininess,
+            });
+        }
+    }
+
+    #[test]
+    fn dielectric_passes_material_tests() {
+        for ior in [1.0, 1.33, 1.5] {
+            check_material(&Dielectric {
+                color: vector![1.0, 1.0, 1.0],
+                ior,
+                abbe_number: None,
+            });
+        }
+    }
+
+    #[test]
+    fn dielectric_follows_snell_and_fresnel() {
+        let glass = Dielectric {
+            color: vector![1.0, 1.0, 1.0],
+            ior: 1.5,
+            abbe_number: None,
+        };
+        let intersection = RayIntersection {
+            position: Point::origin(),
+            normal: Vector::y_axis(),
+            distance: 1.0,
+            material: &glass,
+            uv: point![0.0, 0.0],
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let theta: Float = 40.0_f64.to_radians();
+        let incoming = |direction: Vector| Ray {
+            origin: Point::origin() - direction,
+            direction: Unit::new_normalize(direction),
+            time: 0.0,
+        };
+
+        // From outside, 4% of light is reflected head on, rising towards grazing angles
+        let ray = incoming(vector![theta.sin(), -theta.cos(), 0.0]);
+        let scattered: Vec<ScatteredRay> = (0..20_000)
+            .map(|_| glass.scatter_ray(&ray, &intersection, &mut rng).unwrap())
+            .collect();
+        let reflected = scattered.iter().filter(|s| s.ray.direction.y > 0.0).count();
+        let fraction = reflected as Float / scattered.len() as Float;
+        assert!(fraction > 0.04 && fraction < 0.07, "{}", fraction);
+        let refracted = scattered.iter().find(|s| s.ray.direction.y < 0.0).unwrap();
+        assert_relative_eq!(refracted.ray.direction.x, theta.sin() / 1.5, epsilon = 1e-9);
+
+        // From inside, beyond the critical angle of 41.8°, everything is reflected
+        let theta: Float = 45.0_f64.to_radians();
+        let ray = incoming(vector![theta.sin(), theta.cos(), 0.0]);
+        for _ in 0..100 {
+            let scattered = glass.scatter_ray(&ray, &intersection, &mut rng).unwrap();
+            assert!(scattered.ray.direction.y < 0.0);
+        }
+    }
+
+    #[test]
+    fn dispersive_dielectric_bends_blue_light_more() {
+        // Crown glass, with an index of 1.5168 at the d line
+        let glass = Dielectric {
+            color: vector![1.0, 1.0, 1.0],
+            ior: 1.5168,
+            abbe_number: Some(64.17),
+        };
+        assert!(glass.is_dispersive());
+        assert_relative_eq!(glass.ior_at(D_LINE), 1.5168, epsilon = 1e-12);
+        assert_relative_eq!(
+            glass.ior_at(F_LINE) - glass.ior_at(C_LINE),
+            0.5168 / 64.17,
+            epsilon = 1e-12
+        );
+
+        let intersection = RayIntersection {
+            position: Point::origin(),
+            normal: Vector::y_axis(),
+            distance: 1.0,
+            material: &glass,
+            uv: point![0.0, 0.0],
+        };
+        let theta: Float = 60.0_f64.to_radians();
+        let ray = Ray {
+            origin: point![-theta.sin(), theta.cos(), 0.0],
+            direction: Unit::new_normalize(vector![theta.sin(), -theta.cos(), 0.0]),
+            time: 0.0,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut refracted_x = |wavelength: Float| loop {
+            let scattered = glass
+                .scatter_wavelength(&ray, &intersection, wavelength, &mut rng)
+                .unwrap();
+            if scattered.ray.direction.y < 0.0 {
+                return scattered.ray.direction.x;
+            }
+        };
+        let blue = refracted_x(450.0);
+        let red = refracted_x(650.0);
+        assert_relative_eq!(blue, theta.sin() / glass.ior_at(450.0), epsilon = 1e-9);
+        assert!(blue < red, "{} {}", blue, red);
+    }
+
+    #[test]
+    fn floor_material_passes_material_tests() {
+        check_material(&FloorMaterial {
+            color: vector![1.0, 1.0, 1.0],
+        });
     }
 }
@@ -0,0 +1,594 @@
+use crate::aabb::Aabb;
+use crate::common::Direction;
+use crate::common::Float;
+use crate::common::Material;
+use crate::common::Ray;
+use crate::common::RayIntersection;
+use crate::common::RayTracable;
+use crate::common::ScatteredRay;
+use crate::common::Vector;
+use crate::common::INFINITY;
+use crate::density::DensityField;
+use crate::sampling::orthonormal_basis;
+use nalgebra::Point2;
+use nalgebra::Unit;
+use rand::prelude::*;
+use std::f64::consts::PI;
+
+/// Henyey-Greenstein phase function, used as the material of scattering events inside
+/// a medium. Positive `g` scatters forwards and negative `g` backwards. Scattered
+/// light is tinted by `albedo`.
+#[derive(Debug)]
+pub struct HenyeyGreenstein {
+    pub g: Float,
+    pub albedo: Vector,
+}
+
+impl HenyeyGreenstein {
+    fn phase(&self, cos_theta: Float) -> Float {
+        let g = self.g;
+        let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+    }
+
+    /// Samples a new direction for light travelling along `incoming`, with density
+    /// equal to the phase function.
+    pub fn sample_direction(&self, incoming: &Direction, rng: &mut dyn RngCore) -> Direction {
+        let g = self.g;
+        let u: Float = rng.gen();
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+            ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng.gen::<Float>();
+        let (tangent, bitangent) = orthonormal_basis(incoming);
+        Unit::new_normalize(
+            tangent * (sin_theta * phi.cos())
+                + bitangent * (sin_theta * phi.sin())
+                + incoming.into_inner() * cos_theta,
+        )
+    }
+}
+
+impl Material for HenyeyGreenstein {
+    fn scatter_ray(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        rng: &mut dyn RngCore,
+    ) -> Option<ScatteredRay> {
+        Some(ScatteredRay {
+            ray: Ray {
+                origin: intersection.position,
+                direction: self.sample_direction(&ray.direction, rng),
+                time: ray.time,
+            },
+            attenuation: self.albedo,
+            specular: false,
+        })
+    }
+
+    fn eval(&self, ray: &Ray, _intersection: &RayIntersection, direction: &Direction) -> Vector {
+        self.albedo * self.phase(ray.direction.dot(direction))
+    }
+
+    fn pdf(&self, ray: &Ray, _intersection: &RayIntersection, direction: &Direction) -> Float {
+        self.phase(ray.direction.dot(direction))
+    }
+
+    fn albedo(&self, _intersection: &RayIntersection) -> Vector {
+        self.albedo
+    }
+}
+
+/// Medium with the same absorption and scattering coefficients everywhere. The
+/// coefficients are per unit distance and the same for all colours; `color` tints the
+/// scattered light.
+#[derive(Debug)]
+pub struct HomogeneousMedium {
+    pub absorption: Float,
+    pub scattering: Float,
+    pub color: Vector,
+    pub g: Float,
+}
+
+impl HomogeneousMedium {
+    pub fn extinction(&self) -> Float {
+        self.absorption + self.scattering
+    }
+
+    pub fn transmittance(&self, distance: Float) -> Float {
+        // Avoids 0 * infinity for clear media and rays that never enter one
+        if distance <= 0.0 || self.extinction() <= 0.0 {
+            1.0
+        } else {
+            (-self.extinction() * distance).exp()
+        }
+    }
+
+    /// Samples the free-flight distance to the next collision, which is exponentially
+    /// distributed with the extinction coefficient.
+    pub fn sample_distance(&self, rng: &mut dyn RngCore) -> Float {
+        if self.extinction() <= 0.0 {
+            INFINITY
+        } else {
+            -(1.0 - rng.gen::<Float>()).ln() / self.extinction()
+        }
+    }
+
+    fn phase_function(&self) -> HenyeyGreenstein {
+        let albedo = if self.extinction() > 0.0 {
+            self.scattering / self.extinction()
+        } else {
+            0.0
+        };
+        HenyeyGreenstein {
+            g: self.g,
+            albedo: self.color * albedo,
+        }
+    }
+}
+
+/// Homogeneous medium filling the inside of a closed boundary, or all of space below
+/// a height (global fog). Rays that collide with the medium return an intersection
+/// whose material is the phase function, while rays that pass through it return
+/// nothing, so shadow rays see the medium as occluding with probability one minus the
+/// transmittance.
+pub struct Volume {
+    extent: Extent,
+    medium: HomogeneousMedium,
+    phase_function: HenyeyGreenstein,
+}
+
+enum Extent {
+    Inside(Box<dyn RayTracable>),
+    Below(Float),
+}
+
+impl Volume {
+    pub fn new(boundary: Box<dyn RayTracable>, medium: HomogeneousMedium) -> Volume {
+        Volume {
+            extent: Extent::Inside(boundary),
+            phase_function: medium.phase_function(),
+            medium,
+        }
+    }
+
+    /// Fog everywhere below `top`. The top lets light from the sun and the sky
+    /// reach the ground through a finite amount of fog.
+    pub fn global(medium: HomogeneousMedium, top: Float) -> Volume {
+        Volume {
+            extent: Extent::Below(top),
+            phase_function: medium.phase_function(),
+            medium,
+        }
+    }
+
+    /// Part of the ray within the boundary, clipped to the distance range.
+    fn inside_range(
+        &self,
+        ray: &Ray,
+        min_dist: Float,
+        max_dist: Float,
+        rng: &mut dyn RngCore,
+    ) -> Option<(Float, Float)> {
+        let (entry, exit) = match &self.extent {
+            Extent::Below(top) => {
+                let height = top - ray.origin.y;
+                let crossing = height / ray.direction.y;
+                let (entry, exit) = if height > 0.0 {
+                    let exit = if ray.direction.y > 0.0 {
+                        crossing
+                    } else {
+                        INFINITY
+                    };
+                    (-INFINITY, exit)
+                } else if ray.direction.y < 0.0 {
+                    (crossing, INFINITY)
+                } else {
+                    return None;
+                };
+                (entry.max(min_dist), exit.min(max_dist))
+            }
+            Extent::Inside(boundary) => {
+                let entry = boundary.trace_ray(ray, -INFINITY, INFINITY, rng)?.distance;
+                let exit = boundary
+                    .trace_ray(ray, entry + 1e-6, INFINITY, rng)?
+                    .distance;
+                (entry.max(min_dist), exit.min(max_dist))
+            }
+        };
+        if entry < exit {
+            Some((entry, exit))
+        } else {
+            None
+        }
+    }
+}
+
+impl RayTracable for Volume {
+    fn trace_ray(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Option<RayIntersection<'_>> {
+        let (entry, exit) = self.inside_range(ray, min_dist, max_dist, rng)?;
+        let distance = entry + self.medium.sample_distance(rng);
+        if distance >= exit {
+            return None;
+        }
+        Some(RayIntersection {
+            position: ray.at(distance),
+            normal: -ray.direction,
+            uv: Point2::origin(),
+            distance,
+            material: &self.phase_function,
+        })
+    }
+
+    fn transmittance(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Float {
+        match self.inside_range(ray, min_dist, max_dist, rng) {
+            Some((entry, exit)) => self.medium.transmittance(exit - entry),
+            None => 1.0,
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        match &self.extent {
+            Extent::Inside(boundary) => boundary.bounding_box(),
+            Extent::Below(_) => None,
+        }
+    }
+}
+
+/// Medium inside a box whose density varies according to a density field. The
+/// coefficients of `medium` are per unit density. Collisions are found with delta
+/// tracking and shadow rays are attenuated with ratio tracking, both using the
+/// field's maximum density as the majorant.
+pub struct HeterogeneousVolume {
+    bounds: Aabb,
+    density: Box<dyn DensityField>,
+    medium: HomogeneousMedium,
+    phase_function: HenyeyGreenstein,
+}
+
+impl HeterogeneousVolume {
+    pub fn new(
+        bounds: Aabb,
+        density: Box<dyn DensityField>,
+        medium: HomogeneousMedium,
+    ) -> HeterogeneousVolume {
+        HeterogeneousVolume {
+            bounds,
+            density,
+            phase_function: medium.phase_function(),
+            medium,
+        }
+    }
+
+    fn majorant(&self) -> Float {
+        self.density.max_density() * self.medium.extinction()
+    }
+
+    /// Distance to the next tentative collision with the majorant, which includes
+    /// null collisions where the density is below its maximum.
+    fn sample_tentative_distance(&self, rng: &mut dyn RngCore) -> Float {
+        let majorant = self.majorant();
+        if majorant <= 0.0 {
+            INFINITY
+        } else {
+            -(1.0 - rng.gen::<Float>()).ln() / majorant
+        }
+    }
+
+    /// Density relative to the majorant at a distance along the ray.
+    fn collision_probability(&self, ray: &Ray, distance: Float) -> Float {
+        let local = self.bounds.local_position(&ray.at(distance));
+        self.density.density(&local) / self.density.max_density()
+    }
+}
+
+impl RayTracable for HeterogeneousVolume {
+    fn trace_ray(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Option<RayIntersection<'_>> {
+        let (entry, exit) = self.bounds.intersect(ray, min_dist, max_dist)?;
+        let mut distance = entry;
+        loop {
+            distance += self.sample_tentative_distance(rng);
+            if distance >= exit {
+                return None;
+            }
+            // Null collisions continue the walk unchanged
+            if rng.gen::<Float>() < self.collision_probability(ray, distance) {
+                return Some(RayIntersection {
+                    position: ray.at(distance),
+                    normal: -ray.direction,
+                    uv: Point2::origin(),
+                    distance,
+                    material: &self.phase_function,
+                });
+            }
+        }
+    }
+
+    fn transmittance(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Float {
+        let (entry, exit) = match self.bounds.intersect(ray, min_dist, max_dist) {
+            Some(range) => range,
+            None => return 1.0,
+        };
+        let mut transmittance = 1.0;
+        let mut distance = entry;
+        loop {
+            distance += self.sample_tentative_distance(rng);
+            if distance >= exit {
+                return transmittance;
+            }
+            transmittance *= 1.0 - self.collision_probability(ray, distance);
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::density::VoxelGrid;
+    use crate::environment::GradientEnvironment;
+    use crate::lights::DirectionalLight;
+    use crate::material_testing::check_phase_function;
+    use crate::materials::Lambertian;
+    use crate::render::sample_lights;
+    use crate::render::RayCounters;
+    use crate::scene::Scene;
+    use crate::scene::SceneList;
+    use crate::scene::Sphere;
+    use nalgebra::{point, vector};
+    use rand::rngs::StdRng;
+
+    fn medium() -> HomogeneousMedium {
+        HomogeneousMedium {
+            absorption: 0.2,
+            scattering: 0.3,
+            color: vector![1.0, 1.0, 1.0],
+            g: 0.0,
+        }
+    }
+
+    fn pass_fraction(volume: &dyn RayTracable, ray: &Ray, max_dist: Float) -> Float {
+        let mut rng = StdRng::seed_from_u64(0);
+        let n = 20_000;
+        let passed = (0..n)
+            .filter(|_| volume.trace_ray(ray, 0.0, max_dist, &mut rng).is_none())
+            .count();
+        passed as Float / n as Float
+    }
+
+    #[test]
+    fn henyey_greenstein_passes_phase_function_tests() {
+        for g in [-0.5, 0.0, 0.3, 0.7] {
+            check_phase_function(&HenyeyGreenstein {
+                g,
+                albedo: vector![1.0, 1.0, 1.0],
+            });
+        }
+    }
+
+    #[test]
+    fn rays_pass_through_sphere_with_expected_transmittance() {
+        let volume = Volume::new(
+            Box::new(Sphere {
+                center: point![0.0, 0.0, 0.0],
+                radius: 2.0,
+                material: Box::new(Lambertian {
+                    color: vector![1.0, 1.0, 1.0],
+                }),
+            }),
+            medium(),
+        );
+        let outside = Ray {
+            origin: point![0.0, 0.0, -5.0],
+            direction: Unit::new_normalize(vector![0.0, 0.0, 1.0]),
+            time: 0.0,
+        };
+        let expected = volume.medium.transmittance(4.0);
+        assert!((pass_fraction(&volume, &outside, INFINITY) - expected).abs() < 0.01);
+
+        let inside = Ray {
+            origin: point![0.0, 0.0, 0.0],
+            direction: Unit::new_normalize(vector![1.0, 0.0, 0.0]),
+            time: 0.0,
+        };
+        let expected = volume.medium.transmittance(2.0);
+        assert!((pass_fraction(&volume, &inside, INFINITY) - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn global_fog_only_collides_below_its_top_and_before_max_distance() {
+        let volume = Volume::global(medium(), 4.0);
+        let up = Ray {
+            origin: point![0.0, 0.0, 0.0],
+            direction: Unit::new_normalize(vector![0.0, 1.0, 0.0]),
+            time: 0.0,
+        };
+        let expected = volume.medium.transmittance(3.0);
+        assert!((pass_fraction(&volume, &up, 3.0) - expected).abs() < 0.01);
+        let expected = volume.medium.transmittance(4.0);
+        assert!((pass_fraction(&volume, &up, INFINITY) - expected).abs() < 0.01);
+
+        let down = Ray {
+            origin: point![0.0, 6.0, 0.0],
+            direction: Unit::new_normalize(vector![0.0, -1.0, 0.0]),
+            time: 0.0,
+        };
+        assert_eq!(pass_fraction(&volume, &down, 2.0), 1.0);
+        let expected = volume.medium.transmittance(1.0);
+        assert!((pass_fraction(&volume, &down, 3.0) - expected).abs() < 0.01);
+
+        let along = Ray {
+            origin: point![0.0, 0.0, 0.0],
+            direction: Unit::new_normalize(vector![1.0, 0.0, 0.0]),
+            time: 0.0,
+        };
+        assert_eq!(pass_fraction(&volume, &along, INFINITY), 0.0);
+    }
+
+    #[test]
+    fn clear_global_fog_lets_everything_through() {
+        let volume = Volume::global(
+            HomogeneousMedium {
+                absorption: 0.0,
+                scattering: 0.0,
+                color: vector![1.0, 1.0, 1.0],
+                g: 0.0,
+            },
+            INFINITY,
+        );
+        let ray = Ray {
+            origin: point![0.0, 0.0, 0.0],
+            direction: Unit::new_normalize(vector![1.0, 0.0, 0.0]),
+            time: 0.0,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(volume.transmittance(&ray, 0.0, INFINITY, &mut rng), 1.0);
+    }
+
+    #[test]
+    fn sunlight_is_attenuated_by_the_fog_above() {
+        let floor = Lambertian {
+            color: vector![1.0, 1.0, 1.0],
+        };
+        let intersection = RayIntersection {
+            position: point![0.0, 0.0, 0.0],
+            normal: Vector::y_axis(),
+            uv: Point2::origin(),
+            distance: 1.0,
+            material: &floor,
+        };
+        let ray = Ray {
+            origin: point![0.0, 1.0, 1.0],
+            direction: Unit::new_normalize(vector![0.0, -1.0, -1.0]),
+            time: 0.0,
+        };
+        let direct = |objects: Vec<Box<dyn RayTracable>>| {
+            let scene = Scene {
+                objects: SceneList { objects },
+                environment: Box::new(GradientEnvironment {
+                    bottom: vector![0.0, 0.0, 0.0],
+                    top: vector![0.0, 0.0, 0.0],
+                }),
+                lights: vec![Box::new(DirectionalLight {
+                    direction: Unit::new_normalize(vector![0.0, -1.0, -1.0]),
+                    irradiance: vector![1.0, 1.0, 1.0],
+                })],
+            };
+            let mut rng = StdRng::seed_from_u64(0);
+            let mut counters = RayCounters::default();
+            sample_lights(
+                &ray,
+                &intersection,
+                &scene,
+                &None,
+                0.0,
+                &mut counters,
+                &mut rng,
+            )
+        };
+        let clear = direct(vec![]);
+        let foggy = direct(vec![Box::new(Volume::global(medium(), 2.0))]);
+        assert!(clear.x > 0.0);
+        // The sun comes in at 45 degrees through 2 units of fog height
+        let expected = clear * medium().transmittance(2.0 * (2.0 as Float).sqrt());
+        approx::assert_relative_eq!(foggy, expected, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn scattering_events_carry_single_scattering_albedo() {
+        let volume = Volume::global(medium(), 10.0);
+        approx::assert_relative_eq!(volume.phase_function.albedo, vector![0.6, 0.6, 0.6]);
+    }
+
+    fn box_volume(sizes: [usize; 3], values: Vec<Float>) -> HeterogeneousVolume {
+        HeterogeneousVolume::new(
+            Aabb::new(point![-1.0, -1.0, -1.0], point![1.0, 1.0, 1.0]),
+            Box::new(VoxelGrid::new(sizes, values)),
+            medium(),
+        )
+    }
+
+    fn mean_transmittance(volume: &dyn RayTracable, ray: &Ray) -> Float {
+        let mut rng = StdRng::seed_from_u64(0);
+        let n = 20_000;
+        (0..n)
+            .map(|_| volume.transmittance(ray, 0.0, INFINITY, &mut rng))
+            .sum::<Float>()
+            / n as Float
+    }
+
+    #[test]
+    fn homogeneous_volume_has_analytic_transmittance() {
+        let volume = Volume::global(medium(), 10.0);
+        let ray = Ray {
+            origin: point![0.0, 0.0, 0.0],
+            direction: Unit::new_normalize(vector![0.0, 1.0, 0.0]),
+            time: 0.0,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        approx::assert_relative_eq!(
+            volume.transmittance(&ray, 0.0, 3.0, &mut rng),
+            volume.medium.transmittance(3.0)
+        );
+    }
+
+    #[test]
+    fn constant_grid_matches_homogeneous_medium() {
+        let volume = box_volume([1, 1, 1], vec![1.0]);
+        let ray = Ray {
+            origin: point![-3.0, 0.3, 0.2],
+            direction: Unit::new_normalize(vector![1.0, 0.0, 0.0]),
+            time: 0.0,
+        };
+        let expected = medium().transmittance(2.0);
+        assert!((pass_fraction(&volume, &ray, INFINITY) - expected).abs() < 0.01);
+        assert!((mean_transmittance(&volume, &ray) - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn tracking_through_gradient_matches_optical_depth() {
+        // Density ramps from 0 to 2 between the voxel centres, so the density
+        // integrated across the two units wide box is 2
+        let volume = box_volume([2, 1, 1], vec![0.0, 2.0]);
+        let ray = Ray {
+            origin: point![-3.0, 0.0, 0.0],
+            direction: Unit::new_normalize(vector![1.0, 0.0, 0.0]),
+            time: 0.0,
+        };
+        let expected = (-medium().extinction() * 2.0).exp();
+        assert!((mean_transmittance(&volume, &ray) - expected).abs() < 0.01);
+        assert!((pass_fraction(&volume, &ray, INFINITY) - expected).abs() < 0.01);
+    }
+}
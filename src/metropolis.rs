@@ -0,0 +1,498 @@
+use crate::common::Float;
+use crate::common::Vector;
+use crate::common::INFINITY;
+use crate::common::MIN_DIST;
+use crate::render::trace_path;
+use crate::render::CancellationToken;
+use crate::render::Film;
+use crate::render::RayCounters;
+use crate::render::RenderConfig;
+use crate::render::RenderObserver;
+use crate::sampling::Distribution1D;
+use crate::scene;
+use crate::scene::Scene;
+use crate::srgb::luminance;
+use nalgebra::{point, vector, Point2};
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::RngCore;
+use rand::SeedableRng;
+use rand_distr::StandardNormal;
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A seedable sampler over primary sample space: the uniform numbers a path is built
+/// from, which a Markov chain mutates instead of drawing new ones. The path tracer
+/// draws from it as from any other generator and gets the chain's current values in
+/// the order it asks for them. Every mutation comes from the seeded generator, so a
+/// chain is reproducible.
+pub struct PrimarySamples {
+    rng: StdRng,
+    mutation_size: Float, // Standard deviation of a small step
+    large_step_probability: Float,
+    samples: Vec<PrimarySample>,
+    index: usize, // Of the next sample handed out in this iteration
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64, // Iteration of the last accepted large step
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PrimarySample {
+    value: Float,
+    modified: u64, // Iteration that last changed the value
+    backup: Float,
+    backup_modified: u64,
+}
+
+impl PrimarySamples {
+    /// Starts out with a large step, so the first path uses fresh uniform samples.
+    pub fn new(seed: u64, mutation_size: Float, large_step_probability: Float) -> PrimarySamples {
+        PrimarySamples {
+            rng: StdRng::seed_from_u64(seed),
+            mutation_size,
+            large_step_probability,
+            samples: Vec::new(),
+            index: 0,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+        }
+    }
+
+    /// Draws future mutations from a generator seeded with `seed`, leaving the
+    /// current samples as they are.
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Proposes a new state, either by perturbing every sample a little or by
+    /// replacing them all.
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<Float>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    /// Goes back to the samples from before the proposal.
+    pub fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.modified == self.iteration {
+                sample.value = sample.backup;
+                sample.modified = sample.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    /// Next sample in [0, 1), mutated lazily: a sample that the path didn't ask for
+    /// during some iterations catches up on all of their small steps at once.
+    fn next_sample(&mut self) -> Float {
+        if self.index == self.samples.len() {
+            // Samples the path didn't use before are fresh
+            self.samples.push(PrimarySample {
+                value: self.rng.gen(),
+                modified: self.iteration,
+                backup: 0.0,
+                backup_modified: 0,
+            });
+        }
+        let sample = &mut self.samples[self.index];
+        self.index += 1;
+        if sample.modified < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.modified = self.last_large_step;
+        }
+        sample.backup = sample.value;
+        sample.backup_modified = sample.modified;
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            let steps = (self.iteration - sample.modified) as Float;
+            let offset: Float = self.rng.sample(StandardNormal);
+            sample.value =
+                (sample.value + offset * self.mutation_size * steps.sqrt()).rem_euclid(1.0);
+            // Rounding can wrap tiny negative values to 1
+            if sample.value >= 1.0 {
+                sample.value = 0.0;
+            }
+        }
+        sample.modified = self.iteration;
+        sample.value
+    }
+}
+
+impl RngCore for PrimarySamples {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_sample() * 4_294_967_296.0) as u32
+    }
+
+    // Generated floats take the highest bits, which give back the sample itself
+    fn next_u64(&mut self) -> u64 {
+        (self.next_sample() * 18_446_744_073_709_551_616.0) as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Settings of the Metropolis integrator, as given in `Integrator::Metropolis`, and
+/// the seed that all of its streams are derived from.
+pub(crate) struct MetropolisSettings {
+    pub(crate) mutation_size: Float,
+    pub(crate) large_step_probability: Float,
+    pub(crate) bootstrap: u32,
+    pub(crate) chains: u32,
+    pub(crate) seed: u64,
+}
+
+/// What the chains of a Metropolis render did.
+pub(crate) struct Chains {
+    pub(crate) mutations: u64,
+    pub(crate) rays: RayCounters,
+    pub(crate) complete: bool, // Whether every chain made all its mutations
+}
+
+/// Primary sample space Metropolis light transport (Kelemen et al.) on top of the
+/// path tracer. Paths from uniform primary samples estimate the image's mean
+/// luminance and give the chains their starting points, and each chain then splats
+/// both its current and its proposed path onto the film, weighted by how likely it
+/// is to move to them. The film is scaled by pixels per mutation, as for
+/// bidirectional splats, so `samples_per_pixel` gives the mutations per pixel.
+pub(crate) fn render_metropolis(
+    settings: &MetropolisSettings,
+    config: &RenderConfig,
+    scene: &Scene,
+    film: &Film,
+    observer: &dyn RenderObserver,
+    cancel: &CancellationToken,
+) -> Chains {
+    let evaluate = |samples: &mut PrimarySamples, rays: &mut RayCounters| {
+        path_contribution(samples, scene, film, config.max_depth, rays)
+    };
+    let new_samples = |seed| {
+        PrimarySamples::new(
+            seed,
+            settings.mutation_size,
+            settings.large_step_probability,
+        )
+    };
+
+    let (weights, mut rays) = (0..settings.bootstrap as u64)
+        .into_par_iter()
+        .fold(
+            || (Vec::new(), RayCounters::default()),
+            |(mut weights, mut rays), path| {
+                let mut samples = new_samples(stream_seed(settings.seed, path));
+                let (_, radiance) = evaluate(&mut samples, &mut rays);
+                weights.push(luminance(&radiance));
+                (weights, rays)
+            },
+        )
+        .reduce(
+            || (Vec::new(), RayCounters::default()),
+            |(mut weights, mut rays), (other_weights, other_rays)| {
+                weights.extend(other_weights);
+                rays.add(&other_rays);
+                (weights, rays)
+            },
+        );
+    let bootstrap = Distribution1D::new(weights);
+    // Mean luminance of the image, which the chains only know relative to
+    let mean = bootstrap.integral();
+    if mean == 0.0 || !mean.is_finite() {
+        return Chains {
+            mutations: 0,
+            rays,
+            complete: true,
+        };
+    }
+
+    // Positions are spread over more than the image, so each splat stands for more of it
+    let scale = mean * film.sampled_area();
+    let total = (config.width * config.height) as u64 * config.samples_per_pixel as u64;
+    let chains = settings.chains.max(1) as u64;
+    let finished_chains = AtomicUsize::new(0);
+    let results: Vec<(u64, RayCounters)> = (0..chains)
+        .into_par_iter()
+        .map(|chain| {
+            let mut rays = RayCounters::default();
+            let mut rng = StdRng::seed_from_u64(stream_seed(!settings.seed, chain));
+            let (path, _) = bootstrap.sample_discrete(rng.gen());
+            let mut samples = new_samples(stream_seed(settings.seed, path as u64));
+            let (mut current_position, mut current) = evaluate(&mut samples, &mut rays);
+            // Chains starting from the same path mutate it differently
+            samples.reseed(rng.gen());
+
+            let mutations = total / chains + u64::from(chain < total % chains);
+            let mut done = 0;
+            while done < mutations && !cancel.is_cancelled() {
+                samples.start_iteration();
+                let (position, proposed) = evaluate(&mut samples, &mut rays);
+                let (current_luminance, proposed_luminance) =
+                    (luminance(&current), luminance(&proposed));
+                let acceptance = if current_luminance > 0.0 {
+                    (proposed_luminance / current_luminance).min(1.0)
+                } else {
+                    1.0
+                };
+                if acceptance > 0.0 {
+                    film.splat(
+                        position,
+                        proposed * (acceptance * scale / proposed_luminance),
+                    );
+                }
+                if acceptance < 1.0 {
+                    film.splat(
+                        current_position,
+                        current * ((1.0 - acceptance) * scale / current_luminance),
+                    );
+                }
+                if rng.gen::<Float>() < acceptance {
+                    samples.accept();
+                    current_position = position;
+                    current = proposed;
+                } else {
+                    samples.reject();
+                }
+                done += 1;
+            }
+            let finished = finished_chains.fetch_add(1, Ordering::Relaxed) + 1;
+            observer.tile_finished(finished, chains as usize);
+            (done, rays)
+        })
+        .collect();
+
+    let mut mutations = 0;
+    for (done, chain_rays) in &results {
+        mutations += done;
+        rays.add(chain_rays);
+    }
+    Chains {
+        mutations,
+        rays,
+        complete: mutations == total,
+    }
+}
+
+/// Path traces a camera ray through a uniformly sampled film position, returning
+/// the position and the light along the ray. Paths whose light isn't finite count
+/// as black, so that they can't take over a chain.
+fn path_contribution(
+    samples: &mut PrimarySamples,
+    scene: &Scene,
+    film: &Film,
+    max_depth: u32,
+    rays: &mut RayCounters,
+) -> (Point2<Float>, Vector) {
+    let position = film.sample_screen(point![samples.gen(), samples.gen()]);
+    let lens = point![samples.gen(), samples.gen()];
+    let time = samples.gen();
+    let Some(ray) = film.camera.generate_ray(position, lens, time) else {
+        return (position, vector![0.0, 0.0, 0.0]);
+    };
+    // The path is traced on a single thread, whose count only it adds to
+    let tests = scene::intersection_tests();
+    let radiance = trace_path(&ray, scene, MIN_DIST, INFINITY, max_depth, rays, samples).radiance();
+    rays.intersection_tests += scene::intersection_tests() - tests;
+    if luminance(&radiance).is_finite() && luminance(&radiance) >= 0.0 {
+        (position, radiance)
+    } else {
+        (position, vector![0.0, 0.0, 0.0])
+    }
+}
+
+/// Seed of one of the independent streams derived from `seed`.
+fn stream_seed(seed: u64, stream: u64) -> u64 {
+    seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::Aov;
+    use crate::camera::PerspectiveCamera;
+    use crate::environment::GradientEnvironment;
+    use crate::integrator::Integrator;
+    use crate::lights::PointLight;
+    use crate::materials::Lambertian;
+    use crate::render::render_with_observer;
+    use crate::scene::Floor;
+    use crate::scene::SceneList;
+    use crate::scene::Sphere;
+
+    fn draw(samples: &mut PrimarySamples, count: usize) -> Vec<Float> {
+        (0..count).map(|_| samples.gen::<Float>()).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_same_mutations() {
+        let run = || {
+            let mut samples = PrimarySamples::new(7, 0.01, 0.3);
+            let mut drawn = vec![draw(&mut samples, 5)];
+            for iteration in 0..50 {
+                samples.start_iteration();
+                // Paths of different lengths ask for different numbers of samples
+                drawn.push(draw(&mut samples, 3 + iteration % 4));
+                if iteration % 3 == 0 {
+                    samples.reject();
+                } else {
+                    samples.accept();
+                }
+            }
+            drawn
+        };
+        assert_eq!(run(), run());
+        assert_ne!(run()[0], draw(&mut PrimarySamples::new(8, 0.01, 0.3), 5));
+    }
+
+    #[test]
+    fn small_steps_stay_close_and_rejection_restores_samples() {
+        let mut samples = PrimarySamples::new(1, 0.01, 0.0);
+        let initial = draw(&mut samples, 4);
+        assert!(initial.iter().all(|&value| (0.0..1.0).contains(&value)));
+        samples.start_iteration();
+        let mutated = draw(&mut samples, 4);
+        for (before, after) in initial.iter().zip(&mutated) {
+            // Distance around the unit circle that the samples wrap on
+            let distance = (before - after).abs().min(1.0 - (before - after).abs());
+            assert!(distance > 0.0 && distance < 0.06, "{} to {}", before, after);
+        }
+        samples.reject();
+        let restored: Vec<Float> = samples.samples.iter().map(|sample| sample.value).collect();
+        assert_eq!(restored, initial);
+    }
+
+    #[test]
+    fn large_steps_are_uniform() {
+        let mut samples = PrimarySamples::new(3, 0.01, 1.0);
+        draw(&mut samples, 1);
+        let mut drawn = Vec::new();
+        for _ in 0..10_000 {
+            samples.start_iteration();
+            drawn.extend(draw(&mut samples, 1));
+            samples.accept();
+        }
+        let mean = drawn.iter().sum::<Float>() / drawn.len() as Float;
+        assert!((mean - 0.5).abs() < 0.01, "{}", mean);
+        let below_tenth = drawn.iter().filter(|&&value| value < 0.1).count();
+        assert!((800..1200).contains(&below_tenth), "{}", below_tenth);
+    }
+
+    fn lit_scene() -> Scene {
+        Scene {
+            objects: SceneList {
+                objects: vec![
+                    Box::new(Floor {
+                        y: 0.0,
+                        material: Box::new(Lambertian {
+                            color: vector![0.6, 0.6, 0.6],
+                        }),
+                    }),
+                    Box::new(Sphere {
+                        center: point![0.0, 0.5, 0.0],
+                        radius: 0.5,
+                        material: Box::new(Lambertian {
+                            color: vector![0.8, 0.3, 0.3],
+                        }),
+                    }),
+                ],
+            },
+            environment: Box::new(GradientEnvironment {
+                bottom: vector![0.0, 0.0, 0.0],
+                top: vector![0.2, 0.2, 0.3],
+            }),
+            lights: vec![Box::new(PointLight {
+                position: point![1.0, 2.0, 1.0],
+                intensity: vector![3.0, 3.0, 3.0],
+            })],
+        }
+    }
+
+    fn render(scene: &Scene, integrator: Integrator) -> Vec<Vector> {
+        let config = RenderConfig {
+            width: 24,
+            height: 18,
+            aspect_ratio: 4.0 / 3.0,
+            samples_per_pixel: 64,
+            max_depth: 4,
+            tile_size: 8,
+            aovs: vec![Aov::Beauty],
+            denoiser: None,
+            integrator,
+            seed: Some(5),
+        };
+        let camera = PerspectiveCamera::new(
+            point![0.0, 1.5, 4.0],
+            point![0.0, 0.4, 0.0],
+            45.0,
+            0.0,
+            config.aspect_ratio,
+        );
+        let output = render_with_observer(
+            &config,
+            scene,
+            &camera,
+            &|_, _| {},
+            &CancellationToken::new(),
+        );
+        assert!(!output.incomplete);
+        assert_eq!(output.stats.samples, 24 * 18 * 64);
+        output.aovs.layer(Aov::Beauty).unwrap().to_vec()
+    }
+
+    const METROPOLIS: Integrator = Integrator::Metropolis {
+        mutation_size: 0.01,
+        large_step_probability: 0.3,
+        bootstrap: 20_000,
+        chains: 64,
+    };
+
+    #[test]
+    fn agrees_with_the_path_tracer() {
+        let scene = lit_scene();
+        let metropolis = render(&scene, METROPOLIS);
+        let path_traced = render(&scene, Integrator::PathTracer);
+        let mean = |image: &[Vector]| image.iter().sum::<Vector>() / image.len() as Float;
+        let (metropolis, path_traced) = (mean(&metropolis), mean(&path_traced));
+        assert!(
+            (metropolis.x / path_traced.x - 1.0).abs() < 0.03,
+            "{} vs {}",
+            metropolis,
+            path_traced
+        );
+        // The sphere's colour survives the luminance-driven chains
+        assert!(
+            (metropolis.y / metropolis.x - path_traced.y / path_traced.x).abs() < 0.02,
+            "{} vs {}",
+            metropolis,
+            path_traced
+        );
+    }
+
+    #[test]
+    fn same_seed_renders_the_same_image() {
+        let scene = lit_scene();
+        let first = render(&scene, METROPOLIS);
+        let second = render(&scene, METROPOLIS);
+        // Only the order that threads add splats in differs
+        for (a, b) in first.iter().zip(&second) {
+            assert!((a - b).norm() <= 1e-9 * a.norm().max(1.0), "{} vs {}", a, b);
+        }
+    }
+}
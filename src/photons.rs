@@ -0,0 +1,414 @@
+use crate::common::Direction;
+use crate::common::Float;
+use crate::common::Point;
+use crate::common::Ray;
+use crate::common::RayIntersection;
+use crate::common::RayTracable;
+use crate::common::Vector;
+use crate::common::INFINITY;
+use crate::common::MIN_DIST;
+use crate::render::stream_rng;
+use crate::render::RayCounters;
+use crate::render::RenderConfig;
+use crate::scene;
+use crate::scene::Scene;
+use nalgebra::vector;
+use rand::Rng;
+use rand::RngCore;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+/// How quickly the gather radius shrinks from pass to pass, between 0 and 1. Lower
+/// values shrink it faster, which trades noise for less blurry caustics.
+const RADIUS_REDUCTION: Float = 2.0 / 3.0;
+
+/// Photons that reached a surface from point and spot lights through mirrors and
+/// glass, for rendering the caustics that path tracing can't find. Each pass of a
+/// progressive render traces its own map with a smaller gather radius, so that the
+/// average over the passes converges to the right image.
+pub(crate) struct PhotonMap {
+    photons: Vec<Photon>,
+    emitted: usize,
+    radius: Float,
+    cells: HashMap<(i64, i64, i64), Vec<usize>>, // Photons in cubes as big as the radius
+}
+
+struct Photon {
+    position: Point,
+    direction: Direction, // Travelling towards the surface
+    normal: Direction,
+    power: Vector,
+}
+
+impl PhotonMap {
+    /// Emits `count` photons from the scene's lights in parallel, keeping those that
+    /// hit a surface after bouncing specularly at least once. Each photon of each
+    /// pass has a generator of its own, so seeded renders trace the same photons.
+    pub(crate) fn trace(
+        scene: &Scene,
+        count: usize,
+        radius: Float,
+        config: &RenderConfig,
+        pass: u32,
+        counters: &mut RayCounters,
+    ) -> PhotonMap {
+        let empty = || (Vec::new(), RayCounters::default());
+        let (photons, rays) = (0..count)
+            .into_par_iter()
+            .fold(empty, |(mut photons, mut rays), index| {
+                let mut rng = stream_rng(config, &[pass, index as u32]);
+                // The photon is traced on a single thread, whose count only it adds to
+                let tests = scene::intersection_tests();
+                trace_photon(scene, config.max_depth, &mut photons, &mut rays, &mut rng);
+                rays.intersection_tests += scene::intersection_tests() - tests;
+                (photons, rays)
+            })
+            .reduce(empty, |(mut photons, mut rays), (more, more_rays)| {
+                photons.extend(more);
+                rays.add(&more_rays);
+                (photons, rays)
+            });
+        counters.add(&rays);
+        PhotonMap::new(photons, count, radius)
+    }
+
+    fn new(photons: Vec<Photon>, emitted: usize, radius: Float) -> PhotonMap {
+        let mut cells: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+        for (index, photon) in photons.iter().enumerate() {
+            cells
+                .entry(cell(&photon.position, radius))
+                .or_default()
+                .push(index);
+        }
+        PhotonMap {
+            photons,
+            emitted,
+            radius,
+            cells,
+        }
+    }
+
+    /// Gather radius for pass `pass` of a progressive render starting at `radius`.
+    pub(crate) fn pass_radius(radius: Float, pass: u32) -> Float {
+        let squared = (1..=pass).fold(radius * radius, |squared, i| {
+            squared * (i as Float + RADIUS_REDUCTION) / (i as Float + 1.0)
+        });
+        squared.sqrt()
+    }
+
+    /// Caustic light leaving `intersection` back along `ray`, from the photons within
+    /// the gather radius.
+    pub(crate) fn radiance(&self, ray: &Ray, intersection: &RayIntersection) -> Vector {
+        let (x, y, z) = cell(&intersection.position, self.radius);
+        let mut power = vector![0.0, 0.0, 0.0];
+        for neighbour in (-1..=1)
+            .flat_map(|dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| (dx, dy, dz))))
+        {
+            let (dx, dy, dz) = neighbour;
+            let Some(indices) = self.cells.get(&(x + dx, y + dy, z + dz)) else {
+                continue;
+            };
+            for photon in indices.iter().map(|&index| &self.photons[index]) {
+                if (photon.position - intersection.position).norm_squared()
+                    > self.radius * self.radius
+                    || photon.normal.dot(&intersection.normal) < 0.5
+                {
+                    continue;
+                }
+                // The density of photons already accounts for the cosine
+                let incident = -photon.direction;
+                let cos = incident.dot(&intersection.normal).abs();
+                if cos == 0.0 {
+                    continue;
+                }
+                let f = intersection.material.eval(ray, intersection, &incident) / cos;
+                power += f.component_mul(&photon.power);
+            }
+        }
+        power / (PI * self.radius * self.radius * self.emitted as Float)
+    }
+
+    /// Caustics seen along a camera ray, directly or through mirrors and glass, as
+    /// what the first hit scatters and what reaches it through a specular bounce.
+    pub(crate) fn gather(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        max_depth: u32,
+        counters: &mut RayCounters,
+        rng: &mut dyn RngCore,
+    ) -> (Vector, Vector) {
+        let mut direct = vector![0.0, 0.0, 0.0];
+        let mut specular = vector![0.0, 0.0, 0.0];
+        let mut throughput = vector![1.0, 1.0, 1.0];
+        let mut ray = ray.clone();
+        for depth in 0..max_depth {
+            // The camera ray itself was already counted by the path tracer
+            if depth > 0 {
+                counters.secondary += 1;
+            }
+            let Some(intersection) = scene.objects.trace_ray(&ray, MIN_DIST, INFINITY, rng) else {
+                break;
+            };
+            let radiance = throughput.component_mul(&self.radiance(&ray, &intersection));
+            if depth == 0 {
+                direct += radiance;
+            } else {
+                specular += radiance;
+            }
+            match intersection.material.scatter_ray(&ray, &intersection, rng) {
+                Some(scattered) if scattered.specular => {
+                    throughput = throughput.component_mul(&scattered.attenuation);
+                    ray = scattered.ray;
+                }
+                _ => break,
+            }
+        }
+        (direct, specular)
+    }
+}
+
+fn cell(position: &Point, size: Float) -> (i64, i64, i64) {
+    let index = |coordinate: Float| (coordinate / size).floor() as i64;
+    (index(position.x), index(position.y), index(position.z))
+}
+
+/// Follows a photon from a light picked uniformly among the scene's lights through
+/// specular bounces, storing it wherever it lands after at least one of them.
+fn trace_photon(
+    scene: &Scene,
+    max_depth: u32,
+    photons: &mut Vec<Photon>,
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) {
+    if scene.lights.is_empty() {
+        return;
+    }
+    let light_choice = 1.0 / scene.lights.len() as Float;
+    let light = &scene.lights[rng.gen_range(0..scene.lights.len())];
+    let Some(emitted) = light.sample_emission(rng) else {
+        return;
+    };
+    let mut power = emitted.intensity / (light_choice * emitted.pdf);
+    let mut ray = Ray {
+        origin: emitted.origin,
+        direction: emitted.direction,
+        time: 0.0,
+    };
+    for depth in 0..max_depth {
+        counters.photon += 1;
+        let Some(intersection) = scene.objects.trace_ray(&ray, MIN_DIST, INFINITY, rng) else {
+            return;
+        };
+        let material = intersection.material;
+        // Only surfaces with a non-specular lobe show caustics
+        if depth > 0
+            && material.eval(&ray, &intersection, &intersection.normal) != vector![0.0, 0.0, 0.0]
+        {
+            photons.push(Photon {
+                position: intersection.position,
+                direction: ray.direction,
+                normal: intersection.normal,
+                power,
+            });
+        }
+        match material.scatter_ray(&ray, &intersection, rng) {
+            Some(scattered) if scattered.specular => {
+                power = power.component_mul(&scattered.attenuation);
+                ray = scattered.ray;
+            }
+            _ => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::Integrator;
+    use crate::lights::SpotLight;
+    use crate::materials::Dielectric;
+    use crate::materials::Lambertian;
+    use crate::materials::Metal;
+    use crate::scene::Sphere;
+    use crate::test_scenes::band_colour;
+    use crate::test_scenes::config;
+    use crate::test_scenes::dark_scene;
+    use crate::test_scenes::floor;
+    use crate::test_scenes::mirror_image_scene;
+    use crate::test_scenes::mirror_scene;
+    use crate::test_scenes::render;
+    use crate::test_scenes::FLOOR_ROWS;
+    use approx::assert_relative_eq;
+    use nalgebra::point;
+    use nalgebra::Unit;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn gathers_photons_within_the_radius() {
+        let floor = Lambertian {
+            color: vector![1.0, 1.0, 1.0],
+        };
+        let up = Vector::y_axis();
+        let down = -up;
+        let mut rng = StdRng::seed_from_u64(0);
+        let photons: Vec<Photon> = (0..2000)
+            .map(|_| Photon {
+                position: point![rng.gen_range(-1.0..1.0), 0.0, rng.gen_range(-1.0..1.0)],
+                direction: down,
+                normal: up,
+                power: vector![1.0, 2.0, 3.0],
+            })
+            .collect();
+        let inside = |centre: &Point, radius: Float| {
+            photons
+                .iter()
+                .filter(|photon| (photon.position - centre).norm() <= radius)
+                .count()
+        };
+        let radius = 0.13;
+        let centre = point![0.3, 0.0, -0.2];
+        let expected = inside(&centre, radius) as Float;
+        let map = PhotonMap::new(photons, 10, radius);
+        let intersection = RayIntersection {
+            position: centre,
+            normal: up,
+            distance: 1.0,
+            material: &floor,
+            uv: point![0.0, 0.0],
+        };
+        let ray = Ray {
+            origin: centre + up.into_inner(),
+            direction: Unit::new_normalize(vector![0.0, -1.0, 0.0]),
+            time: 0.0,
+        };
+        let radiance = map.radiance(&ray, &intersection);
+        assert_relative_eq!(
+            radiance,
+            vector![1.0, 2.0, 3.0] * expected / (PI * PI * radius * radius * 10.0),
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn pass_radius_shrinks_slower_than_the_photon_density_grows() {
+        assert_eq!(PhotonMap::pass_radius(0.1, 0), 0.1);
+        let mut previous = 0.1;
+        for pass in 1..100 {
+            let radius = PhotonMap::pass_radius(0.1, pass);
+            assert!(radius < previous);
+            previous = radius;
+        }
+        // Area shrinks like pass^(α - 1), so photons gathered per pixel keep growing
+        let area_ratio = PhotonMap::pass_radius(0.1, 99).powi(2) / 0.01;
+        assert!(area_ratio > 1.0 / 100.0 && area_ratio < 0.5);
+    }
+
+    #[test]
+    fn gather_counts_only_bounced_rays_as_secondary() {
+        let mirror = Metal {
+            color: vector![0.9, 0.9, 0.9],
+        };
+        let sphere = Sphere {
+            center: point![0.0, 1.0, 0.0],
+            radius: 0.5,
+            material: Box::new(mirror),
+        };
+        let scene = dark_scene(vec![floor(), Box::new(sphere)], vec![]);
+        let map = PhotonMap::new(vec![], 1, 0.1);
+        let mut rng = StdRng::seed_from_u64(0);
+        let gather = |direction: Vector, rng: &mut StdRng| {
+            let ray = Ray {
+                origin: point![0.0, 1.0, 5.0],
+                direction: Unit::new_normalize(direction),
+                time: 0.0,
+            };
+            let mut counters = RayCounters::default();
+            map.gather(&ray, &scene, 5, &mut counters, rng);
+            counters
+        };
+        // Straight onto the diffuse floor, then off the mirror and down onto it
+        assert_eq!(gather(vector![0.0, -1.0, -1.0], &mut rng).secondary, 0);
+        let mirrored = gather(vector![0.0, 0.0, -1.0], &mut rng);
+        assert_eq!((mirrored.primary, mirrored.secondary), (0, 1));
+    }
+
+    /// Mean linear colour of the floor in front of the camera.
+    fn floor_colour(scene: &Scene, integrator: Integrator) -> Vector {
+        band_colour(&render(scene, &config(integrator)), FLOOR_ROWS)
+    }
+
+    const PHOTON_MAPPING: Integrator = Integrator::PhotonMapping {
+        photons: 20_000,
+        radius: 0.25,
+    };
+
+    #[test]
+    fn finds_caustics_from_a_mirror() {
+        // Light reflected by a mirror wall onto the floor looks as if it came from the
+        // light's mirror image
+        let photon_mapped = floor_colour(&mirror_scene(), PHOTON_MAPPING);
+        let expected = floor_colour(&mirror_image_scene(), Integrator::PathTracer);
+        assert!(
+            (photon_mapped.x / expected.x - 1.0).abs() < 0.1,
+            "{} vs {}",
+            photon_mapped,
+            expected
+        );
+    }
+
+    #[test]
+    fn seeded_renders_trace_the_same_photons() {
+        let config = RenderConfig {
+            width: 16,
+            height: 12,
+            samples_per_pixel: 2,
+            seed: Some(7),
+            ..config(Integrator::PhotonMapping {
+                photons: 1000,
+                radius: 0.25,
+            })
+        };
+        let scene = mirror_scene();
+        let (first, second) = (render(&scene, &config), render(&scene, &config));
+        assert_eq!(first.image, second.image);
+        assert_eq!(first.stats.rays, second.stats.rays);
+        // Every photon traces at least the ray leaving its light, on every pass
+        assert!(first.stats.rays.photon >= 2 * 1000);
+    }
+
+    #[test]
+    fn agrees_with_bidirectional_on_glass_caustics() {
+        let glass = Sphere {
+            center: point![0.0, 0.6, 0.0],
+            radius: 0.4,
+            material: Box::new(Dielectric {
+                color: vector![1.0, 1.0, 1.0],
+                ior: 1.2,
+                abbe_number: None,
+            }),
+        };
+        // A spot just narrower than the sphere, so that the floor only gets the caustic
+        let spot = SpotLight {
+            position: point![0.0, 2.0, -1.0],
+            direction: Unit::new_normalize(vector![0.0, -1.4, 1.0]),
+            intensity: vector![4.0, 4.0, 4.0],
+            cone_angle_degrees: 12.0,
+            falloff_start_degrees: 10.0,
+        };
+        let scene = dark_scene(vec![floor(), Box::new(glass)], vec![Box::new(spot)]);
+        let photon_mapped = floor_colour(&scene, PHOTON_MAPPING);
+        let bidirectional = floor_colour(&scene, Integrator::Bidirectional);
+        let path_traced = floor_colour(&scene, Integrator::PathTracer);
+        assert!(
+            (photon_mapped.x / bidirectional.x - 1.0).abs() < 0.15,
+            "{} vs {}",
+            photon_mapped,
+            bidirectional
+        );
+        assert!(path_traced.x < bidirectional.x * 0.1);
+    }
+}
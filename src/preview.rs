@@ -0,0 +1,456 @@
+use crate::aov::Aov;
+use crate::camera::Camera;
+use crate::common::Float;
+use crate::common::Point;
+use crate::common::Vector;
+use crate::render::render_with_observer;
+use crate::render::CancellationToken;
+use crate::render::RenderConfig;
+use crate::scene::Scene;
+use crate::srgb::rgb_to_srgb;
+use image::codecs::png::PngEncoder;
+use image::{ColorType, RgbImage};
+use nalgebra::vector;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+// How often the server checks for connections and the finished render for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Position of the preview camera, circling the point it looks at with y up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Orbit {
+    pub look_at: Point,
+    pub distance: Float,
+    pub yaw_degrees: Float,   // Around the y axis, zero looking along -z
+    pub pitch_degrees: Float, // Height above the look at point, as an angle
+}
+
+impl Orbit {
+    pub fn new(origin: Point, look_at: Point) -> Orbit {
+        let offset = origin - look_at;
+        let distance = offset.norm();
+        Orbit {
+            look_at,
+            distance,
+            yaw_degrees: offset.x.atan2(offset.z).to_degrees(),
+            pitch_degrees: (offset.y / distance).asin().to_degrees(),
+        }
+    }
+
+    pub fn origin(&self) -> Point {
+        let (yaw, pitch) = (
+            self.yaw_degrees.to_radians(),
+            self.pitch_degrees.to_radians(),
+        );
+        self.look_at
+            + vector![
+                pitch.cos() * yaw.sin(),
+                pitch.sin(),
+                pitch.cos() * yaw.cos()
+            ] * self.distance
+    }
+
+    /// Turns the camera by the given angles and multiplies its distance by `zoom`.
+    /// The pitch stays short of straight up or down, where the view has no up.
+    pub fn move_by(&mut self, yaw_degrees: Float, pitch_degrees: Float, zoom: Float) {
+        self.yaw_degrees = (self.yaw_degrees + yaw_degrees) % 360.0;
+        self.pitch_degrees = (self.pitch_degrees + pitch_degrees).clamp(-89.0, 89.0);
+        if zoom > 0.0 {
+            self.distance *= zoom;
+        }
+    }
+}
+
+/// Running mean of the linear colour of successive passes.
+#[derive(Debug, Clone)]
+pub struct Accumulator {
+    pub width: u32,
+    pub height: u32,
+    pub passes: u32,
+    sum: Vec<Vector>,
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Accumulator {
+        Accumulator {
+            width,
+            height,
+            passes: 0,
+            sum: vec![vector![0.0, 0.0, 0.0]; (width * height) as usize],
+        }
+    }
+
+    pub fn add(&mut self, pass: &[Vector]) {
+        for (sum, colour) in self.sum.iter_mut().zip(pass) {
+            *sum += colour;
+        }
+        self.passes += 1;
+    }
+
+    pub fn reset(&mut self) {
+        self.sum.fill(vector![0.0, 0.0, 0.0]);
+        self.passes = 0;
+    }
+
+    pub fn image(&self) -> RgbImage {
+        let passes = self.passes.max(1) as Float;
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            rgb_to_srgb(self.sum[(y * self.width + x) as usize] / passes)
+        })
+    }
+}
+
+/// State shared by the render loop and the server.
+struct Shared {
+    orbit: Mutex<Orbit>,
+    // Cancelled when the camera moves, which ends the pass being rendered
+    restart: Mutex<CancellationToken>,
+    png: Mutex<Vec<u8>>,
+    passes: AtomicU32,
+}
+
+/// Renders the scene progressively, one sample per pixel at a time, and serves the
+/// accumulated image over HTTP on `listener` until `stop` is cancelled.
+///
+/// The page at `/` shows the image and orbits the camera when it is dragged or
+/// scrolled. Viewers can also fetch `/image.png` and `/status`, and move the camera
+/// with `/orbit?yaw=<degrees>&pitch=<degrees>&zoom=<factor>`, which restarts the
+/// accumulation. Rendering pauses once `config.samples_per_pixel` passes are done.
+pub fn serve(
+    listener: TcpListener,
+    config: &RenderConfig,
+    scene: &Scene,
+    orbit: Orbit,
+    camera: impl Fn(&Orbit) -> Box<dyn Camera>,
+    stop: &CancellationToken,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let shared = Shared {
+        orbit: Mutex::new(orbit),
+        restart: Mutex::new(CancellationToken::new()),
+        png: Mutex::new(encode_png(&RgbImage::new(config.width, config.height))),
+        passes: AtomicU32::new(0),
+    };
+    let pass_config = RenderConfig {
+        width: config.width,
+        height: config.height,
+        aspect_ratio: config.aspect_ratio,
+        samples_per_pixel: 1,
+        max_depth: config.max_depth,
+        tile_size: config.tile_size,
+        aovs: vec![Aov::Beauty],
+        denoiser: None,
+        integrator: config.integrator,
+        seed: None, // Each pass needs samples of its own
+    };
+
+    thread::scope(|scope| {
+        let server = scope.spawn(|| serve_requests(&listener, &shared, config, stop));
+
+        let mut accumulator = Accumulator::new(config.width, config.height);
+        while !stop.is_cancelled() {
+            let restart = CancellationToken::new();
+            *shared.restart.lock().unwrap() = restart.clone();
+            accumulator.reset();
+            shared.passes.store(0, Ordering::Relaxed);
+            let camera = camera(&shared.orbit.lock().unwrap());
+            while !restart.is_cancelled() && !stop.is_cancelled() {
+                if accumulator.passes >= config.samples_per_pixel {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                let output = render_with_observer(
+                    &pass_config,
+                    scene,
+                    camera.as_ref(),
+                    &|_, _| {},
+                    &restart,
+                );
+                if output.incomplete {
+                    break;
+                }
+                accumulator.add(output.aovs.layer(Aov::Beauty).unwrap());
+                let mut image = accumulator.image();
+                if config.integrator.is_normalized() {
+                    // Dividing by the largest value makes the sum as good as the mean
+                    config.integrator.normalize(&accumulator.sum, &mut image);
+                }
+                *shared.png.lock().unwrap() = encode_png(&image);
+                shared.passes.store(accumulator.passes, Ordering::Relaxed);
+            }
+        }
+        server.join().unwrap()
+    })
+}
+
+fn serve_requests(
+    listener: &TcpListener,
+    shared: &Shared,
+    config: &RenderConfig,
+    stop: &CancellationToken,
+) -> io::Result<()> {
+    while !stop.is_cancelled() {
+        match listener.accept() {
+            // A viewer that goes away mid-request shouldn't stop the preview
+            Ok((stream, _)) => {
+                let _ = handle_request(stream, shared, config);
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(error) => {
+                stop.cancel();
+                shared.restart.lock().unwrap().cancel();
+                return Err(error);
+            }
+        }
+    }
+    // End the pass in progress
+    shared.restart.lock().unwrap().cancel();
+    Ok(())
+}
+
+fn handle_request(mut stream: TcpStream, shared: &Shared, config: &RenderConfig) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let target = request.split_whitespace().nth(1).unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    match path {
+        "/" => respond(&mut stream, "200 OK", "text/html", PAGE.as_bytes()),
+        "/image.png" => {
+            let png = shared.png.lock().unwrap().clone();
+            respond(&mut stream, "200 OK", "image/png", &png)
+        }
+        "/status" => {
+            let status = format!(
+                "{{\"passes\": {}, \"samples_per_pixel\": {}}}",
+                shared.passes.load(Ordering::Relaxed),
+                config.samples_per_pixel
+            );
+            respond(&mut stream, "200 OK", "application/json", status.as_bytes())
+        }
+        "/orbit" => {
+            let parameter = |name: &str, default: Float| {
+                query
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| *key == name)
+                    .and_then(|(_, value)| value.parse::<Float>().ok())
+                    .filter(|value| value.is_finite())
+                    .unwrap_or(default)
+            };
+            shared.orbit.lock().unwrap().move_by(
+                parameter("yaw", 0.0),
+                parameter("pitch", 0.0),
+                parameter("zoom", 1.0),
+            );
+            shared.restart.lock().unwrap().cancel();
+            respond(&mut stream, "204 No Content", "text/plain", &[])
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b"Not found"),
+    }
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn encode_png(image: &RgbImage) -> Vec<u8> {
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .encode(
+            image.as_raw(),
+            image.width(),
+            image.height(),
+            ColorType::Rgb8,
+        )
+        .unwrap();
+    png
+}
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Render preview</title></head>
+<body style="margin: 0; background: #222; color: #ccc; font-family: sans-serif">
+<img id="image" src="/image.png" draggable="false" style="display: block; max-width: 100%; cursor: move">
+<p id="status" style="margin: 8px">Drag to orbit, scroll to zoom</p>
+<script>
+const image = document.getElementById("image");
+const status = document.getElementById("status");
+function orbit(query) { fetch("/orbit?" + query); }
+let drag = null;
+image.onmousedown = (event) => { drag = [event.clientX, event.clientY]; };
+window.onmouseup = () => { drag = null; };
+window.onmousemove = (event) => {
+  if (!drag) return;
+  const yaw = -(event.clientX - drag[0]) * 0.3, pitch = (event.clientY - drag[1]) * 0.3;
+  drag = [event.clientX, event.clientY];
+  orbit("yaw=" + yaw + "&pitch=" + pitch);
+};
+image.onwheel = (event) => {
+  event.preventDefault();
+  orbit("zoom=" + (event.deltaY > 0 ? 1.1 : 1 / 1.1));
+};
+async function refresh() {
+  try {
+    const next = new Image();
+    next.src = "/image.png?" + Date.now();
+    await next.decode();
+    image.src = next.src;
+    const progress = await (await fetch("/status")).json();
+    status.textContent = progress.passes + " of " + progress.samples_per_pixel +
+      " samples per pixel. Drag to orbit, scroll to zoom";
+  } catch (error) {}
+  setTimeout(refresh, 250);
+}
+refresh();
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::PerspectiveCamera;
+    use crate::environment::ConstantEnvironment;
+    use crate::integrator::Integrator;
+    use crate::materials::Lambertian;
+    use crate::scene::{SceneList, Sphere};
+    use approx::assert_relative_eq;
+    use nalgebra::point;
+
+    #[test]
+    fn orbit_circles_the_look_at_point() {
+        let mut orbit = Orbit::new(point![1.0, 2.0, 5.0], point![1.0, 0.0, 1.0]);
+        assert_relative_eq!(orbit.origin(), point![1.0, 2.0, 5.0], epsilon = 1e-12);
+        orbit.move_by(90.0, 0.0, 0.5);
+        let offset = orbit.origin() - orbit.look_at;
+        assert_relative_eq!(offset.norm(), 0.5 * 20.0_f64.sqrt(), epsilon = 1e-12);
+        assert_relative_eq!(offset.y, 1.0, epsilon = 1e-12);
+        assert_relative_eq!(offset.z, 0.0, epsilon = 1e-12);
+        orbit.move_by(0.0, 180.0, 1.0);
+        assert_eq!(orbit.pitch_degrees, 89.0);
+    }
+
+    fn get(address: &str, path: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, address).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..end]);
+        (
+            head.lines().next().unwrap().to_string(),
+            response[end + 4..].to_vec(),
+        )
+    }
+
+    #[test]
+    fn serves_accumulating_image_and_restarts_when_the_camera_moves() {
+        let scene = Scene {
+            objects: SceneList {
+                objects: vec![Box::new(Sphere {
+                    center: point![0.0, 0.0, 0.0],
+                    radius: 1.0,
+                    material: Box::new(Lambertian {
+                        color: vector![0.5, 0.5, 0.5],
+                    }),
+                })],
+            },
+            environment: Box::new(ConstantEnvironment {
+                color: vector![1.0, 1.0, 1.0],
+            }),
+            lights: vec![],
+        };
+        let config = RenderConfig {
+            width: 16,
+            height: 8,
+            aspect_ratio: 2.0,
+            samples_per_pixel: 4,
+            max_depth: 4,
+            tile_size: 8,
+            aovs: vec![],
+            denoiser: None,
+            integrator: Integrator::PathTracer,
+            seed: None,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let orbit = Orbit::new(point![0.0, 0.0, 4.0], Point::origin());
+        let cameras = AtomicU32::new(0);
+        let stop = CancellationToken::new();
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                serve(
+                    listener,
+                    &config,
+                    &scene,
+                    orbit,
+                    |orbit| {
+                        cameras.fetch_add(1, Ordering::Relaxed);
+                        Box::new(PerspectiveCamera::new(
+                            orbit.origin(),
+                            orbit.look_at,
+                            60.0,
+                            0.0,
+                            2.0,
+                        ))
+                    },
+                    &stop,
+                )
+            });
+            let wait_for_passes = || {
+                while get(&address, "/status").1 != b"{\"passes\": 4, \"samples_per_pixel\": 4}" {
+                    thread::sleep(POLL_INTERVAL);
+                }
+            };
+            wait_for_passes();
+            let (status, png) = get(&address, "/image.png");
+            assert_eq!(status, "HTTP/1.1 200 OK");
+            let image = image::load_from_memory(&png).unwrap().into_rgb8();
+            assert_eq!(image.dimensions(), (16, 8));
+            // The sphere is darker than the environment around it
+            assert!(image.get_pixel(8, 4)[0] < image.get_pixel(0, 0)[0]);
+
+            let (status, _) = get(&address, "/orbit?yaw=30&zoom=1.5");
+            assert_eq!(status, "HTTP/1.1 204 No Content");
+            while cameras.load(Ordering::Relaxed) < 2 {
+                thread::sleep(POLL_INTERVAL);
+            }
+            wait_for_passes();
+            assert_eq!(cameras.load(Ordering::Relaxed), 2);
+            assert_eq!(get(&address, "/missing").0, "HTTP/1.1 404 Not Found");
+            stop.cancel();
+            server.join().unwrap().unwrap();
+        });
+    }
+}
@@ -1,13 +1,30 @@
+use crate::aov::material_id;
+use crate::aov::Aov;
+use crate::aov::AovImage;
 use crate::camera::Camera;
+use crate::common::Direction;
 use crate::common::Float;
+use crate::common::Material;
+use crate::common::Point;
 use crate::common::Ray;
+use crate::common::RayIntersection;
 use crate::common::RayTracable;
 use crate::common::Vector;
 use crate::common::INFINITY;
+use crate::common::MIN_DIST;
+use crate::denoise::Denoiser;
+use crate::integrator::Integrator;
+use crate::metropolis::render_metropolis;
+use crate::metropolis::Chains;
+use crate::metropolis::MetropolisSettings;
+use crate::photons::PhotonMap;
+use crate::sampling::power_heuristic;
+use crate::scene;
+use crate::scene::Scene;
+use crate::spectral::Wavelengths;
+use crate::srgb::luminance;
 use crate::srgb::rgb_to_srgb;
-use crate::srgb::srgb_to_rgb;
 use image::{GenericImage, RgbImage};
-use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
 use nalgebra::{point, vector, Point2, Vector2};
 use rand::prelude::*;
 use rand::seq::SliceRandom;
@@ -15,7 +32,10 @@ use rand::thread_rng;
 use rand_distr::{Distribution, Normal};
 use rayon::prelude::*;
 use std::cmp;
-use std::time::Instant;
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub struct RenderConfig {
     pub width: u32,
@@ -24,76 +44,547 @@ pub struct RenderConfig {
     pub samples_per_pixel: u32,
     pub max_depth: u32,
     pub tile_size: u32,
+    pub aovs: Vec<Aov>, // Extra buffers to render alongside the image
+    pub denoiser: Option<Denoiser>,
+    pub integrator: Integrator,
+    pub seed: Option<u64>, // Makes the same render reproducible, random when None
 }
 
-pub fn render(config: &RenderConfig, scene: &dyn RayTracable, camera: &Camera) -> RgbImage {
-    let tiles = generate_shuffled_tiles(config);
-    println!("Number of tiles: {}", tiles.len());
+pub struct RenderOutput {
+    pub image: RgbImage,
+    pub aovs: AovImage, // Holds the AOVs listed in the config
+    pub stats: RenderStats,
+    pub incomplete: bool, // Cancelled before every pixel got all its samples
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RenderStats {
+    pub tiles: usize,
+    pub samples: u64,          // Camera samples taken
+    pub rays: RayCounters,     // Summed over all threads
+    pub render_time: Duration, // Time spent tracing
+    pub denoise_time: Duration,
+}
+
+/// What the rays of a render did, to tell whether intersecting or shading is the
+/// bottleneck. Each thread counts its own and the counts are added up at the end.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RayCounters {
+    pub primary: u64,            // Camera rays
+    pub secondary: u64,          // Rays leaving a surface after a bounce
+    pub shadow: u64,             // Rays towards lights and the environment
+    pub photon: u64,             // Rays from lights tracing photon maps
+    pub intersection_tests: u64, // Objects tested against any of the rays
+    pub escaped: u64,            // Paths that left the scene to the environment
+    pub max_depth: u64,          // Paths cut off by `max_depth`
+    pub absorbed: u64,           // Paths ended by the material
+    pub path_lengths: Vec<u64>,  // Number of paths by the number of surfaces hit
+}
+
+/// How a path ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PathEnd {
+    Escaped,
+    MaxDepth,
+    Absorbed,
+}
+
+impl RayCounters {
+    pub fn total(&self) -> u64 {
+        self.primary + self.secondary + self.shadow + self.photon
+    }
+
+    pub fn paths(&self) -> u64 {
+        self.escaped + self.max_depth + self.absorbed
+    }
+
+    pub fn intersection_tests_per_ray(&self) -> Float {
+        self.intersection_tests as Float / self.total().max(1) as Float
+    }
+
+    pub fn add(&mut self, other: &RayCounters) {
+        self.primary += other.primary;
+        self.secondary += other.secondary;
+        self.shadow += other.shadow;
+        self.photon += other.photon;
+        self.intersection_tests += other.intersection_tests;
+        self.escaped += other.escaped;
+        self.max_depth += other.max_depth;
+        self.absorbed += other.absorbed;
+        if self.path_lengths.len() < other.path_lengths.len() {
+            self.path_lengths.resize(other.path_lengths.len(), 0);
+        }
+        for (count, other_count) in self.path_lengths.iter_mut().zip(&other.path_lengths) {
+            *count += other_count;
+        }
+    }
+
+    pub(crate) fn end_path(&mut self, length: u32, end: PathEnd) {
+        match end {
+            PathEnd::Escaped => self.escaped += 1,
+            PathEnd::MaxDepth => self.max_depth += 1,
+            PathEnd::Absorbed => self.absorbed += 1,
+        }
+        let length = length as usize;
+        if self.path_lengths.len() <= length {
+            self.path_lengths.resize(length + 1, 0);
+        }
+        self.path_lengths[length] += 1;
+    }
+}
+
+impl RenderStats {
+    pub fn samples_per_second(&self) -> Float {
+        self.samples as Float / self.render_time.as_secs_f64()
+    }
+
+    pub fn rays_per_second(&self) -> Float {
+        self.rays.total() as Float / self.render_time.as_secs_f64()
+    }
+}
+
+/// Receives progress updates while rendering. Any `Fn(usize, usize)` closure can be
+/// used, which is called with the number of finished tiles and the total.
+pub trait RenderObserver: Sync {
+    /// Called from the render threads whenever a tile is done.
+    fn tile_finished(&self, finished_tiles: usize, total_tiles: usize);
+}
 
-    let aa_sigma = calc_gauss_sigma();
-    let aa_dist = Normal::new(0.0, aa_sigma).unwrap();
-    println!("Gaussian sigma for AA: {:.5}", aa_sigma);
+impl<F: Fn(usize, usize) + Sync> RenderObserver for F {
+    fn tile_finished(&self, finished_tiles: usize, total_tiles: usize) {
+        self(finished_tiles, total_tiles)
+    }
+}
+
+/// Stops a render from another thread, such as a Ctrl-C handler. Clones share the
+/// same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Makes renders using the token stop after their current sample pass.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// The camera, and light that lands on arbitrary pixels of its image such as paths
+/// traced from the lights that reach it. Threads add light without locking, and it
+/// is summed into the image once all tiles are done.
+pub(crate) struct Film<'a> {
+    pub(crate) camera: &'a dyn Camera,
+    width: u32,
+    height: u32,
+    sigma: Float,           // Of the pixel filter, in pixels
+    values: Vec<AtomicU64>, // Bits of the summed channels of each pixel
+}
+
+impl<'a> Film<'a> {
+    fn new(camera: &'a dyn Camera, width: u32, height: u32, sigma: Float) -> Film<'a> {
+        Film {
+            camera,
+            width,
+            height,
+            sigma,
+            values: (0..width * height * 3).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Adds `radiance` around a screen position as given to the camera, spread over
+    /// the pixels whose camera samples could have landed there.
+    pub(crate) fn splat(&self, screen_position: Point2<Float>, radiance: Vector) {
+        // Camera samples of a pixel are centred on its top left corner
+        let x = (screen_position.x + 1.0) / 2.0 * self.width as Float;
+        let y = (1.0 - screen_position.y) / 2.0 * self.height as Float;
+        let radius = self.filter_radius() as i64;
+        let (centre_x, centre_y) = (x.round() as i64, y.round() as i64);
+        for pixel_y in centre_y - radius..=centre_y + radius {
+            for pixel_x in centre_x - radius..=centre_x + radius {
+                if pixel_x < 0
+                    || pixel_y < 0
+                    || pixel_x >= self.width as i64
+                    || pixel_y >= self.height as i64
+                {
+                    continue;
+                }
+                let distance_squared =
+                    (x - pixel_x as Float).powi(2) + (y - pixel_y as Float).powi(2);
+                let weight = (-distance_squared / (2.0 * self.sigma * self.sigma)).exp()
+                    / (2.0 * PI * self.sigma * self.sigma);
+                let index = (pixel_y as usize * self.width as usize + pixel_x as usize) * 3;
+                for (value, channel) in self.values[index..index + 3].iter().zip(radiance.iter()) {
+                    value
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                            Some((Float::from_bits(bits) + channel * weight).to_bits())
+                        })
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    /// Screen position from a uniform `sample`, spread over every position that lands
+    /// on some pixel, which reaches past the edges of the image by the filter radius.
+    pub(crate) fn sample_screen(&self, sample: Point2<Float>) -> Point2<Float> {
+        let radius = self.filter_radius();
+        let x = sample.x * (self.width as Float - 1.0 + 2.0 * radius) - radius;
+        let y = sample.y * (self.height as Float - 1.0 + 2.0 * radius) - radius;
+        point![
+            x / self.width as Float * 2.0 - 1.0,
+            1.0 - y / self.height as Float * 2.0
+        ]
+    }
+
+    /// Area that `sample_screen` spreads positions over, relative to the image's.
+    pub(crate) fn sampled_area(&self) -> Float {
+        let radius = self.filter_radius();
+        (self.width as Float - 1.0 + 2.0 * radius) * (self.height as Float - 1.0 + 2.0 * radius)
+            / (self.width * self.height) as Float
+    }
+
+    /// Distance in pixels beyond which splats are cut off.
+    fn filter_radius(&self) -> Float {
+        (3.0 * self.sigma).ceil()
+    }
+
+    fn splats(&self) -> impl Iterator<Item = Vector> + '_ {
+        self.values.chunks(3).map(|pixel| {
+            Vector::from_iterator(
+                pixel
+                    .iter()
+                    .map(|value| Float::from_bits(value.load(Ordering::Relaxed))),
+            )
+        })
+    }
+}
 
-    let pb = ProgressBar::new(tiles.len() as u64);
-    pb.set_style(
-        ProgressStyle::default_bar().template(
-            "[{elapsed_precise} of {duration_precise}] {spinner} {wide_bar} {percent}% [ETA: {eta}] {msg}",
-        ),
-    );
-    pb.tick();
+pub fn render(config: &RenderConfig, scene: &Scene, camera: &dyn Camera) -> RgbImage {
+    render_with_observer(config, scene, camera, &|_, _| {}, &CancellationToken::new()).image
+}
 
+/// Renders while reporting progress to `observer`. Cancelling the token stops every
+/// tile after the sample pass it is in, which returns an image marked as incomplete.
+pub fn render_with_observer(
+    config: &RenderConfig,
+    scene: &Scene,
+    camera: &dyn Camera,
+    observer: &dyn RenderObserver,
+    cancel: &CancellationToken,
+) -> RenderOutput {
+    let tiles = generate_shuffled_tiles(config);
+    let total_tiles = tiles.len();
+    let aa_dist = Normal::new(0.0, calc_gauss_sigma()).unwrap();
     let start = Instant::now();
 
-    let rendered_tiles: Vec<(RenderTile, RgbImage)> = tiles
-        .into_par_iter()
-        .progress_with(pb)
-        .map(|tile| render_tile(tile, config, scene, camera, aa_dist))
-        .collect();
-
-    let duration = (Instant::now() - start).as_secs_f64();
-    let num_samples = (config.width * config.height * config.samples_per_pixel) as f64;
-    let samples_per_sec = num_samples / duration;
-    println!(
-        "Rendered {:.3} million samples in {:.3} seconds. {:.5} million samples/second.",
-        num_samples / 1e6,
-        duration,
-        samples_per_sec / 1e6
-    );
+    // The denoiser needs some AOVs of its own, and normalized debug views the values
+    let mut aovs = config.aovs.clone();
+    if config.denoiser.is_some() {
+        aovs.extend(Denoiser::AOVS);
+    }
+    if config.integrator.is_normalized() || config.integrator.splats() {
+        aovs.push(Aov::Beauty);
+    }
+    let film = Film::new(camera, config.width, config.height, calc_gauss_sigma());
+    let finished_tiles = AtomicUsize::new(0);
+    let mut chains: Option<Chains> = None;
+    let mut photon_rays = RayCounters::default();
+    let rendered_tiles: Vec<RenderedTile> = match config.integrator {
+        // Every pass needs a photon map of its own, so all tiles render a pass before
+        // the next is traced, and progress is counted in tile passes
+        Integrator::PhotonMapping { photons, radius } => {
+            let total = total_tiles * config.samples_per_pixel as usize;
+            let mut tiles: Vec<TileSamples> = tiles
+                .into_iter()
+                .map(|tile| TileSamples::new(tile, &aovs))
+                .collect();
+            for pass in 0..config.samples_per_pixel {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let radius = PhotonMap::pass_radius(radius, pass);
+                let map = PhotonMap::trace(
+                    scene,
+                    photons as usize,
+                    radius,
+                    config,
+                    pass,
+                    &mut photon_rays,
+                );
+                let pass = Pass {
+                    film: &film,
+                    photons: Some(&map),
+                };
+                tiles.par_iter_mut().for_each(|tile| {
+                    if !cancel.is_cancelled() {
+                        tile.render_pass(config, scene, &pass, aa_dist);
+                    }
+                    let finished = finished_tiles.fetch_add(1, Ordering::Relaxed) + 1;
+                    observer.tile_finished(finished, total);
+                });
+            }
+            tiles.into_iter().map(|tile| tile.finish(config)).collect()
+        }
+        // Chains wander over the whole image, so there are no tiles and progress is
+        // counted in finished chains
+        Integrator::Metropolis {
+            mutation_size,
+            large_step_probability,
+            bootstrap,
+            chains: chain_count,
+        } => {
+            let settings = MetropolisSettings {
+                mutation_size,
+                large_step_probability,
+                bootstrap,
+                chains: chain_count,
+                seed: config.seed.unwrap_or_else(|| thread_rng().gen()),
+            };
+            chains = Some(render_metropolis(
+                &settings, config, scene, &film, observer, cancel,
+            ));
+            Vec::new()
+        }
+        _ => {
+            let pass = Pass {
+                film: &film,
+                photons: None,
+            };
+            tiles
+                .into_par_iter()
+                .map(|tile| {
+                    let rendered = render_tile(tile, config, &aovs, scene, &pass, aa_dist, cancel);
+                    let finished = finished_tiles.fetch_add(1, Ordering::Relaxed) + 1;
+                    observer.tile_finished(finished, total_tiles);
+                    rendered
+                })
+                .collect()
+        }
+    };
+    let render_time = start.elapsed();
 
+    let mut stats = RenderStats {
+        tiles: total_tiles,
+        render_time,
+        ..RenderStats::default()
+    };
+    stats.rays.add(&photon_rays);
     let mut img = RgbImage::new(config.width, config.height);
-    for (tile, tile_img) in rendered_tiles {
-        img.copy_from(&tile_img, tile.offset.x, tile.offset.y)
-            .unwrap();
+    let mut aov_img = AovImage::new(config.width, config.height, &aovs);
+    let mut incomplete = false;
+    for rendered in rendered_tiles {
+        let offset = rendered.tile.offset;
+        img.copy_from(&rendered.image, offset.x, offset.y).unwrap();
+        aov_img.copy_from(&rendered.aovs, offset.x, offset.y);
+        stats.samples += rendered.samples;
+        stats.rays.add(&rendered.rays);
+        incomplete |= !rendered.complete;
+    }
+    if let Some(chains) = chains {
+        stats.samples += chains.mutations;
+        stats.rays.add(&chains.rays);
+        incomplete |= !chains.complete;
+    }
+    if config.integrator.splats() && stats.samples > 0 {
+        // Every camera sample traced one light subpath, and every mutation splatted
+        // as much light as one sample
+        let scale = (config.width * config.height) as Float / stats.samples as Float;
+        let beauty = aov_img.layer_mut(Aov::Beauty).unwrap();
+        for ((pixel, colour), splat) in img.pixels_mut().zip(beauty).zip(film.splats()) {
+            *colour += splat * scale;
+            *pixel = rgb_to_srgb(*colour);
+        }
     }
-    img
+    if config.integrator.is_normalized() {
+        config
+            .integrator
+            .normalize(aov_img.layer(Aov::Beauty).unwrap(), &mut img);
+    } else if let Some(denoiser) = &config.denoiser {
+        let start = Instant::now();
+        for (pixel, colour) in img.pixels_mut().zip(denoiser.denoise(&aov_img)) {
+            *pixel = rgb_to_srgb(colour);
+        }
+        stats.denoise_time = start.elapsed();
+    }
+    aov_img.retain(&config.aovs);
+    RenderOutput {
+        image: img,
+        aovs: aov_img,
+        stats,
+        incomplete,
+    }
+}
+
+struct RenderedTile {
+    tile: RenderTile,
+    image: RgbImage,
+    aovs: AovImage,
+    samples: u64,
+    rays: RayCounters,
+    complete: bool, // Whether the tile got all its samples
+}
+
+/// What the samples of a pass share besides the scene: the film that paths from the
+/// lights land on, and the photon map traced for the pass when there is one.
+pub(crate) struct Pass<'a> {
+    pub(crate) film: &'a Film<'a>,
+    pub(crate) photons: Option<&'a PhotonMap>,
 }
 
 fn render_tile(
     tile: RenderTile,
     config: &RenderConfig,
-    scene: &dyn RayTracable,
-    camera: &Camera,
+    aovs: &[Aov],
+    scene: &Scene,
+    pass: &Pass,
     aa_dist: Normal<Float>,
-) -> (RenderTile, RgbImage) {
-    let mut rng = thread_rng();
-    let mut img = RgbImage::new(tile.size.x, tile.size.y);
-    for y in 0..tile.size.y {
-        for x in 0..tile.size.x {
-            let mut colour = vector![0.0, 0.0, 0.0];
-            for _ in 0..config.samples_per_pixel {
-                let sx: Float = aa_dist.sample(&mut rng);
-                let sy: Float = aa_dist.sample(&mut rng);
-                let uv = point![
-                    (((tile.offset.x + x) as Float + sx) / config.width as Float - 0.5) * 2.0,
-                    (0.5 - ((tile.offset.y + y) as Float + sy) / config.height as Float) * 2.0
-                ];
-                colour += render_sample(uv, scene, camera, config.max_depth);
+    cancel: &CancellationToken,
+) -> RenderedTile {
+    let mut samples = TileSamples::new(tile, aovs);
+    // Each pass adds a sample to every pixel, so a cancelled tile is evenly sampled
+    while samples.passes < config.samples_per_pixel && !cancel.is_cancelled() {
+        samples.render_pass(config, scene, pass, aa_dist);
+    }
+    samples.finish(config)
+}
+
+/// The sums of the samples of a tile so far, added to a pass at a time.
+struct TileSamples {
+    tile: RenderTile,
+    aov_img: AovImage,
+    aovs: Vec<Aov>,
+    colours: Vec<Vector>,
+    aov_values: Vec<Vec<Vector>>,
+    passes: u32,
+    rays: RayCounters,
+}
+
+impl TileSamples {
+    fn new(tile: RenderTile, aovs: &[Aov]) -> TileSamples {
+        let aov_img = AovImage::new(tile.size.x, tile.size.y, aovs);
+        let aovs: Vec<Aov> = aov_img.aovs().collect();
+        let pixels = (tile.size.x * tile.size.y) as usize;
+        TileSamples {
+            tile,
+            aov_img,
+            colours: vec![vector![0.0, 0.0, 0.0]; pixels],
+            aov_values: vec![vec![vector![0.0, 0.0, 0.0]; aovs.len()]; pixels],
+            aovs,
+            passes: 0,
+            rays: RayCounters::default(),
+        }
+    }
+
+    fn render_pass(
+        &mut self,
+        config: &RenderConfig,
+        scene: &Scene,
+        pass: &Pass,
+        aa_dist: Normal<Float>,
+    ) {
+        let tile = &self.tile;
+        let mut rng = pass_rng(config, tile, self.passes);
+        // The pass is rendered on a single thread, whose count only this tile adds to
+        let intersection_tests = scene::intersection_tests();
+        for y in 0..tile.size.y {
+            for x in 0..tile.size.x {
+                let index = (y * tile.size.x + x) as usize;
+                let uv = |sx: Float, sy: Float| {
+                    point![
+                        (((tile.offset.x + x) as Float + sx) / config.width as Float - 0.5) * 2.0,
+                        (0.5 - ((tile.offset.y + y) as Float + sy) / config.height as Float) * 2.0
+                    ]
+                };
+                let (sx, sy) = if config.integrator.is_filtered() {
+                    (aa_dist.sample(&mut rng), aa_dist.sample(&mut rng))
+                } else {
+                    (0.0, 0.0)
+                };
+                let sample =
+                    render_sample(uv(sx, sy), scene, pass, config, &mut self.rays, &mut rng);
+                self.colours[index] += sample.radiance();
+                for (value, aov) in self.aov_values[index].iter_mut().zip(&self.aovs) {
+                    if aov.is_filtered() {
+                        *value += sample.aov(*aov);
+                    }
+                }
+                // The rest describe the first hit of a ray through the centre of the pixel
+                if self.passes == 0 && self.aovs.iter().any(|aov| !aov.is_filtered()) {
+                    // Only its camera ray counts, as it isn't one of the image's paths
+                    let mut centre_rays = RayCounters::default();
+                    let sample = render_first_hit(
+                        uv(0.0, 0.0),
+                        scene,
+                        pass.film.camera,
+                        &mut centre_rays,
+                        &mut rng,
+                    );
+                    self.rays.primary += centre_rays.primary;
+                    for (value, aov) in self.aov_values[index].iter_mut().zip(&self.aovs) {
+                        if !aov.is_filtered() {
+                            *value = sample.aov(*aov);
+                        }
+                    }
+                }
             }
-            colour /= config.samples_per_pixel as Float;
-            img.put_pixel(x, y, rgb_to_srgb(colour));
+        }
+        self.rays.intersection_tests += scene::intersection_tests() - intersection_tests;
+        self.passes += 1;
+    }
+
+    fn finish(self, config: &RenderConfig) -> RenderedTile {
+        let TileSamples {
+            tile,
+            mut aov_img,
+            aovs,
+            colours,
+            aov_values,
+            passes,
+            rays,
+        } = self;
+        let mut img = RgbImage::new(tile.size.x, tile.size.y);
+        // Tiles cancelled before their first pass stay black
+        if passes > 0 {
+            let samples = passes as Float;
+            for y in 0..tile.size.y {
+                for x in 0..tile.size.x {
+                    let index = (y * tile.size.x + x) as usize;
+                    let colour = colours[index] / samples;
+                    img.put_pixel(x, y, rgb_to_srgb(colour));
+                    for (value, aov) in aov_values[index].iter().zip(&aovs) {
+                        aov_img.layer_mut(*aov).unwrap()[index] = match aov {
+                            // From the mean squared luminance, with Bessel's correction
+                            Aov::Variance => {
+                                let variance = (value.x / samples - luminance(&colour).powi(2))
+                                    .max(0.0)
+                                    / (samples - 1.0).max(1.0);
+                                vector![variance, variance, variance]
+                            }
+                            _ if aov.is_filtered() => value / samples,
+                            _ => *value,
+                        };
+                    }
+                }
+            }
+        }
+        RenderedTile {
+            samples: colours.len() as u64 * passes as u64,
+            tile,
+            image: img,
+            aovs: aov_img,
+            rays,
+            complete: passes == config.samples_per_pixel,
         }
     }
-    (tile, img)
 }
 
 fn calc_gauss_sigma() -> Float {
@@ -106,46 +597,362 @@ fn calc_gauss_sigma() -> Float {
     sigma
 }
 
-fn render_sample(
+fn render_sample<'a>(
+    uv: Point2<Float>,
+    scene: &'a Scene,
+    pass: &Pass,
+    config: &RenderConfig,
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> PathSample<'a> {
+    match pass
+        .film
+        .camera
+        .generate_ray(uv, point![rng.gen(), rng.gen()], rng.gen())
+    {
+        Some(ray) => config
+            .integrator
+            .sample(&ray, scene, pass, config.max_depth, counters, rng),
+        None => PathSample::default(),
+    }
+}
+
+/// What the camera ray through `uv` hits first, whichever integrator renders the image.
+fn render_first_hit<'a>(
     uv: Point2<Float>,
-    scene: &dyn RayTracable,
-    camera: &Camera,
+    scene: &'a Scene,
+    camera: &dyn Camera,
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> PathSample<'a> {
+    match camera.generate_ray(uv, point![rng.gen(), rng.gen()], rng.gen()) {
+        Some(ray) => trace_path(&ray, scene, MIN_DIST, INFINITY, 1, counters, rng),
+        None => PathSample::default(),
+    }
+}
+
+/// Light arriving along a camera ray, split up by how it got there, together with
+/// what the ray hit first.
+#[derive(Default)]
+pub(crate) struct PathSample<'a> {
+    pub(crate) emission: Vector, // Seen directly
+    pub(crate) diffuse: Vector,  // Scattered first by a diffuse lobe
+    pub(crate) specular: Vector, // Scattered first by a specular lobe
+    pub(crate) first_hit: Option<FirstHit<'a>>,
+}
+
+pub(crate) struct FirstHit<'a> {
+    distance: Float,
+    position: Point,
+    normal: Direction,
+    albedo: Vector,
+    object_index: usize,
+    material: &'a dyn Material,
+}
+
+impl<'a> FirstHit<'a> {
+    pub(crate) fn new(object_index: usize, intersection: &RayIntersection<'a>) -> FirstHit<'a> {
+        FirstHit {
+            distance: intersection.distance,
+            position: intersection.position,
+            normal: intersection.normal,
+            albedo: intersection.material.albedo(intersection),
+            object_index,
+            material: intersection.material,
+        }
+    }
+}
+
+impl PathSample<'_> {
+    pub(crate) fn radiance(&self) -> Vector {
+        self.emission + self.diffuse + self.specular
+    }
+
+    pub(crate) fn add(&mut self, lobe: Option<bool>, radiance: Vector) {
+        match lobe {
+            None => self.emission += radiance,
+            Some(false) => self.diffuse += radiance,
+            Some(true) => self.specular += radiance,
+        }
+    }
+
+    fn aov(&self, aov: Aov) -> Vector {
+        let splat = |value: Float| vector![value, value, value];
+        let Some(hit) = &self.first_hit else {
+            return match aov {
+                Aov::Beauty => self.radiance(),
+                Aov::Emission => self.emission,
+                Aov::Variance => splat(luminance(&self.emission).powi(2)),
+                _ => aov.background(),
+            };
+        };
+        match aov {
+            Aov::Beauty => self.radiance(),
+            Aov::Depth => splat(hit.distance),
+            Aov::Normal => hit.normal.into_inner(),
+            Aov::Albedo => hit.albedo,
+            Aov::Position => hit.position.coords,
+            Aov::ObjectId => splat((hit.object_index + 1) as Float),
+            Aov::MaterialId => splat(material_id(hit.material) as Float),
+            Aov::Diffuse => self.diffuse,
+            Aov::Specular => self.specular,
+            Aov::Emission => self.emission,
+            Aov::Variance => splat(luminance(&self.radiance()).powi(2)),
+        }
+    }
+}
+
+/// Path traces `ray`, sampling the environment directly at every bounce and
+/// combining that with BSDF sampling through multiple importance sampling.
+pub(crate) fn trace_path<'a>(
+    ray: &Ray,
+    scene: &'a Scene,
+    min_dist: Float,
+    max_dist: Float,
     max_depth: u32,
-) -> Vector {
-    let ray = camera.generate_ray(uv, random_circle_disk_point());
-    render_ray(&ray, scene, 0.001, INFINITY, max_depth)
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> PathSample<'a> {
+    trace_path_at(
+        ray, scene, None, min_dist, max_dist, max_depth, counters, rng,
+    )
+}
+
+/// Path traces `ray` as `trace_path` does, but carrying light at a hero wavelength
+/// and two more, which the colours of the scene are uplifted to. Dispersive glass
+/// splits the path up, so only the hero wavelength goes on through it.
+pub(crate) fn trace_spectral_path<'a>(
+    ray: &Ray,
+    scene: &'a Scene,
+    max_depth: u32,
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> PathSample<'a> {
+    let wavelengths = Wavelengths::sample(rng.gen());
+    trace_path_at(
+        ray,
+        scene,
+        Some(wavelengths),
+        MIN_DIST,
+        INFINITY,
+        max_depth,
+        counters,
+        rng,
+    )
+}
+
+/// Colours of the scene as the path carries them, unchanged in RGB or at the sampled
+/// wavelengths.
+fn uplift_reflectance(wavelengths: &Option<Wavelengths>, rgb: &Vector) -> Vector {
+    wavelengths.map_or(*rgb, |wavelengths| wavelengths.reflectance(rgb))
+}
+
+fn uplift_radiance(wavelengths: &Option<Wavelengths>, rgb: &Vector) -> Vector {
+    wavelengths.map_or(*rgb, |wavelengths| wavelengths.radiance(rgb))
 }
 
-fn render_ray(
+#[allow(clippy::too_many_arguments)]
+fn trace_path_at<'a>(
     ray: &Ray,
-    scene: &dyn RayTracable,
+    scene: &'a Scene,
+    mut wavelengths: Option<Wavelengths>,
     min_dist: Float,
     max_dist: Float,
     max_depth: u32,
-) -> Vector {
-    if max_depth == 0 {
-        vector![0.0, 0.0, 0.0]
-    } else if let Some(intersection) = scene.trace_ray(ray, min_dist, max_dist) {
-        if let Some(scatter_ray) = intersection.material.scatter_ray(ray, &intersection) {
-            let scatter_light =
-                render_ray(&scatter_ray.ray, scene, min_dist, max_dist, max_depth - 1);
-            scatter_light.component_mul(&scatter_ray.attenuation)
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> PathSample<'a> {
+    let mut sample = PathSample::default();
+    let mut throughput = vector![1.0, 1.0, 1.0];
+    let mut ray = ray.clone();
+    // Density of the BSDF sample that produced `ray`, or None if it can't be sampled
+    // directly (camera rays and specular bounces)
+    let mut scatter_pdf: Option<Float> = None;
+    // Whether the first bounce was specular, or None before it
+    let mut first_lobe: Option<bool> = None;
+
+    // Surfaces hit so far, and how the path ends if it doesn't break out of the loop
+    let mut length = 0;
+    let mut end = PathEnd::MaxDepth;
+
+    for depth in 0..max_depth {
+        if depth == 0 {
+            counters.primary += 1;
         } else {
-            vector![0.0, 0.0, 0.0]
+            counters.secondary += 1;
+        }
+        let (object_index, mut intersection) = match scene
+            .objects
+            .trace_ray_with_index(&ray, min_dist, max_dist, rng)
+        {
+            Some(hit) => hit,
+            None => {
+                let weight = match scatter_pdf {
+                    Some(pdf) => power_heuristic(pdf, scene.environment.pdf(&ray.direction)),
+                    None => 1.0,
+                };
+                let environment =
+                    uplift_radiance(&wavelengths, &scene.environment.radiance(&ray.direction));
+                sample.add(first_lobe, throughput.component_mul(&environment) * weight);
+                end = PathEnd::Escaped;
+                break;
+            }
+        };
+        length += 1;
+        if depth == 0 {
+            sample.first_hit = Some(FirstHit::new(object_index, &intersection));
+        }
+        // Light entering a subsurface material continues from where it comes out again
+        if let Some(exit) = intersection
+            .material
+            .sample_subsurface_exit(&ray, &intersection, rng)
+        {
+            throughput =
+                throughput.component_mul(&uplift_reflectance(&wavelengths, &exit.throughput));
+            if throughput == vector![0.0, 0.0, 0.0] {
+                end = PathEnd::Absorbed;
+                break;
+            }
+            ray = exit.ray;
+            intersection = exit.intersection;
+        }
+        let material = intersection.material;
+        // Direct light is sampled through the non-specular lobes
+        let direct_lobe = Some(first_lobe.unwrap_or(false));
+
+        // Light that scatters here is only counted if there is a bounce left for it
+        if depth + 1 < max_depth {
+            let light = sample_lights(
+                &ray,
+                &intersection,
+                scene,
+                &wavelengths,
+                min_dist,
+                counters,
+                rng,
+            );
+            sample.add(direct_lobe, throughput.component_mul(&light));
+            if let Some(env_sample) = scene.environment.sample(rng) {
+                let f = uplift_reflectance(
+                    &wavelengths,
+                    &material.eval(&ray, &intersection, &env_sample.direction),
+                );
+                if f != vector![0.0, 0.0, 0.0] {
+                    counters.shadow += 1;
+                    let shadow_ray = Ray {
+                        origin: intersection.position,
+                        direction: env_sample.direction,
+                        time: ray.time,
+                    };
+                    let transmittance =
+                        scene
+                            .objects
+                            .transmittance(&shadow_ray, min_dist, max_dist, rng);
+                    if transmittance > 0.0 {
+                        let weight = power_heuristic(
+                            env_sample.pdf,
+                            material.pdf(&ray, &intersection, &env_sample.direction),
+                        );
+                        let environment = uplift_radiance(&wavelengths, &env_sample.radiance);
+                        sample.add(
+                            direct_lobe,
+                            throughput.component_mul(&f).component_mul(&environment)
+                                * (transmittance * weight / env_sample.pdf),
+                        );
+                    }
+                }
+            }
+        }
+
+        let scattered = match &mut wavelengths {
+            Some(wavelengths) if material.is_dispersive() => {
+                wavelengths.terminate_secondary();
+                material.scatter_wavelength(&ray, &intersection, wavelengths.hero(), rng)
+            }
+            _ => material.scatter_ray(&ray, &intersection, rng),
+        };
+        match scattered {
+            Some(scattered) => {
+                scatter_pdf = if scattered.specular {
+                    None
+                } else {
+                    Some(material.pdf(&ray, &intersection, &scattered.ray.direction))
+                };
+                first_lobe.get_or_insert(scattered.specular);
+                throughput = throughput
+                    .component_mul(&uplift_reflectance(&wavelengths, &scattered.attenuation));
+                ray = scattered.ray;
+            }
+            None => {
+                end = PathEnd::Absorbed;
+                break;
+            }
         }
-    } else {
-        srgb_to_rgb(vector![0.9, 0.9, 0.9])
     }
+    counters.end_path(length, end);
+    if let Some(wavelengths) = wavelengths {
+        sample.emission = wavelengths.to_rgb(&sample.emission);
+        sample.diffuse = wavelengths.to_rgb(&sample.diffuse);
+        sample.specular = wavelengths.to_rgb(&sample.specular);
+    }
+    sample
 }
 
-fn random_circle_disk_point() -> Point2<Float> {
-    let mut rng = thread_rng();
-    loop {
-        let v = vector![rng.gen::<Float>(), rng.gen::<Float>()];
-        let v = (v - vector![0.5, 0.5]) * 2.0;
-        if v.norm_squared() <= 1.0 {
-            return Point2::origin() + v;
+/// Direct light from the scene's delta lights, which BSDF sampling can never hit.
+pub(crate) fn sample_lights(
+    ray: &Ray,
+    intersection: &RayIntersection,
+    scene: &Scene,
+    wavelengths: &Option<Wavelengths>,
+    min_dist: Float,
+    counters: &mut RayCounters,
+    rng: &mut dyn RngCore,
+) -> Vector {
+    let mut radiance = vector![0.0, 0.0, 0.0];
+    for light in &scene.lights {
+        if let Some(sample) = light.sample_incident(&intersection.position) {
+            let f = intersection
+                .material
+                .eval(ray, intersection, &sample.direction);
+            if f == vector![0.0, 0.0, 0.0] {
+                continue;
+            }
+            let f = uplift_reflectance(wavelengths, &f);
+            counters.shadow += 1;
+            let shadow_ray = Ray {
+                origin: intersection.position,
+                direction: sample.direction,
+                time: ray.time,
+            };
+            let max_dist = sample.distance * (1.0 - 1e-9) - min_dist;
+            let transmittance = scene
+                .objects
+                .transmittance(&shadow_ray, min_dist, max_dist, rng);
+            radiance +=
+                f.component_mul(&uplift_radiance(wavelengths, &sample.radiance)) * transmittance;
+        }
+    }
+    radiance
+}
+
+/// Generator for a pass over `tile`. With a seed it only depends on the seed, where
+/// the tile is and the pass, so the render doesn't depend on which thread gets which
+/// tile.
+fn pass_rng(config: &RenderConfig, tile: &RenderTile, pass: u32) -> StdRng {
+    stream_rng(config, &[tile.offset.x, tile.offset.y, pass])
+}
+
+/// Generator for one independent stream of the render, such as a tile's pass,
+/// identified by `stream`. Seeded from the config's seed if there is one.
+pub(crate) fn stream_rng(config: &RenderConfig, stream: &[u32]) -> StdRng {
+    match config.seed {
+        Some(seed) => {
+            let stream = stream.iter().fold(seed, |stream, &value| {
+                (stream ^ value as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            });
+            StdRng::seed_from_u64(stream)
         }
+        None => StdRng::from_rng(thread_rng()).unwrap(),
     }
 }
 
@@ -156,7 +963,7 @@ struct RenderTile {
 }
 
 fn integer_div_round_up(a: u32, b: u32) -> u32 {
-    (a + b - 1) / b
+    a.div_ceil(b)
 }
 
 fn generate_shuffled_tiles(config: &RenderConfig) -> Vec<RenderTile> {
@@ -186,6 +993,627 @@ fn generate_tiles(width: u32, height: u32, tile_size: u32) -> Vec<RenderTile> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::camera::PerspectiveCamera;
+    use crate::environment::{ConstantEnvironment, Environment, EnvironmentMap};
+    use crate::lights::PointLight;
+    use crate::materials::{Lambertian, Metal, MixedMaterial};
+    use crate::scene::{Floor, SceneList, Sphere};
+    use crate::subsurface::Subsurface;
+    use approx::assert_relative_eq;
+    use nalgebra::Unit;
+    use rand::rngs::StdRng;
+    use std::f64::consts::PI;
+    use std::sync::Mutex;
+
+    fn furnace_scene(environment: Box<dyn Environment>) -> Scene {
+        Scene {
+            objects: SceneList {
+                objects: vec![Box::new(Sphere {
+                    center: point![0.0, 0.0, 0.0],
+                    radius: 1.0,
+                    material: Box::new(Lambertian {
+                        color: vector![1.0, 1.0, 1.0],
+                    }),
+                })],
+            },
+            environment,
+            lights: vec![],
+        }
+    }
+
+    fn mean_spectral_radiance(scene: &Scene, samples: usize) -> Vector {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counters = RayCounters::default();
+        let ray = Ray {
+            origin: point![0.0, 0.3, 5.0],
+            direction: Unit::new_normalize(vector![0.0, 0.0, -1.0]),
+            time: 0.0,
+        };
+        let total: Vector = (0..samples)
+            .map(|_| trace_spectral_path(&ray, scene, 10, &mut counters, &mut rng).radiance())
+            .sum();
+        total / samples as Float
+    }
+
+    fn mean_radiance(scene: &Scene, samples: usize) -> Vector {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counters = RayCounters::default();
+        let ray = Ray {
+            origin: point![0.0, 0.3, 5.0],
+            direction: Unit::new_normalize(vector![0.0, 0.0, -1.0]),
+            time: 0.0,
+        };
+        let total: Vector = (0..samples)
+            .map(|_| {
+                trace_path(&ray, scene, MIN_DIST, INFINITY, 10, &mut counters, &mut rng).radiance()
+            })
+            .sum();
+        total / samples as Float
+    }
+
+    #[test]
+    fn point_light_illuminates_floor_with_inverse_square_falloff() {
+        let scene = Scene {
+            objects: SceneList {
+                objects: vec![Box::new(Floor {
+                    y: 0.0,
+                    material: Box::new(Lambertian {
+                        color: vector![0.5, 0.5, 0.5],
+                    }),
+                })],
+            },
+            environment: Box::new(ConstantEnvironment {
+                color: vector![0.0, 0.0, 0.0],
+            }),
+            lights: vec![Box::new(PointLight {
+                position: point![0.0, 2.0, 0.0],
+                intensity: vector![4.0, 4.0, 4.0] * PI,
+            })],
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let ray = Ray {
+            origin: point![0.0, 1.0, 1.0],
+            direction: Unit::new_normalize(vector![0.0, -1.0, -1.0]),
+            time: 0.0,
+        };
+        let radiance = trace_path(
+            &ray,
+            &scene,
+            MIN_DIST,
+            INFINITY,
+            5,
+            &mut RayCounters::default(),
+            &mut rng,
+        )
+        .radiance();
+        assert!(
+            (radiance - vector![0.5, 0.5, 0.5]).amax() < 1e-9,
+            "{}",
+            radiance
+        );
+    }
+
+    #[test]
+    fn white_sphere_vanishes_in_constant_environment() {
+        let scene = furnace_scene(Box::new(ConstantEnvironment {
+            color: vector![1.0, 1.0, 1.0],
+        }));
+        let radiance = mean_radiance(&scene, 2000);
+        assert!(
+            (radiance - vector![1.0, 1.0, 1.0]).amax() < 0.03,
+            "{}",
+            radiance
+        );
+    }
+
+    #[test]
+    fn spectral_path_tracer_agrees_with_rgb() {
+        let scene = furnace_scene(Box::new(ConstantEnvironment {
+            color: vector![1.0, 1.0, 1.0],
+        }));
+        let radiance = mean_spectral_radiance(&scene, 4000);
+        assert!(
+            (radiance - vector![1.0, 1.0, 1.0]).amax() < 0.05,
+            "{}",
+            radiance
+        );
+
+        // Uplifted colours multiply a little differently from RGB ones
+        let mut scene = furnace_scene(Box::new(ConstantEnvironment {
+            color: vector![0.8, 0.7, 0.5],
+        }));
+        scene.objects.objects[0] = Box::new(Sphere {
+            center: point![0.0, 0.0, 0.0],
+            radius: 1.0,
+            material: Box::new(Lambertian {
+                color: vector![0.4, 0.6, 0.5],
+            }),
+        });
+        let rgb = mean_radiance(&scene, 2000);
+        let spectral = mean_spectral_radiance(&scene, 4000);
+        assert!((spectral - rgb).amax() < 0.05, "{} {}", spectral, rgb);
+    }
+
+    #[test]
+    fn white_subsurface_sphere_vanishes_in_constant_environment() {
+        let scene = Scene {
+            objects: SceneList {
+                objects: vec![Box::new(Subsurface::new(
+                    Box::new(Sphere {
+                        center: point![0.0, 0.0, 0.0],
+                        radius: 1.0,
+                        material: Box::new(Lambertian {
+                            color: vector![1.0, 1.0, 1.0],
+                        }),
+                    }),
+                    vector![1.0, 1.0, 1.0],
+                    vector![0.3, 0.1, 0.05],
+                    0.5,
+                ))],
+            },
+            environment: Box::new(ConstantEnvironment {
+                color: vector![1.0, 1.0, 1.0],
+            }),
+            lights: vec![],
+        };
+        let radiance = mean_radiance(&scene, 2000);
+        assert!(
+            (radiance - vector![1.0, 1.0, 1.0]).amax() < 0.03,
+            "{}",
+            radiance
+        );
+    }
+
+    #[test]
+    fn white_sphere_vanishes_in_uniform_environment_map() {
+        let scene = furnace_scene(Box::new(EnvironmentMap::new(
+            8,
+            4,
+            vec![vector![0.5, 0.5, 0.5]; 32],
+        )));
+        let radiance = mean_radiance(&scene, 2000);
+        assert!(
+            (radiance - vector![0.5, 0.5, 0.5]).amax() < 0.015,
+            "{}",
+            radiance
+        );
+    }
+
+    #[test]
+    fn aovs_describe_the_first_hit_and_split_the_light() {
+        let scene = Scene {
+            objects: SceneList {
+                objects: vec![
+                    Box::new(Floor {
+                        y: -10.0,
+                        material: Box::new(Lambertian {
+                            color: vector![0.5, 0.5, 0.5],
+                        }),
+                    }),
+                    Box::new(Sphere {
+                        center: point![0.0, 0.0, -3.0],
+                        radius: 1.0,
+                        material: Box::new(MixedMaterial {
+                            color: vector![0.8, 0.4, 0.2],
+                            shininess: 0.5,
+                        }),
+                    }),
+                ],
+            },
+            environment: Box::new(ConstantEnvironment {
+                color: vector![0.5, 0.5, 0.5],
+            }),
+            lights: vec![],
+        };
+        let config = RenderConfig {
+            width: 31,
+            height: 31,
+            aspect_ratio: 1.0,
+            samples_per_pixel: 8,
+            max_depth: 4,
+            tile_size: 8,
+            denoiser: None,
+            integrator: Integrator::PathTracer,
+            aovs: vec![
+                Aov::Beauty,
+                Aov::Depth,
+                Aov::Normal,
+                Aov::Albedo,
+                Aov::ObjectId,
+                Aov::MaterialId,
+                Aov::Diffuse,
+                Aov::Specular,
+                Aov::Emission,
+            ],
+            seed: Some(0),
+        };
+        let camera =
+            PerspectiveCamera::new(Point::origin(), point![0.0, 0.0, -1.0], 60.0, 0.0, 1.0);
+        let output = render_with_observer(
+            &config,
+            &scene,
+            &camera,
+            &|_, _| {},
+            &CancellationToken::new(),
+        );
+        let aovs = &output.aovs;
+        let centre = |aov| aovs.pixel(aov, 15, 15).unwrap();
+        // Pixel centres are half a pixel above and left of the centre of the image
+        let offset = (30.0 as Float).to_radians().tan() / 31.0;
+        let cos = 1.0 / (1.0 + 2.0 * offset * offset).sqrt();
+        let depth = 3.0 * cos - (9.0 * cos * cos - 8.0).sqrt();
+        assert_relative_eq!(centre(Aov::Depth).x, depth, epsilon = 1e-9);
+        assert!(centre(Aov::Normal).z > 0.95);
+        assert_relative_eq!(centre(Aov::Albedo), vector![0.8, 0.4, 0.2]);
+        assert_eq!(centre(Aov::ObjectId).x, 2.0);
+        let sphere_material = MixedMaterial {
+            color: vector![0.8, 0.4, 0.2],
+            shininess: 0.5,
+        };
+        assert_eq!(
+            centre(Aov::MaterialId).x,
+            material_id(&sphere_material) as Float
+        );
+        assert_eq!(centre(Aov::Emission), vector![0.0, 0.0, 0.0]);
+        assert!(centre(Aov::Diffuse).x > 0.0 && centre(Aov::Specular).x > 0.0);
+        // The corner sees the sky above the floor
+        let corner = |aov| aovs.pixel(aov, 0, 0).unwrap();
+        assert_eq!(corner(Aov::Depth).x, INFINITY);
+        assert_eq!(corner(Aov::ObjectId).x, 0.0);
+        assert_relative_eq!(corner(Aov::Emission), vector![0.5, 0.5, 0.5]);
+        for y in 0..31 {
+            for x in 0..31 {
+                let lobes = [Aov::Diffuse, Aov::Specular, Aov::Emission]
+                    .map(|aov| aovs.pixel(aov, x, y).unwrap());
+                assert_relative_eq!(
+                    aovs.pixel(Aov::Beauty, x, y).unwrap(),
+                    lobes.iter().sum::<Vector>(),
+                    epsilon = 1e-9
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reports_progress_and_stats() {
+        let scene = furnace_scene(Box::new(ConstantEnvironment {
+            color: vector![0.5, 0.5, 0.5],
+        }));
+        let config = RenderConfig {
+            width: 20,
+            height: 10,
+            aspect_ratio: 2.0,
+            samples_per_pixel: 3,
+            max_depth: 4,
+            tile_size: 8,
+            aovs: vec![],
+            denoiser: None,
+            integrator: Integrator::PathTracer,
+            seed: None,
+        };
+        let camera = PerspectiveCamera::new(point![0.0, 0.0, 3.0], Point::origin(), 60.0, 0.0, 2.0);
+        let progress = Mutex::new(Vec::new());
+        let output = render_with_observer(
+            &config,
+            &scene,
+            &camera,
+            &|finished, total| {
+                progress.lock().unwrap().push((finished, total));
+            },
+            &CancellationToken::new(),
+        );
+        let mut progress = progress.into_inner().unwrap();
+        progress.sort();
+        assert_eq!(progress, (1..=6).map(|i| (i, 6)).collect::<Vec<_>>());
+        let stats = output.stats;
+        assert_eq!(stats.tiles, 6);
+        assert_eq!(stats.samples, 20 * 10 * 3);
+        // Paths that hit the convex sphere bounce once and leave, and the sphere is the
+        // only object to test the camera, bounce and shadow rays against
+        let rays = &stats.rays;
+        assert_eq!(rays.primary, stats.samples);
+        assert_eq!(rays.escaped, stats.samples);
+        assert_eq!(rays.paths(), stats.samples);
+        assert_eq!(rays.path_lengths.len(), 2);
+        assert!(rays.path_lengths.iter().all(|count| *count > 0));
+        assert_eq!(rays.secondary, rays.path_lengths[1]);
+        assert!(rays.shadow > 0);
+        assert_eq!(rays.intersection_tests, rays.total());
+        assert!(stats.rays_per_second() > 0.0);
+        assert!(!output.incomplete);
+    }
+
+    #[test]
+    fn counts_paths_cut_off_by_max_depth() {
+        // Inside a mirror sphere every path bounces until it runs out of depth
+        let scene = Scene {
+            objects: SceneList {
+                objects: vec![Box::new(Sphere {
+                    center: point![0.0, 0.0, 0.0],
+                    radius: 1.0,
+                    material: Box::new(Metal {
+                        color: vector![1.0, 1.0, 1.0],
+                    }),
+                })],
+            },
+            environment: Box::new(ConstantEnvironment {
+                color: vector![0.5, 0.5, 0.5],
+            }),
+            lights: vec![],
+        };
+        let mut counters = RayCounters::default();
+        let mut rng = StdRng::seed_from_u64(0);
+        let ray = Ray {
+            origin: Point::origin(),
+            direction: Unit::new_normalize(vector![0.0, 0.0, -1.0]),
+            time: 0.0,
+        };
+        for _ in 0..10 {
+            trace_path(&ray, &scene, MIN_DIST, INFINITY, 3, &mut counters, &mut rng);
+        }
+        assert_eq!(counters.max_depth, 10);
+        assert_eq!(counters.escaped + counters.absorbed, 0);
+        assert_eq!(counters.path_lengths, [0, 0, 0, 10]);
+        assert_eq!((counters.primary, counters.secondary), (10, 20));
+
+        let mut total = RayCounters::default();
+        total.add(&counters);
+        total.add(&counters);
+        assert_eq!(total.path_lengths, [0, 0, 0, 20]);
+        assert_eq!(total.total(), 2 * counters.total());
+    }
+
+    #[test]
+    fn cancelled_render_returns_partial_image() {
+        let scene = furnace_scene(Box::new(ConstantEnvironment {
+            color: vector![0.5, 0.5, 0.5],
+        }));
+        let config = RenderConfig {
+            width: 16,
+            height: 16,
+            aspect_ratio: 1.0,
+            samples_per_pixel: 1000,
+            max_depth: 4,
+            tile_size: 4,
+            aovs: vec![Aov::Beauty],
+            denoiser: None,
+            integrator: Integrator::PathTracer,
+            seed: None,
+        };
+        let camera = PerspectiveCamera::new(point![0.0, 0.0, 3.0], Point::origin(), 60.0, 0.0, 1.0);
+        // Cancel once the first tile is done, which the others notice between passes
+        let cancel = CancellationToken::new();
+        let output =
+            render_with_observer(&config, &scene, &camera, &|_, _| cancel.cancel(), &cancel);
+        assert!(output.incomplete);
+        assert!(output.stats.samples >= 16 * 1000);
+        assert!(output.stats.samples < 16 * 16 * 1000);
+        // The finished tile is fully rendered
+        let rendered = output
+            .aovs
+            .layer(Aov::Beauty)
+            .unwrap()
+            .iter()
+            .filter(|colour| colour.x > 0.0)
+            .count();
+        assert!(rendered >= 16);
+
+        // A token that is already cancelled renders nothing
+        let output = render_with_observer(&config, &scene, &camera, &|_, _| {}, &cancel);
+        assert!(output.incomplete);
+        assert_eq!(output.stats.samples, 0);
+        assert!(output.image.pixels().all(|pixel| pixel.0 == [0, 0, 0]));
+    }
+
+    #[test]
+    fn debug_views_colour_what_the_camera_sees() {
+        let scene = furnace_scene(Box::new(ConstantEnvironment {
+            color: vector![0.5, 0.5, 0.5],
+        }));
+        let camera = PerspectiveCamera::new(point![0.0, 0.0, 4.0], Point::origin(), 40.0, 0.0, 1.0);
+        let view = |integrator| {
+            let config = RenderConfig {
+                width: 15,
+                height: 15,
+                aspect_ratio: 1.0,
+                samples_per_pixel: 4,
+                max_depth: 4,
+                tile_size: 8,
+                aovs: vec![Aov::Beauty],
+                denoiser: None,
+                integrator,
+                seed: None,
+            };
+            render_with_observer(
+                &config,
+                &scene,
+                &camera,
+                &|_, _| {},
+                &CancellationToken::new(),
+            )
+        };
+
+        // The sphere's centre faces the camera along +z, give or take the pixel filter
+        let normals = view(Integrator::Normals);
+        let centre = normals.image.get_pixel(7, 7).0;
+        assert!(
+            centre[0].abs_diff(128) <= 25 && centre[1].abs_diff(128) <= 25,
+            "{:?}",
+            centre
+        );
+        assert!(centre[2] >= 245);
+        assert_eq!(normals.image.get_pixel(0, 0).0, [0, 0, 0]);
+
+        // Misses test one object, while hits also trace bounce and shadow rays
+        let heatmap = view(Integrator::IntersectionHeatmap);
+        assert_eq!(heatmap.aovs.pixel(Aov::Beauty, 0, 0).unwrap().x, 1.0);
+        assert!(heatmap.aovs.pixel(Aov::Beauty, 7, 7).unwrap().x >= 2.0);
+        assert!(heatmap.image.get_pixel(7, 7)[0] > heatmap.image.get_pixel(0, 0)[0]);
+
+        // Depth is shown relative to the furthest hit, which is at most sqrt(15) away.
+        // Pixel centres are half a pixel above and left of the centre of the image
+        let depth = view(Integrator::Depth);
+        let offset = (20.0 as Float).to_radians().tan() / 15.0;
+        let cos = 1.0 / (1.0 + 2.0 * offset * offset).sqrt();
+        assert_relative_eq!(
+            depth.aovs.pixel(Aov::Beauty, 7, 7).unwrap().x,
+            4.0 * cos - (16.0 * cos * cos - 15.0).sqrt(),
+            epsilon = 1e-9
+        );
+        assert!(depth.image.get_pixel(7, 7)[0] > 190);
+        assert_eq!(depth.image.get_pixel(0, 0).0, [0, 0, 0]);
+    }
+
+    #[test]
+    fn previews_show_occlusion_and_direct_light() {
+        let camera = PerspectiveCamera::new(point![0.0, 0.0, 4.0], Point::origin(), 40.0, 0.0, 1.0);
+        let view = |scene: &Scene, integrator| {
+            let config = RenderConfig {
+                width: 15,
+                height: 15,
+                aspect_ratio: 1.0,
+                samples_per_pixel: 64,
+                max_depth: 4,
+                tile_size: 8,
+                aovs: vec![Aov::Beauty],
+                denoiser: None,
+                integrator,
+                seed: Some(0),
+            };
+            render_with_observer(
+                &config,
+                scene,
+                &camera,
+                &|_, _| {},
+                &CancellationToken::new(),
+            )
+            .aovs
+        };
+        let black = || -> Box<dyn Environment> {
+            Box::new(ConstantEnvironment {
+                color: vector![0.0, 0.0, 0.0],
+            })
+        };
+
+        // The floor in front of the sphere is only occluded by it within a large radius.
+        // A small radius only finds occluders where the sphere touches the floor, far
+        // from the corner of the image
+        let mut scene = furnace_scene(black());
+        scene.objects.objects.push(Box::new(Floor {
+            y: -1.0,
+            material: Box::new(Lambertian {
+                color: vector![0.5, 0.5, 0.5],
+            }),
+        }));
+        let occlusion = |radius| view(&scene, Integrator::AmbientOcclusion { radius });
+        let (open, occluded) = (occlusion(0.01), occlusion(10.0));
+        assert_relative_eq!(open.pixel(Aov::Beauty, 7, 7).unwrap().x, 1.0);
+        assert_relative_eq!(open.pixel(Aov::Beauty, 0, 14).unwrap().x, 1.0);
+        assert!(occluded.pixel(Aov::Beauty, 7, 14).unwrap().x < 0.9);
+
+        // A lone convex sphere gets no indirect light, so only direct light is left
+        let lit = Scene {
+            lights: vec![Box::new(PointLight {
+                position: point![2.0, 2.0, 3.0],
+                intensity: vector![10.0, 10.0, 10.0],
+            })],
+            ..furnace_scene(black())
+        };
+        let mean = |aovs: &AovImage| {
+            let beauty = aovs.layer(Aov::Beauty).unwrap();
+            beauty.iter().sum::<Vector>() / beauty.len() as Float
+        };
+        assert_relative_eq!(
+            mean(&view(&lit, Integrator::Whitted)),
+            mean(&view(&lit, Integrator::PathTracer)),
+            max_relative = 0.05
+        );
+
+        // Mirrors show the environment behind the camera
+        let mirror = Scene {
+            objects: SceneList {
+                objects: vec![Box::new(Sphere {
+                    center: Point::origin(),
+                    radius: 1.0,
+                    material: Box::new(Metal {
+                        color: vector![0.9, 0.9, 0.9],
+                    }),
+                })],
+            },
+            ..furnace_scene(Box::new(ConstantEnvironment {
+                color: vector![0.5, 0.5, 0.5],
+            }))
+        };
+        let reflected = view(&mirror, Integrator::Whitted);
+        assert_relative_eq!(
+            reflected.pixel(Aov::Beauty, 7, 7).unwrap(),
+            vector![0.45, 0.45, 0.45],
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn denoised_low_sample_render_is_closer_to_reference() {
+        let scene = Scene {
+            objects: SceneList {
+                objects: vec![
+                    Box::new(Floor {
+                        y: -1.0,
+                        material: Box::new(Lambertian {
+                            color: vector![0.6, 0.6, 0.6],
+                        }),
+                    }),
+                    Box::new(Sphere {
+                        center: point![0.0, 0.0, -4.0],
+                        radius: 1.0,
+                        material: Box::new(Lambertian {
+                            color: vector![0.7, 0.3, 0.2],
+                        }),
+                    }),
+                ],
+            },
+            environment: Box::new(EnvironmentMap::from_fn(16, 8, |direction| {
+                vector![1.0, 1.0, 1.0] * direction.y.max(0.0) * 2.0
+            })),
+            lights: vec![],
+        };
+        let config = |samples_per_pixel, denoiser| RenderConfig {
+            width: 48,
+            height: 32,
+            aspect_ratio: 1.5,
+            samples_per_pixel,
+            max_depth: 4,
+            tile_size: 16,
+            aovs: vec![],
+            denoiser,
+            integrator: Integrator::PathTracer,
+            seed: None,
+        };
+        let camera = PerspectiveCamera::new(
+            point![0.0, 0.5, 0.0],
+            point![0.0, 0.0, -4.0],
+            50.0,
+            0.0,
+            1.5,
+        );
+        let reference = render(&config(128, None), &scene, &camera);
+        let error = |image: &RgbImage| {
+            image
+                .as_raw()
+                .iter()
+                .zip(reference.as_raw())
+                .map(|(a, b)| (*a as Float - *b as Float).powi(2))
+                .sum::<Float>()
+                / image.as_raw().len() as Float
+        };
+        let noisy = error(&render(&config(8, None), &scene, &camera));
+        let denoised = error(&render(
+            &config(8, Some(Denoiser::default())),
+            &scene,
+            &camera,
+        ));
+        assert!(denoised < 0.5 * noisy, "{} vs {}", denoised, noisy);
+    }
 
     #[test]
     fn integer_div_round_up_returns_correct_values() {
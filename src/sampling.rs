@@ -0,0 +1,225 @@
+use crate::common::Direction;
+use crate::common::Float;
+use crate::common::Vector;
+use nalgebra::{point, vector, Point2, Unit};
+use rand::prelude::*;
+use std::f64::consts::PI;
+
+pub const UNIFORM_SPHERE_PDF: Float = 1.0 / (4.0 * PI);
+
+pub fn uniform_sphere_direction(rng: &mut dyn RngCore) -> Direction {
+    let z: Float = 1.0 - 2.0 * rng.gen::<Float>();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.gen::<Float>();
+    Unit::new_normalize(vector![r * phi.cos(), r * phi.sin(), z])
+}
+
+/// Uniformly distributed direction within `cos_max` of `axis`.
+pub fn uniform_cone_direction(
+    axis: &Direction,
+    cos_max: Float,
+    rng: &mut dyn RngCore,
+) -> Direction {
+    let cos_theta = 1.0 - rng.gen::<Float>() * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.gen::<Float>();
+    let (tangent, bitangent) = orthonormal_basis(axis);
+    Unit::new_normalize(
+        tangent * (sin_theta * phi.cos())
+            + bitangent * (sin_theta * phi.sin())
+            + axis.into_inner() * cos_theta,
+    )
+}
+
+pub fn uniform_cone_pdf(cos_max: Float) -> Float {
+    1.0 / (2.0 * PI * (1.0 - cos_max))
+}
+
+/// Two unit vectors that together with `normal` form an orthonormal basis.
+pub fn orthonormal_basis(normal: &Direction) -> (Vector, Vector) {
+    let helper = if normal.x.abs() > 0.9 {
+        vector![0.0, 1.0, 0.0]
+    } else {
+        vector![1.0, 0.0, 0.0]
+    };
+    let tangent = normal.cross(&helper).normalize();
+    let bitangent = normal.cross(&tangent);
+    (tangent, bitangent)
+}
+
+/// Multiple importance sampling weight for a sample drawn with density `pdf` when
+/// another strategy could have produced it with density `other_pdf`.
+pub fn power_heuristic(pdf: Float, other_pdf: Float) -> Float {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b == 0.0 {
+        0.0
+    } else {
+        a / (a + b)
+    }
+}
+
+/// Piecewise-constant distribution over [0, 1) proportional to `function`.
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    function: Vec<Float>,
+    cdf: Vec<Float>,
+    integral: Float,
+}
+
+impl Distribution1D {
+    pub fn new(function: Vec<Float>) -> Distribution1D {
+        let n = function.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + function[i].abs() / n as Float;
+        }
+        let integral = cdf[n];
+        if integral == 0.0 {
+            // Fall back to a uniform distribution
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as Float / n as Float;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        }
+        Distribution1D {
+            function,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.function.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.function.is_empty()
+    }
+
+    pub fn integral(&self) -> Float {
+        self.integral
+    }
+
+    /// Maps `u` in [0, 1) to a position in [0, 1), returning it with its density and
+    /// the index of the segment it falls in.
+    pub fn sample_continuous(&self, u: Float) -> (Float, Float, usize) {
+        let index = self.find_segment(u);
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let offset = if width > 0.0 {
+            (u - self.cdf[index]) / width
+        } else {
+            0.0
+        };
+        let x = ((index as Float + offset) / self.len() as Float).min(1.0 - Float::EPSILON);
+        (x, self.segment_pdf(index), index)
+    }
+
+    /// Picks a segment index with probability proportional to its value.
+    pub fn sample_discrete(&self, u: Float) -> (usize, Float) {
+        let index = self.find_segment(u);
+        (index, self.cdf[index + 1] - self.cdf[index])
+    }
+
+    /// Density at position `x` in [0, 1).
+    pub fn pdf(&self, x: Float) -> Float {
+        let index = ((x * self.len() as Float) as usize).min(self.len() - 1);
+        self.segment_pdf(index)
+    }
+
+    /// Probability of sample_discrete() returning `index`.
+    pub fn discrete_pdf(&self, index: usize) -> Float {
+        self.cdf[index + 1] - self.cdf[index]
+    }
+
+    fn segment_pdf(&self, index: usize) -> Float {
+        if self.integral == 0.0 {
+            1.0
+        } else {
+            self.function[index].abs() / self.integral
+        }
+    }
+
+    fn find_segment(&self, u: Float) -> usize {
+        // Last index whose cdf value is <= u, skipping zero-width segments
+        let index = self.cdf.partition_point(|&c| c <= u);
+        index.clamp(1, self.len()) - 1
+    }
+}
+
+/// Piecewise-constant distribution over [0, 1)² from a row-major grid of values.
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(values: &[Float], width: usize, height: usize) -> Distribution2D {
+        let conditional: Vec<Distribution1D> = values
+            .chunks(width)
+            .take(height)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral()).collect());
+        Distribution2D {
+            conditional,
+            marginal,
+        }
+    }
+
+    pub fn sample(&self, u: Point2<Float>) -> (Point2<Float>, Float) {
+        let (y, pdf_y, row) = self.marginal.sample_continuous(u.y);
+        let (x, pdf_x, _) = self.conditional[row].sample_continuous(u.x);
+        (point![x, y], pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, p: Point2<Float>) -> Float {
+        let row = ((p.y * self.marginal.len() as Float) as usize).min(self.marginal.len() - 1);
+        self.marginal.pdf(p.y) * self.conditional[row].pdf(p.x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn distribution_1d_samples_proportionally() {
+        let distribution = Distribution1D::new(vec![1.0, 0.0, 3.0]);
+        assert_relative_eq!(distribution.integral(), 4.0 / 3.0);
+        let (x, pdf, index) = distribution.sample_continuous(0.1);
+        assert_eq!(index, 0);
+        assert_relative_eq!(pdf, 0.75);
+        assert_relative_eq!(x, 0.4 / 3.0, epsilon = 1e-12);
+        let (x, pdf, index) = distribution.sample_continuous(0.625);
+        assert_eq!(index, 2);
+        assert_relative_eq!(pdf, 2.25);
+        assert_relative_eq!(x, 2.5 / 3.0, epsilon = 1e-12);
+        assert_eq!(distribution.sample_discrete(0.3), (2, 0.75));
+        assert_eq!(distribution.pdf(0.5), 0.0);
+    }
+
+    #[test]
+    fn distribution_1d_of_zeros_is_uniform() {
+        let distribution = Distribution1D::new(vec![0.0, 0.0]);
+        assert_eq!(distribution.sample_continuous(0.75), (0.75, 1.0, 1));
+    }
+
+    #[test]
+    fn distribution_2d_pdf_matches_sampled_pdf() {
+        let values = [1.0, 2.0, 0.0, 4.0, 5.0, 6.0];
+        let distribution = Distribution2D::new(&values, 3, 2);
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let (p, pdf) = distribution.sample(point![rng.gen(), rng.gen()]);
+            assert_relative_eq!(distribution.pdf(p), pdf, epsilon = 1e-12);
+            let value = values[(p.y * 2.0) as usize * 3 + (p.x * 3.0) as usize];
+            assert_relative_eq!(pdf, value / 3.0, epsilon = 1e-12);
+        }
+    }
+}
@@ -1,25 +1,63 @@
+use crate::aabb::Aabb;
 use crate::common::Float;
 use crate::common::Material;
 use crate::common::Point;
 use crate::common::Ray;
 use crate::common::RayIntersection;
 use crate::common::RayTracable;
+use crate::environment::Environment;
+use crate::keyframes::Keyframes;
+use crate::lights::Light;
+use nalgebra::point;
 use nalgebra::vector;
+use nalgebra::Isometry3;
 use nalgebra::Unit;
+use rand::RngCore;
+use std::cell::Cell;
+use std::f64::consts::PI;
+
+thread_local! {
+    // Objects tested against rays by scene lists on this thread, for render statistics
+    static INTERSECTION_TESTS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Number of objects that scene lists have tested against rays on the current thread
+/// so far. The difference between two calls counts the tests in between.
+pub fn intersection_tests() -> u64 {
+    INTERSECTION_TESTS.with(Cell::get)
+}
+
+fn count_intersection_tests(count: usize) {
+    INTERSECTION_TESTS.with(|tests| tests.set(tests.get() + count as u64));
+}
+
+pub struct Scene {
+    pub objects: SceneList,
+    pub environment: Box<dyn Environment>,
+    pub lights: Vec<Box<dyn Light>>,
+}
 
 pub struct SceneList {
     pub objects: Vec<Box<dyn RayTracable>>,
 }
 
-impl RayTracable for SceneList {
-    fn trace_ray(&self, ray: &Ray, min_dist: f64, max_dist: f64) -> Option<RayIntersection> {
+impl SceneList {
+    /// Closest intersection together with the index of the object that was hit.
+    pub fn trace_ray_with_index(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Option<(usize, RayIntersection<'_>)> {
         let mut closest_dist = max_dist;
-        let mut closest_intersection: Option<RayIntersection> = None;
+        let mut closest_intersection: Option<(usize, RayIntersection)> = None;
+        count_intersection_tests(self.objects.len());
 
-        for object in &self.objects {
-            if let Some(intersection) = object.trace_ray(ray, min_dist, closest_dist) {
+        for (index, object) in self.objects.iter().enumerate() {
+            if let Some(intersection) = object.trace_ray(ray, min_dist, closest_dist, rng) {
                 closest_dist = intersection.distance;
-                closest_intersection = Some(intersection);
+                closest_intersection = Some((index, intersection));
             }
         }
 
@@ -27,6 +65,43 @@ impl RayTracable for SceneList {
     }
 }
 
+impl RayTracable for SceneList {
+    fn trace_ray(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Option<RayIntersection<'_>> {
+        self.trace_ray_with_index(ray, min_dist, max_dist, rng)
+            .map(|(_, intersection)| intersection)
+    }
+
+    fn transmittance(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Float {
+        let mut transmittance = 1.0;
+        for object in &self.objects {
+            count_intersection_tests(1);
+            transmittance *= object.transmittance(ray, min_dist, max_dist, rng);
+            if transmittance == 0.0 {
+                break;
+            }
+        }
+        transmittance
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let mut boxes = self.objects.iter().map(|object| object.bounding_box());
+        let first = boxes.next()??;
+        boxes.try_fold(first, |union, object_box| Some(union.union(&object_box?)))
+    }
+}
+
 pub struct Sphere {
     pub center: Point,
     pub radius: Float,
@@ -34,7 +109,13 @@ pub struct Sphere {
 }
 
 impl RayTracable for Sphere {
-    fn trace_ray(&self, ray: &Ray, min_dist: f64, max_dist: f64) -> Option<RayIntersection> {
+    fn trace_ray(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        _rng: &mut dyn RngCore,
+    ) -> Option<RayIntersection<'_>> {
         let oc = ray.origin - self.center;
         let a = ray.direction.dot(&oc);
         let delta = a.powi(2) - (oc.norm_squared() - self.radius.powi(2));
@@ -58,13 +139,24 @@ impl RayTracable for Sphere {
         }
 
         let position = ray.at(distance);
+        let normal = (position - self.center) / self.radius;
         Some(RayIntersection {
             distance,
             position,
-            normal: Unit::new_unchecked((position - self.center) / self.radius),
+            normal: Unit::new_unchecked(normal),
+            // Longitude and latitude, with v running from the top down
+            uv: point![
+                0.5 + normal.x.atan2(normal.z) / (2.0 * PI),
+                normal.y.clamp(-1.0, 1.0).acos() / PI
+            ],
             material: self.material.as_ref(),
         })
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = vector![self.radius, self.radius, self.radius];
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
 }
 
 pub struct Floor {
@@ -73,7 +165,13 @@ pub struct Floor {
 }
 
 impl RayTracable for Floor {
-    fn trace_ray(&self, ray: &Ray, min_dist: f64, max_dist: f64) -> Option<RayIntersection> {
+    fn trace_ray(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        _rng: &mut dyn RngCore,
+    ) -> Option<RayIntersection<'_>> {
         if ray.direction.y.abs() < Float::EPSILON {
             return None;
         }
@@ -81,11 +179,212 @@ impl RayTracable for Floor {
         if distance < min_dist || distance > max_dist {
             return None;
         }
+        let position = ray.at(distance);
         Some(RayIntersection {
             distance,
-            position: ray.at(distance),
+            position,
             normal: Unit::new_unchecked(vector![0.0, 1.0, 0.0]),
+            uv: point![position.x, position.z], // In scene units
             material: self.material.as_ref(),
         })
     }
 }
+
+/// Object placed in the scene by a rigid transform from its own coordinates to the
+/// scene's, which may be keyframed over time so that the object moves during the
+/// shutter interval.
+pub struct Instance {
+    object: Box<dyn RayTracable>,
+    transform: Keyframes<Isometry3<Float>>,
+    bounds: Option<Aabb>, // Encloses the whole motion
+}
+
+impl Instance {
+    pub fn new(object: Box<dyn RayTracable>, transform: Keyframes<Isometry3<Float>>) -> Instance {
+        let bounds = object
+            .bounding_box()
+            .map(|object_box| motion_bounds(&object_box, &transform));
+        Instance {
+            object,
+            transform,
+            bounds,
+        }
+    }
+
+    fn object_ray(&self, ray: &Ray) -> (Isometry3<Float>, Ray) {
+        let transform = self.transform.at(ray.time);
+        let object_ray = Ray {
+            origin: transform.inverse_transform_point(&ray.origin),
+            direction: transform.inverse_transform_unit_vector(&ray.direction),
+            time: ray.time,
+        };
+        (transform, object_ray)
+    }
+}
+
+impl RayTracable for Instance {
+    fn trace_ray(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Option<RayIntersection<'_>> {
+        if let Some(bounds) = &self.bounds {
+            bounds.intersect(ray, min_dist, max_dist)?;
+        }
+        // Rigid transforms keep distances along the ray unchanged
+        let (transform, object_ray) = self.object_ray(ray);
+        let intersection = self
+            .object
+            .trace_ray(&object_ray, min_dist, max_dist, rng)?;
+        Some(RayIntersection {
+            position: transform * intersection.position,
+            normal: transform * intersection.normal,
+            ..intersection
+        })
+    }
+
+    fn transmittance(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Float {
+        let (_, object_ray) = self.object_ray(ray);
+        self.object
+            .transmittance(&object_ray, min_dist, max_dist, rng)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bounds
+    }
+}
+
+/// Box enclosing `object_box` as it moves with the transform. Between keyframes with
+/// the same rotation the box only translates, so the boxes at both ends enclose it.
+/// Otherwise it is bounded by the sphere around the box, whose distance from the
+/// translation stays fixed while rotating.
+fn motion_bounds(object_box: &Aabb, transform: &Keyframes<Isometry3<Float>>) -> Aabb {
+    let keys = transform.keys();
+    let transformed_box = |isometry: &Isometry3<Float>| {
+        let corners = (0..8).map(|i| {
+            let pick = |axis: usize| {
+                if i >> axis & 1 == 1 {
+                    object_box.max[axis]
+                } else {
+                    object_box.min[axis]
+                }
+            };
+            isometry * point![pick(0), pick(1), pick(2)]
+        });
+        corners
+            .map(|corner| Aabb::new(corner, corner))
+            .reduce(|a, b| a.union(&b))
+            .unwrap()
+    };
+    let rotating_box = |isometry: &Isometry3<Float>| {
+        let centre = nalgebra::center(&object_box.min, &object_box.max);
+        let radius = centre.coords.norm() + object_box.size().norm() / 2.0;
+        let translation = Point::from(isometry.translation.vector);
+        let extent = vector![radius, radius, radius];
+        Aabb::new(translation - extent, translation + extent)
+    };
+
+    let mut bounds = transformed_box(&keys[0].1);
+    for pair in keys.windows(2) {
+        let (start, end) = (&pair[0].1, &pair[1].1);
+        bounds = if start.rotation.angle_to(&end.rotation) < 1e-12 {
+            bounds.union(&transformed_box(end))
+        } else {
+            bounds.union(&rotating_box(start)).union(&rotating_box(end))
+        };
+    }
+    bounds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Vector;
+    use crate::common::INFINITY;
+    use crate::materials::Lambertian;
+    use nalgebra::{Translation3, UnitQuaternion};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn sphere(center: Point) -> Box<Sphere> {
+        Box::new(Sphere {
+            center,
+            radius: 0.5,
+            material: Box::new(Lambertian {
+                color: vector![1.0, 1.0, 1.0],
+            }),
+        })
+    }
+
+    fn ray_at_time(time: Float) -> Ray {
+        Ray {
+            origin: point![0.0, 0.0, 5.0],
+            direction: Unit::new_normalize(vector![0.0, 0.0, -1.0]),
+            time,
+        }
+    }
+
+    #[test]
+    fn instance_moves_object_over_time() {
+        let instance = Instance::new(
+            sphere(point![0.0, 0.0, 0.0]),
+            Keyframes::new(vec![
+                (0.0, Isometry3::identity()),
+                (1.0, Isometry3::translation(2.0, 0.0, 0.0)),
+            ]),
+        );
+        let mut rng = StdRng::seed_from_u64(0);
+        let hit = instance
+            .trace_ray(&ray_at_time(0.0), 0.0, INFINITY, &mut rng)
+            .unwrap();
+        assert!((hit.distance - 4.5).abs() < 1e-9);
+        assert!((hit.normal.z - 1.0).abs() < 1e-9);
+        // Facing +z is the middle of the sphere's surface coordinates
+        assert!((hit.uv - point![0.5, 0.5]).norm() < 1e-9);
+        assert!(instance
+            .trace_ray(&ray_at_time(1.0), 0.0, INFINITY, &mut rng)
+            .is_none());
+        // Moving right by two units a second, its edge leaves x = 0 after a quarter second
+        let hits = (0..1000)
+            .filter(|i| {
+                let ray = ray_at_time(*i as Float / 1000.0);
+                instance.trace_ray(&ray, 0.0, INFINITY, &mut rng).is_some()
+            })
+            .count();
+        assert!((hits as i32 - 250).abs() <= 1);
+    }
+
+    #[test]
+    fn bounding_box_encloses_the_whole_motion() {
+        let transform = Keyframes::new(vec![
+            (0.0, Isometry3::identity()),
+            (1.0, Isometry3::translation(2.0, 0.0, 0.0)),
+            (
+                2.0,
+                Isometry3::from_parts(
+                    Translation3::new(2.0, 1.0, 0.0),
+                    UnitQuaternion::from_axis_angle(&Vector::z_axis(), 3.0),
+                ),
+            ),
+        ]);
+        let instance = Instance::new(sphere(point![1.0, 0.0, 0.0]), transform.clone());
+        let bounds = instance.bounding_box().unwrap();
+        assert!(bounds.min.x > 0.0);
+        for i in 0..=200 {
+            let transform = transform.at(i as Float / 100.0);
+            let center = transform * point![1.0, 0.0, 0.0];
+            for offset in [-0.5, 0.5] {
+                let extent = vector![offset, offset, offset];
+                assert!(bounds.contains(&(center + extent)));
+            }
+        }
+    }
+}
@@ -0,0 +1,898 @@
+use crate::aov::Aov;
+use crate::aperture::Aperture;
+use crate::aperture::ApertureMask;
+use crate::camera::Camera;
+use crate::camera::CameraBuilder;
+use crate::camera::Eye;
+use crate::camera::FisheyeMapping;
+use crate::camera::PerspectiveCamera;
+use crate::camera::StereoCamera;
+use crate::camera::StereoLayout;
+use crate::common::Float;
+use crate::common::Material;
+use crate::common::Point;
+use crate::common::RayTracable;
+use crate::common::Vector;
+use crate::denoise::Denoiser;
+use crate::environment::ConstantEnvironment;
+use crate::environment::Environment;
+use crate::environment::EnvironmentMap;
+use crate::integrator::Integrator;
+use crate::keyframes::Curve;
+use crate::keyframes::Interpolate;
+use crate::keyframes::Keyframes;
+use crate::lights::DirectionalLight;
+use crate::lights::Light;
+use crate::lights::PointLight;
+use crate::lights::SpotLight;
+use crate::materials::Dielectric;
+use crate::materials::FloorMaterial;
+use crate::materials::Lambertian;
+use crate::materials::Metal;
+use crate::materials::MixedMaterial;
+use crate::render::RenderConfig;
+use crate::scene::Floor;
+use crate::scene::Instance;
+use crate::scene::Scene;
+use crate::scene::SceneList;
+use crate::scene::Sphere;
+use crate::sky::PhysicalSky;
+use crate::srgb::srgb_to_rgb;
+use nalgebra::{Isometry3, Translation3, Unit, UnitQuaternion};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Scene and camera animation read from a JSON file. Any camera value and object
+/// transform may be either a constant or keyframed, e.g.
+/// `{"keys": [[0.0, [0, 1, 0]], [2.5, [3, 1, 0]]], "curve": "ease_in_out"}` with times in
+/// seconds. Colours are given in sRGB. Point, spot and directional lights stay where
+/// they are for the whole animation.
+pub struct SceneFile {
+    pub config: RenderConfig,
+    pub frames_per_second: Float,
+    pub shutter: Float, // Fraction of each frame that the shutter is open for
+    pub separate_aov_files: bool, // One EXR file per AOV instead of one with all of them
+    camera: CameraDescription,
+    aperture: Aperture, // Loaded up front as it may come from an image
+    environment: EnvironmentDescription,
+    objects: Vec<ObjectDescription>,
+    lights: Vec<LightDescription>,
+    directory: PathBuf, // Relative paths in the file are resolved from here
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileDescription {
+    render: RenderDescription,
+    #[serde(default = "default_frames_per_second")]
+    frames_per_second: Float,
+    #[serde(default = "default_shutter")]
+    shutter: Float,
+    camera: CameraDescription,
+    environment: EnvironmentDescription,
+    objects: Vec<ObjectDescription>,
+    #[serde(default)]
+    lights: Vec<LightDescription>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RenderDescription {
+    width: u32,
+    height: u32,
+    samples_per_pixel: u32,
+    max_depth: u32,
+    #[serde(default)]
+    aovs: Vec<Aov>,
+    #[serde(default)]
+    separate_aov_files: bool,
+    denoiser: Option<Denoiser>,
+    #[serde(default)]
+    integrator: Integrator,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDescription {
+    origin: Animated<[Float; 3]>,
+    look_at: Animated<[Float; 3]>,
+    #[serde(default = "default_fov_degrees")]
+    fov_degrees: Animated<Float>, // Vertical field of view of perspective cameras
+    #[serde(default = "default_f_number")]
+    f_number: Animated<Float>,
+    #[serde(default)]
+    aperture: ApertureDescription,
+    #[serde(default)]
+    cats_eye: Float,
+    #[serde(default)]
+    projection: ProjectionDescription,
+    stereo: Option<StereoDescription>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ApertureDescription {
+    #[default]
+    Circle,
+    Polygon {
+        blades: u32,
+        #[serde(default)]
+        rotation_degrees: Float,
+    },
+    Mask {
+        path: PathBuf,
+    },
+}
+
+/// Renders both eyes into one image. Equirectangular projections become
+/// omni-directional stereo panoramas, which have no convergence distance.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StereoDescription {
+    layout: StereoLayoutDescription,
+    #[serde(default = "default_interpupillary_distance")]
+    interpupillary_distance: Float,
+    convergence_distance: Option<Float>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum StereoLayoutDescription {
+    SideBySide,
+    TopBottom,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ProjectionDescription {
+    #[default]
+    Perspective,
+    Orthographic {
+        height: Float,
+    },
+    Fisheye {
+        mapping: FisheyeMappingDescription,
+        fov_degrees: Float,
+    },
+    Equirectangular,
+    Cylindrical {
+        fov_degrees: Float,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum FisheyeMappingDescription {
+    Equidistant,
+    Equisolid,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum EnvironmentDescription {
+    Constant {
+        color: [Float; 3],
+    },
+    Sky {
+        sun_elevation_degrees: Float,
+        sun_azimuth_degrees: Float,
+        turbidity: Float,
+        intensity: Float,
+    },
+    Map {
+        path: PathBuf,
+        #[serde(default)]
+        rotation_degrees: Float,
+        #[serde(default = "default_intensity")]
+        intensity: Float,
+    },
+}
+
+/// The light's colour is scaled by `intensity`, which is the radiant intensity of
+/// point and spot lights and the irradiance of directional lights.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum LightDescription {
+    Point {
+        position: [Float; 3],
+        color: [Float; 3],
+        intensity: Float,
+    },
+    Spot {
+        position: [Float; 3],
+        direction: [Float; 3],
+        color: [Float; 3],
+        intensity: Float,
+        cone_angle_degrees: Float,
+        falloff_start_degrees: Float,
+    },
+    Directional {
+        direction: [Float; 3], // That the light travels in
+        color: [Float; 3],
+        intensity: Float,
+    },
+}
+
+#[derive(Deserialize)]
+struct ObjectDescription {
+    #[serde(flatten)]
+    shape: ShapeDescription,
+    transform: Option<Animated<TransformDescription>>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ShapeDescription {
+    Sphere {
+        center: [Float; 3],
+        radius: Float,
+        material: MaterialDescription,
+    },
+    Floor {
+        y: Float,
+        material: MaterialDescription,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDescription {
+    Lambertian {
+        color: [Float; 3],
+    },
+    Metal {
+        color: [Float; 3],
+    },
+    Mixed {
+        color: [Float; 3],
+        shininess: Float,
+    },
+    Checkerboard {
+        color: [Float; 3],
+    },
+    Dielectric {
+        color: [Float; 3],
+        ior: Float,
+        abbe_number: Option<Float>,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+struct TransformDescription {
+    #[serde(default)]
+    translation: [Float; 3],
+    #[serde(default)]
+    rotation_degrees: [Float; 3], // About the x, y and z axes, applied in that order
+}
+
+/// Either a constant or keyframes as `[time, value]` pairs.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Animated<T> {
+    Constant(T),
+    Keyframed {
+        #[serde(deserialize_with = "deserialize_keys")]
+        keys: Vec<(Float, T)>,
+        #[serde(default)]
+        curve: Curve,
+    },
+}
+
+impl<T: Clone> Animated<T> {
+    fn keyframes<U: Interpolate>(&self, convert: impl Fn(&T) -> U) -> Keyframes<U> {
+        match self {
+            Animated::Constant(value) => Keyframes::constant(convert(value)),
+            Animated::Keyframed { keys, curve } => Keyframes::new(
+                keys.iter()
+                    .map(|(time, value)| (*time, convert(value)))
+                    .collect(),
+            )
+            .with_curve(*curve),
+        }
+    }
+}
+
+/// Keys that `Keyframes` can be built from: at least one, at finite times.
+fn deserialize_keys<'de, D, T>(deserializer: D) -> Result<Vec<(Float, T)>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let keys = Vec::<(Float, T)>::deserialize(deserializer)?;
+    if keys.is_empty() {
+        return Err(D::Error::custom("Keyframes need at least one key"));
+    }
+    if keys.iter().any(|(time, _)| !time.is_finite()) {
+        return Err(D::Error::custom("Key times must be finite"));
+    }
+    Ok(keys)
+}
+
+fn default_frames_per_second() -> Float {
+    24.0
+}
+
+fn default_shutter() -> Float {
+    0.5
+}
+
+fn default_fov_degrees() -> Animated<Float> {
+    Animated::Constant(40.0)
+}
+
+fn default_interpupillary_distance() -> Float {
+    0.064
+}
+
+fn default_f_number() -> Animated<Float> {
+    Animated::Constant(0.0)
+}
+
+fn default_intensity() -> Float {
+    1.0
+}
+
+impl SceneFile {
+    pub fn load(path: &Path) -> Result<SceneFile, Box<dyn Error>> {
+        let directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        SceneFile::parse(&fs::read_to_string(path)?, &directory)
+    }
+
+    pub fn parse(text: &str, directory: &Path) -> Result<SceneFile, Box<dyn Error>> {
+        let description: FileDescription = serde_json::from_str(text)?;
+        let render = description.render;
+        if render.width == 0 || render.height == 0 {
+            return Err("Image size must not be zero".into());
+        }
+        if description.frames_per_second <= 0.0 {
+            return Err("Frames per second must be positive".into());
+        }
+        let aperture = match &description.camera.aperture {
+            ApertureDescription::Circle => Aperture::Circle,
+            ApertureDescription::Polygon {
+                blades,
+                rotation_degrees,
+            } => {
+                if *blades < 3 {
+                    return Err("Polygonal apertures need at least 3 blades".into());
+                }
+                Aperture::Polygon {
+                    blades: *blades,
+                    rotation_degrees: *rotation_degrees,
+                }
+            }
+            ApertureDescription::Mask { path } => {
+                Aperture::Mask(ApertureMask::load(&directory.join(path))?)
+            }
+        };
+        for light in &description.lights {
+            if let LightDescription::Spot { direction, .. }
+            | LightDescription::Directional { direction, .. } = light
+            {
+                if Vector::from(*direction) == Vector::zeros() {
+                    return Err("Light directions must not be zero".into());
+                }
+            }
+        }
+        Ok(SceneFile {
+            config: RenderConfig {
+                width: render.width,
+                height: render.height,
+                aspect_ratio: render.width as Float / render.height as Float,
+                samples_per_pixel: render.samples_per_pixel,
+                max_depth: render.max_depth,
+                tile_size: 16,
+                aovs: render.aovs,
+                denoiser: render.denoiser,
+                integrator: render.integrator,
+                seed: None,
+            },
+            separate_aov_files: render.separate_aov_files,
+            frames_per_second: description.frames_per_second,
+            shutter: description.shutter,
+            camera: description.camera,
+            aperture,
+            environment: description.environment,
+            objects: description.objects,
+            lights: description.lights,
+            directory: directory.to_path_buf(),
+        })
+    }
+
+    /// Builds the scene for all frames. Objects move according to the time of each ray.
+    pub fn scene(&self) -> Result<Scene, Box<dyn Error>> {
+        let environment: Box<dyn Environment> = match &self.environment {
+            EnvironmentDescription::Constant { color } => Box::new(ConstantEnvironment {
+                color: srgb_color(color),
+            }),
+            EnvironmentDescription::Sky {
+                sun_elevation_degrees,
+                sun_azimuth_degrees,
+                turbidity,
+                intensity,
+            } => Box::new(PhysicalSky::new(
+                *sun_elevation_degrees,
+                *sun_azimuth_degrees,
+                *turbidity,
+                *intensity,
+            )),
+            EnvironmentDescription::Map {
+                path,
+                rotation_degrees,
+                intensity,
+            } => {
+                let mut map = EnvironmentMap::load(&self.directory.join(path))?;
+                map.rotation_degrees = *rotation_degrees;
+                map.intensity = *intensity;
+                Box::new(map)
+            }
+        };
+        let objects = self.objects.iter().map(build_object).collect();
+        Ok(Scene {
+            objects: SceneList { objects },
+            environment,
+            lights: self.lights.iter().map(build_light).collect(),
+        })
+    }
+
+    /// Time in seconds at which a frame starts.
+    pub fn frame_time(&self, frame: u32) -> Float {
+        frame as Float / self.frames_per_second
+    }
+
+    /// Camera for a frame, with the shutter open for its part of the frame.
+    pub fn camera(&self, frame: u32) -> Box<dyn Camera> {
+        let (origin, look_at) = self.camera_view(frame);
+        self.camera_with_view(frame, origin, look_at)
+    }
+
+    /// Origin and look at point of the camera in a frame.
+    pub fn camera_view(&self, frame: u32) -> (Point, Point) {
+        let time = self.frame_time(frame);
+        let point = |p: &[Float; 3]| Point::from(*p);
+        (
+            self.camera.origin.keyframes(point).at(time),
+            self.camera.look_at.keyframes(point).at(time),
+        )
+    }
+
+    /// Camera for a frame moved to look from `origin` to `look_at`, with the rest of
+    /// its settings from the file.
+    pub fn camera_with_view(&self, frame: u32, origin: Point, look_at: Point) -> Box<dyn Camera> {
+        let time = self.frame_time(frame);
+        let description = &self.camera;
+        let builder = PerspectiveCamera::builder(origin, look_at)
+            .field_of_view_degrees(description.fov_degrees.keyframes(|v| *v).at(time))
+            .f_number(description.f_number.keyframes(|v| *v).at(time))
+            .aperture(self.aperture.clone())
+            .cats_eye(description.cats_eye)
+            .shutter(time, time + self.shutter / self.frames_per_second);
+        let Some(stereo) = &description.stereo else {
+            return build_projection(
+                &description.projection,
+                builder.aspect_ratio(self.config.aspect_ratio),
+            );
+        };
+        let (layout, eye_aspect_ratio) = match stereo.layout {
+            StereoLayoutDescription::SideBySide => {
+                (StereoLayout::SideBySide, self.config.aspect_ratio / 2.0)
+            }
+            StereoLayoutDescription::TopBottom => {
+                (StereoLayout::TopBottom, self.config.aspect_ratio * 2.0)
+            }
+        };
+        let builder = builder.aspect_ratio(eye_aspect_ratio);
+        let [left, right] = [Eye::Left, Eye::Right].map(|eye| -> Box<dyn Camera> {
+            match description.projection {
+                ProjectionDescription::Equirectangular => Box::new(
+                    builder
+                        .clone()
+                        .build_omnidirectional_stereo(eye, stereo.interpupillary_distance),
+                ),
+                _ => build_projection(
+                    &description.projection,
+                    builder.eye(
+                        eye,
+                        stereo.interpupillary_distance,
+                        stereo.convergence_distance,
+                    ),
+                ),
+            }
+        });
+        Box::new(StereoCamera::new(left, right, layout))
+    }
+}
+
+fn build_projection(projection: &ProjectionDescription, builder: CameraBuilder) -> Box<dyn Camera> {
+    match *projection {
+        ProjectionDescription::Perspective => Box::new(builder.build()),
+        ProjectionDescription::Orthographic { height } => {
+            Box::new(builder.build_orthographic(height))
+        }
+        ProjectionDescription::Fisheye {
+            mapping,
+            fov_degrees,
+        } => {
+            let mapping = match mapping {
+                FisheyeMappingDescription::Equidistant => FisheyeMapping::Equidistant,
+                FisheyeMappingDescription::Equisolid => FisheyeMapping::Equisolid,
+            };
+            Box::new(builder.build_fisheye(mapping, fov_degrees))
+        }
+        ProjectionDescription::Equirectangular => Box::new(builder.build_equirectangular()),
+        ProjectionDescription::Cylindrical { fov_degrees } => {
+            Box::new(builder.build_cylindrical(fov_degrees))
+        }
+    }
+}
+
+fn build_object(description: &ObjectDescription) -> Box<dyn RayTracable> {
+    let object: Box<dyn RayTracable> = match &description.shape {
+        ShapeDescription::Sphere {
+            center,
+            radius,
+            material,
+        } => Box::new(Sphere {
+            center: Point::from(*center),
+            radius: *radius,
+            material: build_material(material),
+        }),
+        ShapeDescription::Floor { y, material } => Box::new(Floor {
+            y: *y,
+            material: build_material(material),
+        }),
+    };
+    match &description.transform {
+        None => object,
+        Some(transform) => Box::new(Instance::new(object, transform.keyframes(isometry))),
+    }
+}
+
+fn build_light(description: &LightDescription) -> Box<dyn Light> {
+    match description {
+        LightDescription::Point {
+            position,
+            color,
+            intensity,
+        } => Box::new(PointLight {
+            position: Point::from(*position),
+            intensity: srgb_color(color) * *intensity,
+        }),
+        LightDescription::Spot {
+            position,
+            direction,
+            color,
+            intensity,
+            cone_angle_degrees,
+            falloff_start_degrees,
+        } => Box::new(SpotLight {
+            position: Point::from(*position),
+            direction: Unit::new_normalize(Vector::from(*direction)),
+            intensity: srgb_color(color) * *intensity,
+            cone_angle_degrees: *cone_angle_degrees,
+            falloff_start_degrees: *falloff_start_degrees,
+        }),
+        LightDescription::Directional {
+            direction,
+            color,
+            intensity,
+        } => Box::new(DirectionalLight {
+            direction: Unit::new_normalize(Vector::from(*direction)),
+            irradiance: srgb_color(color) * *intensity,
+        }),
+    }
+}
+
+fn build_material(description: &MaterialDescription) -> Box<dyn Material> {
+    match description {
+        MaterialDescription::Lambertian { color } => Box::new(Lambertian {
+            color: srgb_color(color),
+        }),
+        MaterialDescription::Metal { color } => Box::new(Metal {
+            color: srgb_color(color),
+        }),
+        MaterialDescription::Mixed { color, shininess } => Box::new(MixedMaterial {
+            color: srgb_color(color),
+            shininess: *shininess,
+        }),
+        MaterialDescription::Checkerboard { color } => Box::new(FloorMaterial {
+            color: srgb_color(color),
+        }),
+        MaterialDescription::Dielectric {
+            color,
+            ior,
+            abbe_number,
+        } => Box::new(Dielectric {
+            color: srgb_color(color),
+            ior: *ior,
+            abbe_number: *abbe_number,
+        }),
+    }
+}
+
+fn isometry(transform: &TransformDescription) -> Isometry3<Float> {
+    let [x, y, z] = transform.translation;
+    let [roll, pitch, yaw] = transform.rotation_degrees.map(|d| d.to_radians());
+    Isometry3::from_parts(
+        Translation3::new(x, y, z),
+        UnitQuaternion::from_euler_angles(roll, pitch, yaw),
+    )
+}
+
+fn srgb_color(color: &[Float; 3]) -> Vector {
+    srgb_to_rgb(Vector::from(*color))
+}
+
+/// Path for a frame, replacing the last run of `#` in `pattern` with the zero-padded
+/// frame number, e.g. `frames/shot_####.png`. Without any `#` the number is added
+/// before the extension.
+pub fn frame_path(pattern: &str, frame: u32) -> PathBuf {
+    match pattern.rfind('#') {
+        Some(end) => {
+            let start = pattern[..end].trim_end_matches('#').len();
+            let width = end + 1 - start;
+            let number = format!("{:0width$}", frame, width = width);
+            PathBuf::from(format!(
+                "{}{}{}",
+                &pattern[..start],
+                number,
+                &pattern[end + 1..]
+            ))
+        }
+        None => {
+            let path = Path::new(pattern);
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let name = match path.extension() {
+                Some(extension) => {
+                    format!("{}_{:04}.{}", stem, frame, extension.to_string_lossy())
+                }
+                None => format!("{}_{:04}", stem, frame),
+            };
+            path.with_file_name(name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Ray;
+    use crate::common::INFINITY;
+    use crate::common::MIN_DIST;
+    use crate::lights::LightSample;
+    use approx::assert_relative_eq;
+    use nalgebra::{point, vector, Point2};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const SCENE: &str = r#"{
+        "render": {"width": 64, "height": 32, "samples_per_pixel": 4, "max_depth": 4,
+            "aovs": ["depth", "normal"], "denoiser": {"iterations": 3},
+            "integrator": "uv_checker"},
+        "frames_per_second": 10,
+        "shutter": 0.5,
+        "camera": {
+            "origin": {"keys": [[0.0, [0, 1, 0]], [1.0, [0, 1, 2]]]},
+            "look_at": [0, 1, -5],
+            "fov_degrees": {"keys": [[0.0, 90], [1.0, 45]], "curve": "ease_in_out"}
+        },
+        "environment": {"type": "constant", "color": [1, 1, 1]},
+        "objects": [
+            {
+                "type": "sphere", "center": [0, 0, 0], "radius": 1,
+                "material": {"type": "lambertian", "color": [0.5, 0.5, 0.5]},
+                "transform": {"keys": [
+                    [0.0, {"translation": [0, 0, -5]}],
+                    [1.0, {"translation": [10, 0, -5], "rotation_degrees": [0, 90, 0]}]
+                ]}
+            },
+            {"type": "floor", "y": 0, "material": {"type": "checkerboard", "color": [1, 1, 1]}}
+        ]
+    }"#;
+
+    #[test]
+    fn camera_follows_its_keyframes() {
+        let file = SceneFile::parse(SCENE, Path::new("")).unwrap();
+        assert_eq!(file.config.aspect_ratio, 2.0);
+        assert_eq!(file.config.aovs, [Aov::Depth, Aov::Normal]);
+        assert_eq!(file.config.integrator, Integrator::UvChecker);
+        assert_eq!(
+            file.config.denoiser,
+            Some(Denoiser {
+                iterations: 3,
+                ..Denoiser::default()
+            })
+        );
+        // Halfway through an ease in and out curve is halfway between the values
+        let halfway = PerspectiveCamera::builder(point![0.0, 1.0, 1.0], point![0.0, 1.0, -5.0])
+            .field_of_view_degrees(67.5)
+            .aspect_ratio(2.0)
+            .shutter(0.5, 0.55)
+            .build();
+        let corner = point![1.0, 1.0];
+        let ray = file
+            .camera(5)
+            .generate_ray(corner, Point2::origin(), 1.0)
+            .unwrap();
+        let expected = halfway.generate_ray(corner, Point2::origin(), 1.0).unwrap();
+        assert_relative_eq!(ray.origin, expected.origin);
+        assert_relative_eq!(ray.direction, expected.direction);
+        assert_relative_eq!(ray.time, 0.55);
+        let ray = file
+            .camera(10)
+            .generate_ray(Point2::origin(), Point2::origin(), 1.0)
+            .unwrap();
+        assert_relative_eq!(ray.time, 1.05);
+    }
+
+    #[test]
+    fn reads_camera_projections() {
+        let with_projection = |projection: &str| {
+            let text = SCENE.replace(
+                r#""look_at": [0, 1, -5],"#,
+                &format!(r#""look_at": [0, 1, -5], "projection": {projection},"#),
+            );
+            SceneFile::parse(&text, Path::new("")).unwrap().camera(0)
+        };
+        let backwards = with_projection(r#"{"type": "equirectangular"}"#)
+            .generate_ray(point![1.0, 0.0], Point2::origin(), 0.0)
+            .unwrap();
+        assert_relative_eq!(backwards.direction.z, 1.0, epsilon = 1e-9);
+        let fisheye =
+            with_projection(r#"{"type": "fisheye", "mapping": "equisolid", "fov_degrees": 180}"#);
+        assert!(fisheye
+            .generate_ray(point![1.0, 1.0], Point2::origin(), 0.0)
+            .is_none());
+        let orthographic = with_projection(r#"{"type": "orthographic", "height": 4}"#)
+            .generate_ray(point![0.0, 1.0], Point2::origin(), 0.0)
+            .unwrap();
+        assert_relative_eq!(orthographic.origin, point![0.0, 3.0, 0.0], epsilon = 1e-9);
+    }
+
+    #[test]
+    fn reads_aperture_masks_relative_to_the_file() {
+        let directory = std::env::temp_dir().join("raytracer_scene_file_aperture");
+        fs::create_dir_all(&directory).unwrap();
+        // Only the right column of the mask is open
+        image::GrayImage::from_fn(4, 4, |x, _| image::Luma([if x == 3 { 255 } else { 0 }]))
+            .save(directory.join("mask.png"))
+            .unwrap();
+        let text = SCENE.replace(
+            r#""look_at": [0, 1, -5],"#,
+            r#""look_at": [0, 1, -5], "f_number": 2, "aperture": {"type": "mask", "path": "mask.png"},"#,
+        );
+        let camera = SceneFile::parse(&text, &directory).unwrap().camera(0);
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let ray = camera
+                .generate_ray(Point2::origin(), point![rng.gen(), rng.gen()], 0.0)
+                .unwrap();
+            assert!(ray.origin.x > 0.0);
+        }
+        let missing = text.replace("mask.png", "missing.png");
+        assert!(SceneFile::parse(&missing, &directory).is_err());
+    }
+
+    #[test]
+    fn stereo_cameras_split_the_image_between_eyes() {
+        let with_camera = |extra: &str| {
+            let text = SCENE.replace(
+                r#""look_at": [0, 1, -5],"#,
+                &format!(r#""look_at": [0, 1, -5], {extra},"#),
+            );
+            SceneFile::parse(&text, Path::new("")).unwrap().camera(0)
+        };
+        let origin = |camera: &dyn Camera, x, y| {
+            camera
+                .generate_ray(point![x, y], Point2::origin(), 0.0)
+                .unwrap()
+                .origin
+        };
+        let side_by_side =
+            with_camera(r#""stereo": {"layout": "side_by_side", "interpupillary_distance": 0.1}"#);
+        assert_relative_eq!(
+            origin(side_by_side.as_ref(), -0.5, 0.0),
+            point![-0.05, 1.0, 0.0]
+        );
+        assert_relative_eq!(
+            origin(side_by_side.as_ref(), 0.5, 0.0),
+            point![0.05, 1.0, 0.0]
+        );
+        let panorama = with_camera(
+            r#""projection": {"type": "equirectangular"}, "stereo": {"layout": "top_bottom"}"#,
+        );
+        assert_relative_eq!(
+            origin(panorama.as_ref(), 0.0, 0.5),
+            point![-0.032, 1.0, 0.0]
+        );
+        assert_relative_eq!(
+            origin(panorama.as_ref(), 0.5, -0.5),
+            point![0.0, 1.0, 0.032],
+            epsilon = 1e-12
+        );
+    }
+
+    #[test]
+    fn reads_lights() {
+        let with_lights = SCENE.replace(
+            r#""objects": ["#,
+            r#""lights": [
+                {"type": "point", "position": [0, 4, 0], "color": [1, 1, 1], "intensity": 16},
+                {"type": "spot", "position": [0, 4, 0], "direction": [0, -1, 0],
+                    "color": [1, 0, 0], "intensity": 1,
+                    "cone_angle_degrees": 30, "falloff_start_degrees": 20},
+                {"type": "directional", "direction": [0, -2, 0], "color": [1, 1, 1],
+                    "intensity": 0.5}
+            ],
+            "objects": ["#,
+        );
+        let scene = SceneFile::parse(&with_lights, Path::new(""))
+            .unwrap()
+            .scene()
+            .unwrap();
+        assert_eq!(scene.lights.len(), 3);
+        let samples: Vec<LightSample> = scene
+            .lights
+            .iter()
+            .map(|light| light.sample_incident(&Point::origin()).unwrap())
+            .collect();
+        for sample in &samples {
+            assert_relative_eq!(sample.direction.into_inner(), vector![0.0, 1.0, 0.0]);
+        }
+        assert_relative_eq!(samples[0].radiance, vector![1.0, 1.0, 1.0]);
+        assert_relative_eq!(samples[1].radiance, vector![1.0, 0.0, 0.0] / 16.0);
+        assert_relative_eq!(samples[2].radiance, vector![0.5, 0.5, 0.5]);
+
+        let no_direction = with_lights.replace("[0, -2, 0]", "[0, 0, 0]");
+        assert!(SceneFile::parse(&no_direction, Path::new("")).is_err());
+    }
+
+    #[test]
+    fn objects_move_with_their_transforms() {
+        let file = SceneFile::parse(SCENE, Path::new("")).unwrap();
+        let scene = file.scene().unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let ray_at_time = |time| Ray {
+            origin: point![5.0, 0.5, 0.0],
+            direction: Unit::new_normalize(vector![0.0, 0.0, -1.0]),
+            time,
+        };
+        let hit_sphere = |time, rng: &mut StdRng| {
+            scene
+                .objects
+                .trace_ray(&ray_at_time(time), MIN_DIST, INFINITY, rng)
+                .is_some_and(|hit| (hit.distance - 5.0).abs() < 1.0)
+        };
+        assert!(!hit_sphere(0.0, &mut rng));
+        assert!(hit_sphere(0.5, &mut rng));
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let unknown_material = SCENE.replace("checkerboard", "plaid");
+        assert!(SceneFile::parse(&unknown_material, Path::new("")).is_err());
+        let no_size = SCENE.replace("\"width\": 64", "\"width\": 0");
+        assert!(SceneFile::parse(&no_size, Path::new("")).is_err());
+        let no_keys = SCENE.replace("[[0.0, 90], [1.0, 45]]", "[]");
+        assert!(SceneFile::parse(&no_keys, Path::new("")).is_err());
+        let infinite_time = SCENE.replace("[1.0, 45]", "[1e999, 45]");
+        assert!(SceneFile::parse(&infinite_time, Path::new("")).is_err());
+    }
+
+    #[test]
+    fn frame_paths_are_numbered() {
+        assert_eq!(
+            frame_path("out/f_####.png", 7),
+            PathBuf::from("out/f_0007.png")
+        );
+        assert_eq!(frame_path("f#.png", 12), PathBuf::from("f12.png"));
+        assert_eq!(
+            frame_path("out/frame.png", 3),
+            PathBuf::from("out/frame_0003.png")
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use crate::common::Direction;
+use crate::common::Float;
+use crate::common::Vector;
+use crate::environment::Environment;
+use crate::environment::EnvironmentMap;
+use crate::environment::EnvironmentSample;
+use crate::sampling::uniform_cone_direction;
+use crate::sampling::uniform_cone_pdf;
+use crate::srgb::xyz_to_rgb;
+use nalgebra::{vector, Unit};
+use rand::prelude::*;
+use std::f64::consts::PI;
+
+const SUN_ANGULAR_RADIUS_DEGREES: Float = 0.2667;
+// Luminance of the sun outside the atmosphere, in kcd/m²
+const SUN_EXTRATERRESTRIAL_LUMINANCE: Float = 2.0e6;
+// Wavelengths in micrometres used for the red, green and blue sun transmittance
+const SUN_WAVELENGTHS: [Float; 3] = [0.65, 0.57, 0.475];
+const SUN_SAMPLE_PROBABILITY: Float = 0.5;
+const SKY_TABLE_WIDTH: usize = 128;
+const SKY_TABLE_HEIGHT: usize = 64;
+
+/// Analytic daylight from Preetham et al., "A Practical Analytic Model for Daylight"
+/// (1999), together with a sun disk of the right angular size. Directions below the
+/// horizon see the sky colour at the horizon.
+pub struct PhysicalSky {
+    sun_direction: Direction,
+    sun_radiance: Vector,
+    cos_sun_radius: Float,
+    // Perez coefficients A to E for luminance Y and chromaticities x and y
+    perez_y: [Float; 5],
+    perez_x_chroma: [Float; 5],
+    perez_y_chroma: [Float; 5],
+    zenith: Vector, // (Y, x, y) at the zenith
+    scale: Float,
+    sky_table: EnvironmentMap, // Tabulated sky used for importance sampling
+}
+
+impl PhysicalSky {
+    /// Sky for the sun at `elevation` above the horizon and `azimuth` clockwise from -z
+    /// when seen from above, both in degrees. `turbidity` ranges from about 2 (very
+    /// clear) to 10 (hazy). `intensity` scales from kcd/m² to scene radiance units;
+    /// around 0.025 maps a white surface in full sunlight to roughly one.
+    pub fn new(
+        sun_elevation_degrees: Float,
+        sun_azimuth_degrees: Float,
+        turbidity: Float,
+        intensity: Float,
+    ) -> PhysicalSky {
+        let elevation = sun_elevation_degrees.to_radians();
+        let azimuth = sun_azimuth_degrees.to_radians();
+        let sun_direction = Unit::new_normalize(vector![
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            -elevation.cos() * azimuth.cos()
+        ]);
+        // The model is only valid with the sun above the horizon
+        let theta_s = (PI / 2.0 - elevation).clamp(0.0, PI / 2.0);
+        let t = turbidity;
+
+        let perez_y = [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ];
+        let perez_x_chroma = [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ];
+        let perez_y_chroma = [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let theta = vector![theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+        let zenith_x = t * t * vector![0.00166, -0.00375, 0.00209, 0.0].dot(&theta)
+            + t * vector![-0.02903, 0.06377, -0.03202, 0.00394].dot(&theta)
+            + vector![0.11693, -0.21196, 0.06052, 0.25886].dot(&theta);
+        let zenith_y = t * t * vector![0.00275, -0.00610, 0.00317, 0.0].dot(&theta)
+            + t * vector![-0.04214, 0.08970, -0.04153, 0.00516].dot(&theta)
+            + vector![0.15346, -0.26756, 0.06670, 0.26688].dot(&theta);
+
+        let sun_radiance = if elevation > 0.0 {
+            sun_transmittance(theta_s, t) * SUN_EXTRATERRESTRIAL_LUMINANCE * intensity
+        } else {
+            vector![0.0, 0.0, 0.0]
+        };
+
+        let mut sky = PhysicalSky {
+            sun_direction,
+            sun_radiance,
+            cos_sun_radius: SUN_ANGULAR_RADIUS_DEGREES.to_radians().cos(),
+            perez_y,
+            perez_x_chroma,
+            perez_y_chroma,
+            zenith: vector![zenith_luminance.max(0.0), zenith_x, zenith_y],
+            scale: intensity,
+            sky_table: EnvironmentMap::new(1, 1, vec![vector![0.0, 0.0, 0.0]]),
+        };
+        sky.sky_table =
+            EnvironmentMap::from_fn(SKY_TABLE_WIDTH, SKY_TABLE_HEIGHT, |d| sky.sky_radiance(d));
+        sky
+    }
+
+    pub fn sun_direction(&self) -> Direction {
+        self.sun_direction
+    }
+
+    /// Radiance of the sky alone, without the sun disk.
+    pub fn sky_radiance(&self, direction: &Direction) -> Vector {
+        let cos_theta = direction.y.max(0.001);
+        let cos_gamma = direction.dot(&self.sun_direction).clamp(-1.0, 1.0);
+        let gamma = cos_gamma.acos();
+        let theta_s = self.sun_direction.y.clamp(0.0, 1.0).acos();
+
+        let relative = |coefficients: &[Float; 5]| {
+            perez(coefficients, cos_theta, gamma, cos_gamma)
+                / perez(coefficients, 1.0, theta_s, theta_s.cos())
+        };
+        let luminance = self.zenith.x * relative(&self.perez_y);
+        let x = self.zenith.y * relative(&self.perez_x_chroma);
+        let y = self.zenith.z * relative(&self.perez_y_chroma);
+
+        let xyz = vector![x / y * luminance, luminance, (1.0 - x - y) / y * luminance];
+        xyz_to_rgb(xyz).map(|c| c.max(0.0)) * self.scale
+    }
+
+    fn sun_sample_probability(&self) -> Float {
+        if self.sun_radiance == vector![0.0, 0.0, 0.0] {
+            0.0
+        } else {
+            SUN_SAMPLE_PROBABILITY
+        }
+    }
+
+    fn in_sun_disk(&self, direction: &Direction) -> bool {
+        direction.dot(&self.sun_direction) >= self.cos_sun_radius
+    }
+}
+
+impl Environment for PhysicalSky {
+    fn radiance(&self, direction: &Direction) -> Vector {
+        if self.in_sun_disk(direction) {
+            self.sky_radiance(direction) + self.sun_radiance
+        } else {
+            self.sky_radiance(direction)
+        }
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore) -> Option<EnvironmentSample> {
+        let direction = if rng.gen::<Float>() < self.sun_sample_probability() {
+            uniform_cone_direction(&self.sun_direction, self.cos_sun_radius, rng)
+        } else {
+            self.sky_table.sample(rng)?.direction
+        };
+        Some(EnvironmentSample {
+            direction,
+            radiance: self.radiance(&direction),
+            pdf: self.pdf(&direction),
+        })
+    }
+
+    fn pdf(&self, direction: &Direction) -> Float {
+        let sun_probability = self.sun_sample_probability();
+        let sun_pdf = if self.in_sun_disk(direction) {
+            uniform_cone_pdf(self.cos_sun_radius)
+        } else {
+            0.0
+        };
+        sun_probability * sun_pdf + (1.0 - sun_probability) * self.sky_table.pdf(direction)
+    }
+}
+
+/// Perez et al. sky luminance distribution function.
+fn perez(coefficients: &[Float; 5], cos_theta: Float, gamma: Float, cos_gamma: Float) -> Float {
+    let [a, b, c, d, e] = *coefficients;
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+/// Fraction of sunlight reaching the ground through Rayleigh and aerosol scattering,
+/// following the appendix of Preetham et al.
+fn sun_transmittance(theta_s: Float, turbidity: Float) -> Vector {
+    let relative_optical_mass =
+        1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let alpha = 1.3;
+    vector![SUN_WAVELENGTHS[0], SUN_WAVELENGTHS[1], SUN_WAVELENGTHS[2]].map(|lambda| {
+        let rayleigh = (-0.008735 * lambda.powf(-4.08) * relative_optical_mass).exp();
+        let aerosol = (-beta * lambda.powf(-alpha) * relative_optical_mass).exp();
+        rayleigh * aerosol
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::srgb::luminance;
+    use approx::assert_relative_eq;
+    use rand::rngs::StdRng;
+
+    fn direction(elevation_degrees: Float, azimuth_degrees: Float) -> Direction {
+        let (elevation, azimuth) = (elevation_degrees.to_radians(), azimuth_degrees.to_radians());
+        Unit::new_normalize(vector![
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            -elevation.cos() * azimuth.cos()
+        ])
+    }
+
+    #[test]
+    fn zenith_matches_preetham_zenith_luminance() {
+        // Zenith luminance for turbidity 3 and the sun 30 degrees from the zenith
+        let sky = PhysicalSky::new(60.0, 0.0, 3.0, 1.0);
+        let zenith = sky.sky_radiance(&direction(90.0, 0.0));
+        let theta_s = (30.0 as Float).to_radians();
+        let chi = (4.0 / 9.0 - 3.0 / 120.0) * (PI - 2.0 * theta_s);
+        let expected = (4.0453 * 3.0 - 4.9710) * chi.tan() - 0.2155 * 3.0 + 2.4192;
+        assert_relative_eq!(luminance(&zenith), expected, max_relative = 0.01);
+    }
+
+    #[test]
+    fn sky_is_brightest_around_the_sun() {
+        let sky = PhysicalSky::new(30.0, 90.0, 3.0, 1.0);
+        let near_sun = luminance(&sky.sky_radiance(&direction(35.0, 90.0)));
+        let opposite = luminance(&sky.sky_radiance(&direction(35.0, 270.0)));
+        assert!(near_sun > 3.0 * opposite);
+        assert_relative_eq!(sky.sun_direction(), direction(30.0, 90.0), epsilon = 1e-12);
+    }
+
+    #[test]
+    fn sun_is_dimmer_and_redder_when_hazy_or_low() {
+        let clear = PhysicalSky::new(60.0, 0.0, 2.0, 1.0);
+        let hazy = PhysicalSky::new(60.0, 0.0, 8.0, 1.0);
+        let low = PhysicalSky::new(3.0, 0.0, 2.0, 1.0);
+        let set = PhysicalSky::new(-5.0, 0.0, 2.0, 1.0);
+        assert!(luminance(&hazy.sun_radiance) < luminance(&clear.sun_radiance));
+        assert!(
+            low.sun_radiance.z / low.sun_radiance.x < clear.sun_radiance.z / clear.sun_radiance.x
+        );
+        assert_eq!(set.sun_radiance, vector![0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn samples_hit_the_sun_and_match_pdf() {
+        let sky = PhysicalSky::new(40.0, 20.0, 2.5, 0.025);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut sun_samples = 0;
+        for _ in 0..2000 {
+            let sample = sky.sample(&mut rng).unwrap();
+            assert_relative_eq!(sample.pdf, sky.pdf(&sample.direction), max_relative = 1e-9);
+            assert_relative_eq!(sample.radiance, sky.radiance(&sample.direction));
+            if sky.in_sun_disk(&sample.direction) {
+                sun_samples += 1;
+            }
+        }
+        assert!((900..1100).contains(&sun_samples));
+    }
+}
@@ -0,0 +1,384 @@
+use crate::common::Float;
+use crate::common::Vector;
+use crate::srgb::xyz_to_rgb;
+use nalgebra::{vector, Matrix3};
+use std::sync::OnceLock;
+
+/// Shortest and longest wavelengths that spectral renders sample, in nanometres.
+pub const MIN_WAVELENGTH: Float = 380.0;
+pub const MAX_WAVELENGTH: Float = 780.0;
+const RANGE: Float = MAX_WAVELENGTH - MIN_WAVELENGTH;
+
+/// CIE standard illuminant D65 from 380 nm to 780 nm in steps of 10 nm, relative to
+/// 100 at 560 nm.
+const D65: [Float; 41] = [
+    49.9755, 54.6482, 82.7549, 91.486, 93.4318, 86.6823, 104.865, 117.008, 117.812, 114.861,
+    115.923, 108.811, 109.354, 107.802, 104.790, 107.689, 104.405, 104.046, 100.000, 96.3342,
+    95.788, 88.6856, 90.0062, 89.5991, 87.6987, 83.2886, 83.6992, 80.0268, 80.2146, 82.2778,
+    78.2842, 69.7213, 71.6091, 74.349, 61.604, 69.8856, 75.087, 63.5927, 46.4182, 66.8054, 63.3828,
+];
+
+/// Brightness that colours are uplifted at before the spectrum is scaled to theirs.
+/// Staying below one leaves room for the sigmoid to reach saturated colours.
+const FIT_BRIGHTNESS: Float = 0.8;
+
+/// Grid cells along each axis of the uplifting table.
+const TABLE_SIZE: usize = 16;
+
+/// The wavelengths that a path carries light at, in nanometres: a hero wavelength
+/// and two more spread evenly over the visible range from it. A `Vector` of values
+/// along the path holds the light at each of them in turn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Wavelengths {
+    wavelengths: Vector,
+    pdfs: Vector, // Densities that the wavelengths were sampled with, zero once dropped
+}
+
+impl Wavelengths {
+    /// Hero wavelength from a uniform `u` in [0, 1), with the others at a third and two
+    /// thirds of the range further on, wrapping around.
+    pub fn sample(u: Float) -> Wavelengths {
+        let offset = u * RANGE;
+        Wavelengths {
+            wavelengths: Vector::from_fn(|i, _| {
+                MIN_WAVELENGTH + (offset + i as Float * RANGE / 3.0).rem_euclid(RANGE)
+            }),
+            pdfs: Vector::repeat(1.0 / RANGE),
+        }
+    }
+
+    pub fn hero(&self) -> Float {
+        self.wavelengths.x
+    }
+
+    /// Keeps only the hero wavelength, for paths that go a different way for each
+    /// wavelength, such as through dispersive glass.
+    pub fn terminate_secondary(&mut self) {
+        if self.pdfs.y > 0.0 {
+            self.pdfs = vector![self.pdfs.x / 3.0, 0.0, 0.0];
+        }
+    }
+
+    /// A reflectance given in linear sRGB, at these wavelengths.
+    pub fn reflectance(&self, rgb: &Vector) -> Vector {
+        let spectrum = RgbSpectrum::new(rgb);
+        self.wavelengths.map(|wavelength| spectrum.eval(wavelength))
+    }
+
+    /// Light given in linear sRGB, at these wavelengths. White light is D65, as the
+    /// white point of sRGB is.
+    pub fn radiance(&self, rgb: &Vector) -> Vector {
+        let spectrum = RgbSpectrum::new(rgb);
+        self.wavelengths
+            .map(|wavelength| spectrum.eval(wavelength) * d65(wavelength))
+    }
+
+    /// Linear sRGB of light with `values` at these wavelengths, through CIE XYZ. The
+    /// mean over many samples of wavelengths converges to the colour of the spectrum.
+    pub fn to_rgb(&self, values: &Vector) -> Vector {
+        (0..3)
+            .filter(|&i| self.pdfs[i] > 0.0)
+            .map(|i| rgb_response(self.wavelengths[i]) * (values[i] / self.pdfs[i]))
+            .sum::<Vector>()
+            / 3.0
+    }
+}
+
+/// A smooth reflectance spectrum with a given linear sRGB colour under D65, from
+/// Jakob and Hanika's "A Low-Dimensional Function Space for Efficient Spectral
+/// Upsampling": a sigmoid of a quadratic in the wavelength. The quadratic's
+/// coefficients come from a table fitted once, and the spectrum is scaled to the
+/// brightness of the colour.
+#[derive(Debug, Clone, Copy)]
+pub struct RgbSpectrum {
+    coefficients: Vector,
+    scale: Float,
+}
+
+impl RgbSpectrum {
+    /// Negative channels are taken as zero.
+    pub fn new(rgb: &Vector) -> RgbSpectrum {
+        let rgb = rgb.map(|channel| channel.max(0.0));
+        let max = rgb.max();
+        if max <= 0.0 || !max.is_finite() {
+            return RgbSpectrum {
+                coefficients: vector![0.0, 0.0, 0.0],
+                scale: 0.0,
+            };
+        }
+        RgbSpectrum {
+            coefficients: uplifting_table().coefficients(&(rgb / max)),
+            scale: max / FIT_BRIGHTNESS,
+        }
+    }
+
+    pub fn eval(&self, wavelength: Float) -> Float {
+        self.scale * sigmoid(quadratic(&self.coefficients, wavelength))
+    }
+}
+
+fn quadratic(coefficients: &Vector, wavelength: Float) -> Float {
+    let t = (wavelength - MIN_WAVELENGTH) / RANGE;
+    (coefficients.x * t + coefficients.y) * t + coefficients.z
+}
+
+fn sigmoid(x: Float) -> Float {
+    if x.is_infinite() {
+        return if x > 0.0 { 1.0 } else { 0.0 };
+    }
+    0.5 + x / (2.0 * (1.0 + x * x).sqrt())
+}
+
+fn sigmoid_derivative(x: Float) -> Float {
+    0.5 / (1.0 + x * x).powf(1.5)
+}
+
+/// CIE 1931 colour matching functions, from the multi-lobe fit by Wyman, Sloan and
+/// Shirley.
+pub fn cie_xyz(wavelength: Float) -> Vector {
+    let lobe = |mean: Float, below: Float, above: Float| {
+        let t = (wavelength - mean) / if wavelength < mean { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+    vector![
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7)
+            - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8)
+    ]
+}
+
+/// Relative spectral power of D65, linearly interpolated and about one.
+fn d65(wavelength: Float) -> Float {
+    let position = ((wavelength - MIN_WAVELENGTH) / 10.0).clamp(0.0, (D65.len() - 1) as Float);
+    let index = (position as usize).min(D65.len() - 2);
+    let t = position - index as Float;
+    (D65[index] * (1.0 - t) + D65[index + 1] * t) / 100.0
+}
+
+/// Linear sRGB per nanometre of light at `wavelength`, scaled so that D65 integrates
+/// to white.
+fn rgb_response(wavelength: Float) -> Vector {
+    static WHITE: OnceLock<Vector> = OnceLock::new();
+    let white = WHITE.get_or_init(|| {
+        (0..RANGE as usize)
+            .map(|i| {
+                let wavelength = MIN_WAVELENGTH + i as Float + 0.5;
+                xyz_to_rgb(cie_xyz(wavelength)) * d65(wavelength)
+            })
+            .sum()
+    });
+    xyz_to_rgb(cie_xyz(wavelength)).component_div(white)
+}
+
+/// Coefficients for colours whose largest channel is one, on a grid over the other
+/// two channels for each channel that can be the largest.
+struct UpliftingTable {
+    faces: [Vec<Vector>; 3], // Rows along the first other channel
+}
+
+fn uplifting_table() -> &'static UpliftingTable {
+    static TABLE: OnceLock<UpliftingTable> = OnceLock::new();
+    TABLE.get_or_init(UpliftingTable::fit)
+}
+
+impl UpliftingTable {
+    /// Fits every grid point, starting from white and moving towards saturated
+    /// colours from a neighbour that is already fitted.
+    fn fit() -> UpliftingTable {
+        let fitting = Fitting::new();
+        let white = {
+            let y = 2.0 * FIT_BRIGHTNESS - 1.0;
+            vector![0.0, 0.0, y / (1.0 - y * y).sqrt()]
+        };
+        let points = TABLE_SIZE + 1;
+        let faces = [0, 1, 2].map(|largest| {
+            let mut face = vec![white; points * points];
+            for i in (0..points).rev() {
+                for j in (0..points).rev() {
+                    let guess = if j < TABLE_SIZE {
+                        face[i * points + j + 1]
+                    } else if i < TABLE_SIZE {
+                        face[(i + 1) * points + j]
+                    } else {
+                        white
+                    };
+                    let mut target = vector![0.0, 0.0, 0.0];
+                    target[largest] = 1.0;
+                    target[(largest + 1) % 3] = i as Float / TABLE_SIZE as Float;
+                    target[(largest + 2) % 3] = j as Float / TABLE_SIZE as Float;
+                    face[i * points + j] = fitting.fit(&(target * FIT_BRIGHTNESS), guess);
+                }
+            }
+            face
+        });
+        UpliftingTable { faces }
+    }
+
+    /// Bilinearly interpolated coefficients for `rgb`, whose largest channel is one.
+    fn coefficients(&self, rgb: &Vector) -> Vector {
+        let largest = rgb.imax();
+        let face = &self.faces[largest];
+        let scaled = |channel: Float| channel.clamp(0.0, 1.0) * TABLE_SIZE as Float;
+        let (a, b) = (
+            scaled(rgb[(largest + 1) % 3]),
+            scaled(rgb[(largest + 2) % 3]),
+        );
+        let (i, j) = (
+            (a as usize).min(TABLE_SIZE - 1),
+            (b as usize).min(TABLE_SIZE - 1),
+        );
+        let (s, t) = (a - i as Float, b - j as Float);
+        let at = |i: usize, j: usize| face[i * (TABLE_SIZE + 1) + j];
+        (at(i, j) * (1.0 - t) + at(i, j + 1) * t) * (1.0 - s)
+            + (at(i + 1, j) * (1.0 - t) + at(i + 1, j + 1) * t) * s
+    }
+}
+
+/// The colour of reflectance spectra under D65, summed over 5 nm bins.
+struct Fitting {
+    bins: Vec<(Float, Vector)>, // Wavelength and the sRGB of the bin's share of D65
+}
+
+impl Fitting {
+    fn new() -> Fitting {
+        let bins: Vec<(Float, Vector)> = (0..(RANGE / 5.0) as usize)
+            .map(|i| {
+                let wavelength = MIN_WAVELENGTH + (i as Float + 0.5) * 5.0;
+                (
+                    wavelength,
+                    rgb_response(wavelength) * (d65(wavelength) * 5.0),
+                )
+            })
+            .collect();
+        // The sums should give white for a reflectance of one
+        let white: Vector = bins.iter().map(|(_, rgb)| rgb).sum();
+        let bins = bins
+            .into_iter()
+            .map(|(wavelength, rgb)| (wavelength, rgb.component_div(&white)))
+            .collect();
+        Fitting { bins }
+    }
+
+    /// Colour of the spectrum with `coefficients`, and its derivatives with respect to
+    /// them as columns.
+    fn colour(&self, coefficients: &Vector) -> (Vector, Matrix3<Float>) {
+        let mut colour = vector![0.0, 0.0, 0.0];
+        let mut jacobian = Matrix3::zeros();
+        for (wavelength, rgb) in &self.bins {
+            let x = quadratic(coefficients, *wavelength);
+            colour += rgb * sigmoid(x);
+            let t = (wavelength - MIN_WAVELENGTH) / RANGE;
+            let slope = sigmoid_derivative(x);
+            for (k, power) in [t * t, t, 1.0].into_iter().enumerate() {
+                let mut column = jacobian.column_mut(k);
+                column += rgb * (slope * power);
+            }
+        }
+        (colour, jacobian)
+    }
+
+    /// Levenberg-Marquardt fit of the coefficients to `target`. Colours outside what
+    /// reflectances can give end up as close as the iterations get.
+    fn fit(&self, target: &Vector, guess: Vector) -> Vector {
+        let mut coefficients = guess;
+        let (mut colour, mut jacobian) = self.colour(&coefficients);
+        let mut damping = 1e-3;
+        for _ in 0..100 {
+            let residual = colour - target;
+            if residual.norm() < 1e-8 {
+                break;
+            }
+            let normal = jacobian.transpose() * jacobian;
+            let damped = normal + Matrix3::from_diagonal(&normal.diagonal()) * damping;
+            let Some(step) = damped.lu().solve(&(-jacobian.transpose() * residual)) else {
+                break;
+            };
+            let candidate = coefficients + step;
+            let (candidate_colour, candidate_jacobian) = self.colour(&candidate);
+            if (candidate_colour - target).norm() < residual.norm() {
+                coefficients = candidate;
+                colour = candidate_colour;
+                jacobian = candidate_jacobian;
+                damping *= 0.3;
+            } else {
+                damping *= 10.0;
+            }
+        }
+        coefficients
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn uplifted_reflectances_keep_their_colour() {
+        let fitting = Fitting::new();
+        for rgb in [
+            vector![1.0, 1.0, 1.0],
+            vector![0.5, 0.5, 0.5],
+            vector![0.8, 0.3, 0.2],
+            vector![0.1, 0.5, 0.9],
+            vector![0.2, 0.7, 0.1],
+            vector![0.93, 0.87, 0.12],
+        ] {
+            let spectrum = RgbSpectrum::new(&rgb);
+            let colour: Vector = fitting
+                .bins
+                .iter()
+                .map(|(wavelength, response)| response * spectrum.eval(*wavelength))
+                .sum();
+            assert!((colour - rgb).amax() < 0.01, "{} gave {}", rgb, colour);
+            // Reflectances stay physical
+            for wavelength in (380..780).step_by(10) {
+                let value = spectrum.eval(wavelength as Float);
+                assert!((0.0..=1.0 + 1e-6).contains(&value), "{}", value);
+            }
+        }
+        // Grey is flat
+        let grey = RgbSpectrum::new(&vector![0.5, 0.5, 0.5]);
+        assert_relative_eq!(grey.eval(400.0), 0.5, epsilon = 1e-3);
+        assert_relative_eq!(grey.eval(700.0), 0.5, epsilon = 1e-3);
+    }
+
+    #[test]
+    fn sampled_light_converges_to_its_colour() {
+        for rgb in [vector![1.0, 1.0, 1.0], vector![0.2, 0.5, 0.8]] {
+            let samples = 1000;
+            let colour = (0..samples)
+                .map(|i| {
+                    let wavelengths = Wavelengths::sample((i as Float + 0.5) / samples as Float);
+                    wavelengths.to_rgb(&wavelengths.radiance(&rgb))
+                })
+                .sum::<Vector>()
+                / samples as Float;
+            assert!((colour - rgb).amax() < 0.01, "{} gave {}", rgb, colour);
+        }
+    }
+
+    #[test]
+    fn hero_wavelengths_spread_over_the_range() {
+        let wavelengths = Wavelengths::sample(0.9);
+        assert_relative_eq!(wavelengths.hero(), 740.0);
+        assert_relative_eq!(
+            wavelengths.wavelengths.y,
+            380.0 + 400.0 * (0.9 + 1.0 / 3.0 - 1.0)
+        );
+        assert_relative_eq!(
+            wavelengths.wavelengths.z,
+            380.0 + 400.0 * (0.9 + 2.0 / 3.0 - 1.0)
+        );
+
+        // Keeping only the hero counts it for all three
+        let mut hero_only = wavelengths;
+        hero_only.terminate_secondary();
+        let values = vector![1.0, 0.0, 0.0];
+        assert_relative_eq!(
+            hero_only.to_rgb(&vector![1.0, 5.0, 5.0]),
+            wavelengths.to_rgb(&values) * 3.0
+        );
+    }
+}
@@ -34,6 +34,20 @@ pub fn srgb_to_rgb(colour: Vector) -> Vector {
     ]
 }
 
+/// Converts CIE XYZ to linear sRGB (D65 white point).
+pub fn xyz_to_rgb(xyz: Vector) -> Vector {
+    vector![
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z
+    ]
+}
+
+/// Relative luminance of a linear sRGB colour.
+pub fn luminance(colour: &Vector) -> Float {
+    0.2126 * colour.x + 0.7152 * colour.y + 0.0722 * colour.z
+}
+
 fn srgb_to_rgb_channel(value: Float) -> Float {
     if value <= 0.04045 {
         value / 12.92
@@ -0,0 +1,387 @@
+use crate::aabb::Aabb;
+use crate::common::Direction;
+use crate::common::Float;
+use crate::common::Material;
+use crate::common::Ray;
+use crate::common::RayIntersection;
+use crate::common::RayTracable;
+use crate::common::ScatteredRay;
+use crate::common::SubsurfaceExit;
+use crate::common::Vector;
+use crate::materials::random_direction_on_hemisphere_cosine_weighted;
+use crate::materials::Lambertian;
+use crate::media::HenyeyGreenstein;
+use nalgebra::vector;
+use nalgebra::Point2;
+use rand::prelude::*;
+use std::fmt;
+
+const MAX_WALK_STEPS: u32 = 4096;
+// Number of scattering events before Russian roulette may end the walk
+const ROULETTE_START: u32 = 8;
+const WALK_MIN_DIST: Float = 1e-6;
+
+/// Closed object filled with a dense scattering medium, for skin, wax, marble and
+/// the like. Light enters through a rough boundary, takes a random walk inside and
+/// leaves diffusely wherever the walk reaches the boundary again.
+///
+/// `albedo` is the single-scattering albedo and `mean_free_path` the average
+/// distance between scattering events, both per colour channel. Longer mean free
+/// paths let that colour bleed further through the object.
+pub struct Subsurface {
+    boundary: Box<dyn RayTracable>,
+    albedo: Vector,
+    extinction: Vector,
+    phase_function: HenyeyGreenstein,
+    exit_material: Lambertian, // Scatters light out of the object at the exit
+}
+
+impl Subsurface {
+    pub fn new(
+        boundary: Box<dyn RayTracable>,
+        albedo: Vector,
+        mean_free_path: Vector,
+        g: Float,
+    ) -> Subsurface {
+        Subsurface {
+            boundary,
+            albedo,
+            extinction: mean_free_path.map(|d| 1.0 / d),
+            phase_function: HenyeyGreenstein {
+                g,
+                albedo: vector![1.0, 1.0, 1.0],
+            },
+            exit_material: Lambertian {
+                color: vector![1.0, 1.0, 1.0],
+            },
+        }
+    }
+
+    /// Follows light entering at `intersection` until it leaves the object. One
+    /// channel, picked at random for the whole walk, drives the distance sampling and
+    /// every channel is weighted by the path's density averaged over all channels, so
+    /// the weights stay bounded however different the mean free paths are.
+    fn random_walk(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        rng: &mut dyn RngCore,
+    ) -> SubsurfaceExit<'_> {
+        let outward = if intersection.normal.dot(&ray.direction) < 0.0 {
+            intersection.normal
+        } else {
+            -intersection.normal
+        };
+        let mut walk = Ray {
+            origin: intersection.position,
+            direction: random_direction_on_hemisphere_cosine_weighted(&-outward, rng),
+            time: ray.time,
+        };
+        let channel = rng.gen_range(0..3);
+        // Albedo and Russian roulette weights of the walk so far
+        let mut throughput = vector![1.0, 1.0, 1.0];
+        // Density of the walk so far if each channel had been sampled, up to a scale
+        let mut path_density = vector![1.0, 1.0, 1.0];
+        let weight = |throughput: &Vector, path_density: &Vector| {
+            throughput.component_mul(path_density) / path_density.mean()
+        };
+
+        // Only the first segment starts on the boundary, later ones start strictly inside
+        let mut min_dist = WALK_MIN_DIST;
+
+        for step in 0..MAX_WALK_STEPS {
+            let distance = -(1.0 - rng.gen::<Float>()).ln() / self.extinction[channel];
+
+            if let Some(hit) = self.boundary.trace_ray(&walk, min_dist, distance, rng) {
+                path_density.component_mul_assign(&self.transmittance(hit.distance));
+                let normal = if hit.normal.dot(&walk.direction) > 0.0 {
+                    hit.normal
+                } else {
+                    -hit.normal
+                };
+                return SubsurfaceExit {
+                    ray: walk,
+                    intersection: RayIntersection {
+                        position: hit.position,
+                        normal,
+                        uv: hit.uv,
+                        distance: hit.distance,
+                        material: &self.exit_material,
+                    },
+                    throughput: weight(&throughput, &path_density),
+                };
+            }
+
+            let density = self.extinction.component_mul(&self.transmittance(distance));
+            path_density.component_mul_assign(&density);
+            path_density /= path_density.max();
+            throughput.component_mul_assign(&self.albedo);
+            if throughput == vector![0.0, 0.0, 0.0] {
+                break;
+            }
+
+            if step >= ROULETTE_START {
+                let survival = weight(&throughput, &path_density).max().min(1.0);
+                if rng.gen::<Float>() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+
+            min_dist = 0.0;
+            walk = Ray {
+                origin: walk.at(distance),
+                direction: self.phase_function.sample_direction(&walk.direction, rng),
+                time: walk.time,
+            };
+        }
+
+        SubsurfaceExit {
+            intersection: RayIntersection {
+                position: walk.origin,
+                normal: outward,
+                uv: Point2::origin(),
+                distance: 0.0,
+                material: &self.exit_material,
+            },
+            ray: walk,
+            throughput: vector![0.0, 0.0, 0.0],
+        }
+    }
+
+    fn transmittance(&self, distance: Float) -> Vector {
+        self.extinction.map(|e| (-e * distance).exp())
+    }
+}
+
+impl fmt::Debug for Subsurface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subsurface")
+            .field("albedo", &self.albedo)
+            .field("extinction", &self.extinction)
+            .field("g", &self.phase_function.g)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RayTracable for Subsurface {
+    fn trace_ray(
+        &self,
+        ray: &Ray,
+        min_dist: f64,
+        max_dist: f64,
+        rng: &mut dyn RngCore,
+    ) -> Option<RayIntersection<'_>> {
+        let intersection = self.boundary.trace_ray(ray, min_dist, max_dist, rng)?;
+        Some(RayIntersection {
+            material: self,
+            ..intersection
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.boundary.bounding_box()
+    }
+}
+
+/// The object is its own material at the entry point. Integrators that don't handle
+/// subsurface exits see it as a specular lobe leaving from the exit point.
+impl Material for Subsurface {
+    fn scatter_ray(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        rng: &mut dyn RngCore,
+    ) -> Option<ScatteredRay> {
+        let exit = self.random_walk(ray, intersection, rng);
+        if exit.throughput == vector![0.0, 0.0, 0.0] {
+            return None;
+        }
+        let scattered = self
+            .exit_material
+            .scatter_ray(&exit.ray, &exit.intersection, rng)?;
+        Some(ScatteredRay {
+            attenuation: exit.throughput.component_mul(&scattered.attenuation),
+            ray: scattered.ray,
+            specular: true,
+        })
+    }
+
+    fn eval(&self, _ray: &Ray, _intersection: &RayIntersection, _direction: &Direction) -> Vector {
+        vector![0.0, 0.0, 0.0]
+    }
+
+    fn pdf(&self, _ray: &Ray, _intersection: &RayIntersection, _direction: &Direction) -> Float {
+        0.0
+    }
+
+    fn albedo(&self, _intersection: &RayIntersection) -> Vector {
+        self.albedo
+    }
+
+    fn sample_subsurface_exit(
+        &self,
+        ray: &Ray,
+        intersection: &RayIntersection,
+        rng: &mut dyn RngCore,
+    ) -> Option<SubsurfaceExit<'_>> {
+        Some(self.random_walk(ray, intersection, rng))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Point;
+    use crate::common::INFINITY;
+    use nalgebra::{point, Unit};
+    use rand::rngs::StdRng;
+
+    /// Infinite slab between y = 0 and y = -thickness.
+    struct Slab {
+        thickness: Float,
+        material: Lambertian,
+    }
+
+    impl RayTracable for Slab {
+        fn trace_ray(
+            &self,
+            ray: &Ray,
+            min_dist: f64,
+            max_dist: f64,
+            _rng: &mut dyn RngCore,
+        ) -> Option<RayIntersection<'_>> {
+            [0.0, -self.thickness]
+                .iter()
+                .map(|y| (y - ray.origin.y) / ray.direction.y)
+                .filter(|d| *d >= min_dist && *d <= max_dist)
+                .min_by(|a, b| a.total_cmp(b))
+                .map(|distance| RayIntersection {
+                    position: ray.at(distance),
+                    normal: Unit::new_unchecked(vector![0.0, 1.0, 0.0]),
+                    uv: point![ray.at(distance).x, ray.at(distance).z],
+                    distance,
+                    material: &self.material,
+                })
+        }
+    }
+
+    /// Mean reflected and transmitted throughput of light entering the top of a slab.
+    fn reflectance_and_transmittance(
+        thickness: Float,
+        albedo: Float,
+        mean_free_path: Float,
+        walks: usize,
+    ) -> (Float, Float) {
+        let object = Subsurface::new(
+            Box::new(Slab {
+                thickness,
+                material: Lambertian {
+                    color: vector![1.0, 1.0, 1.0],
+                },
+            }),
+            vector![albedo, albedo, albedo],
+            vector![mean_free_path, mean_free_path, mean_free_path],
+            0.0,
+        );
+        let ray = Ray {
+            origin: point![0.0, 1.0, 0.0],
+            direction: Unit::new_normalize(vector![0.0, -1.0, 0.0]),
+            time: 0.0,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let entry = object.trace_ray(&ray, 0.0, INFINITY, &mut rng).unwrap();
+        let (mut reflected, mut transmitted) = (0.0, 0.0);
+        for _ in 0..walks {
+            let exit = entry
+                .material
+                .sample_subsurface_exit(&ray, &entry, &mut rng)
+                .unwrap();
+            if exit.throughput == vector![0.0, 0.0, 0.0] {
+                continue;
+            }
+            if exit.intersection.position.y > -thickness / 2.0 {
+                assert!(exit.intersection.normal.y > 0.0);
+                reflected += exit.throughput.x;
+            } else {
+                assert!(exit.intersection.normal.y < 0.0);
+                transmitted += exit.throughput.x;
+            }
+        }
+        (reflected / walks as Float, transmitted / walks as Float)
+    }
+
+    /// Integrates over the cosine-weighted directions that light enters the slab in.
+    fn integrate_cosine_weighted(f: impl Fn(Float) -> Float) -> Float {
+        let n = 2000;
+        (0..n)
+            .map(|i| {
+                let mu = (i as Float + 0.5) / n as Float;
+                2.0 * mu * f(mu) / n as Float
+            })
+            .sum()
+    }
+
+    #[test]
+    fn absorbing_slab_transmits_uncollided_light() {
+        let (reflected, transmitted) = reflectance_and_transmittance(0.5, 0.0, 1.0, 100_000);
+        let expected = integrate_cosine_weighted(|mu| (-0.5 / mu).exp());
+        assert_eq!(reflected, 0.0);
+        assert!((transmitted - expected).abs() < 0.005);
+    }
+
+    #[test]
+    fn conservative_slab_conserves_energy() {
+        let (reflected, transmitted) = reflectance_and_transmittance(0.5, 1.0, 0.25, 20_000);
+        approx::assert_relative_eq!(reflected + transmitted, 1.0, epsilon = 1e-9);
+        assert!(reflected > transmitted);
+    }
+
+    #[test]
+    fn thin_slab_reflects_single_scattering() {
+        // Light scattered once at optical depth t towards direction cosine mu escapes
+        // with probability exp(-t / mu). Multiple scattering is negligible here.
+        let (thickness, albedo) = (0.02, 0.5);
+        let (reflected, _) = reflectance_and_transmittance(thickness, albedo, 1.0, 100_000);
+        let expected = integrate_cosine_weighted(|mu_in| {
+            albedo
+                * integrate_cosine_weighted(|mu_out| {
+                    let rate = 1.0 / mu_in + 1.0 / mu_out;
+                    (1.0 - (-thickness * rate).exp()) / (mu_in * rate) / (4.0 * mu_out)
+                })
+        });
+        approx::assert_relative_eq!(reflected, expected, max_relative = 0.05);
+    }
+
+    #[test]
+    fn longer_mean_free_path_travels_further() {
+        let object = Subsurface::new(
+            Box::new(Slab {
+                thickness: 10.0,
+                material: Lambertian {
+                    color: vector![1.0, 1.0, 1.0],
+                },
+            }),
+            vector![0.9, 0.9, 0.9],
+            vector![0.5, 0.1, 0.1],
+            0.0,
+        );
+        let ray = Ray {
+            origin: point![0.0, 1.0, 0.0],
+            direction: Unit::new_normalize(vector![0.0, -1.0, 0.0]),
+            time: 0.0,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let entry = object.trace_ray(&ray, 0.0, INFINITY, &mut rng).unwrap();
+        let mut spread = vector![0.0, 0.0, 0.0];
+        let mut total = vector![0.0, 0.0, 0.0];
+        for _ in 0..5000 {
+            let exit = object.random_walk(&ray, &entry, &mut rng);
+            let offset: Point = exit.intersection.position;
+            spread += exit.throughput * offset.coords.norm();
+            total += exit.throughput;
+        }
+        let mean_spread = spread.component_div(&total);
+        assert!(mean_spread.x > 3.0 * mean_spread.y);
+    }
+}
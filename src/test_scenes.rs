@@ -0,0 +1,123 @@
+//! Scenes and render settings shared by the tests of the integrators, so that they
+//! can be checked against each other on the same images.
+
+use crate::aov::Aov;
+use crate::camera::PerspectiveCamera;
+use crate::common::Float;
+use crate::common::RayTracable;
+use crate::common::Vector;
+use crate::environment::GradientEnvironment;
+use crate::integrator::Integrator;
+use crate::lights::Light;
+use crate::lights::PointLight;
+use crate::materials::Lambertian;
+use crate::materials::Metal;
+use crate::render::render_with_observer;
+use crate::render::CancellationToken;
+use crate::render::RenderConfig;
+use crate::render::RenderOutput;
+use crate::scene::Floor;
+use crate::scene::Scene;
+use crate::scene::SceneList;
+use crate::scene::Sphere;
+use nalgebra::{point, vector};
+
+/// Rows of the rendered image that only show the floor in front of the camera.
+pub(crate) const FLOOR_ROWS: (u32, u32) = (14, 24);
+
+/// Grey diffuse floor at y = 0.
+pub(crate) fn floor() -> Box<dyn RayTracable> {
+    Box::new(Floor {
+        y: 0.0,
+        material: Box::new(Lambertian {
+            color: vector![0.6, 0.6, 0.6],
+        }),
+    })
+}
+
+/// White point light one unit above the floor at `z`.
+pub(crate) fn point_light(z: Float, intensity: Float) -> Box<dyn Light> {
+    Box::new(PointLight {
+        position: point![0.0, 1.0, z],
+        intensity: vector![intensity, intensity, intensity],
+    })
+}
+
+/// Scene lit only by `lights`, with a black environment.
+pub(crate) fn dark_scene(objects: Vec<Box<dyn RayTracable>>, lights: Vec<Box<dyn Light>>) -> Scene {
+    Scene {
+        objects: SceneList { objects },
+        environment: Box::new(GradientEnvironment {
+            bottom: vector![0.0, 0.0, 0.0],
+            top: vector![0.0, 0.0, 0.0],
+        }),
+        lights,
+    }
+}
+
+/// Floor lit by a light in front of a mirror wall, whose reflection onto the floor
+/// can only be found by tracing from the light.
+pub(crate) fn mirror_scene() -> Scene {
+    let mirror = Sphere {
+        center: point![0.0, 0.0, -1002.0],
+        radius: 1000.0,
+        material: Box::new(Metal {
+            color: vector![0.9, 0.9, 0.9],
+        }),
+    };
+    dark_scene(
+        vec![floor(), Box::new(mirror)],
+        vec![point_light(-1.5, 4.0)],
+    )
+}
+
+/// What `mirror_scene()` should look like on the floor: the light together with its
+/// mirror image, dimmed by the mirror.
+pub(crate) fn mirror_image_scene() -> Scene {
+    dark_scene(
+        vec![floor()],
+        vec![point_light(-1.5, 4.0), point_light(-2.5, 3.6)],
+    )
+}
+
+/// Small image with enough samples to compare integrators' mean colours.
+pub(crate) fn config(integrator: Integrator) -> RenderConfig {
+    RenderConfig {
+        width: 32,
+        height: 24,
+        aspect_ratio: 4.0 / 3.0,
+        samples_per_pixel: 48,
+        max_depth: 5,
+        tile_size: 8,
+        aovs: vec![Aov::Beauty],
+        denoiser: None,
+        integrator,
+        seed: None,
+    }
+}
+
+/// Renders `scene` looking down at the floor in front of the origin.
+pub(crate) fn render(scene: &Scene, config: &RenderConfig) -> RenderOutput {
+    let camera = PerspectiveCamera::new(
+        point![0.0, 2.0, 5.0],
+        point![0.0, 0.6, 0.0],
+        50.0,
+        0.0,
+        config.aspect_ratio,
+    );
+    render_with_observer(
+        config,
+        scene,
+        &camera,
+        &|_, _| {},
+        &CancellationToken::new(),
+    )
+}
+
+/// Mean linear colour of a horizontal band of rows of the rendered image.
+pub(crate) fn band_colour(output: &RenderOutput, rows: (u32, u32)) -> Vector {
+    let width = output.image.width();
+    let beauty = output.aovs.layer(Aov::Beauty).unwrap();
+    let band = &beauty[(rows.0 * width) as usize..(rows.1 * width) as usize];
+    band.iter().sum::<Vector>() / band.len() as Float
+}